@@ -57,10 +57,19 @@ CONTRACT_ABI = [
     },
 ]
 
+def secret(name, section, key):
+    """Secret passed by strategy in environment (it can be not in config file)"""
+    value = os.environ.get(name)
+    if value is None:
+        value = CFG[section][key]
+        if not isinstance(value, str):
+            raise ValueError(f"Secret {section}.{key} isn't passed in environment variable {name}")
+    return value
+
+
 try:
     CFG = toml.load('config.toml')
     ETH_KEY = CFG['wallet']['key']
-    ETH_PRIVATE_KEY = CFG['wallet']['secret']
     WEB3 = Web3(Web3.HTTPProvider(INFURA_URL))
     USDC_CONTRACT = WEB3.eth.contract(abi=CONTRACT_ABI, address=USDC_CONTRACT_ADDRESS)
     CMD = sys.argv[1]
@@ -72,6 +81,7 @@ try:
     elif CMD == "--deposit":
         amount = int(sys.argv[3])
         to = sys.argv[2]
+        ETH_PRIVATE_KEY = secret('ETH_PRIVATE_KEY', 'wallet', 'secret')
         if to == "1":
             #
             # To dYdX:
@@ -80,7 +90,7 @@ try:
             client = Client(
                 host=dydx.API_HOST_MAINNET,
                 network_id=dydx.NETWORK_ID_MAINNET,
-                stark_private_key=CFG['dydx']['stark_private_key'],
+                stark_private_key=secret('DYDX_STARK_PRIVATE_KEY', 'dydx', 'stark_private_key'),
                 default_ethereum_address=ETH_KEY,
                 eth_private_key=ETH_PRIVATE_KEY,
                 web3=WEB3,
@@ -115,7 +125,10 @@ try:
             #
             # To Kraken/Binance:
             #
-            if to == "2":
+            # Address checked by allowlist of strategy (if it is passed)
+            if len(sys.argv) > 4 and sys.argv[4]:
+                to_account = Web3.toChecksumAddress(sys.argv[4])
+            elif to == "2":
                 to_account = Web3.toChecksumAddress(CFG['kraken']['usdc_account'])
             elif to == "3":
                 to_account = Web3.toChecksumAddress(CFG['binance']['usdc_account'])
@@ -158,6 +171,25 @@ try:
         else:
             print(f"Unknown account: {to}", file=sys.stderr)
             sys.exit(1)
+    elif CMD == "--derive-dydx-keys":
+        #
+        # Keys of dYdX onboarding (the same as dYdX web site generates), they are derived
+        # from signatures of typed messages by ETH key:
+        #
+        client = Client(
+            host=dydx.API_HOST_MAINNET,
+            network_id=dydx.NETWORK_ID_MAINNET,
+            default_ethereum_address=ETH_KEY,
+            eth_private_key=secret('ETH_PRIVATE_KEY', 'wallet', 'secret'),
+            web3=WEB3,
+        )
+        stark_key_pair = client.onboarding.derive_stark_key()
+        api_key = client.onboarding.recover_default_api_key_credentials()
+        print(f"STARK_PRIVATE_KEY: {int(stark_key_pair['private_key'], 16):064x}")
+        print(f"STARK_PUBLIC_KEY: {int(stark_key_pair['public_key'], 16):064x}")
+        print(f"API_KEY: {api_key['key']}")
+        print(f"API_SECRET: {api_key['secret']}")
+        print(f"API_PASSPHRASE: {api_key['passphrase']}")
     else:
         print(f'Unknown command: "{cmd}"', file=sys.stderr)
         sys.exit(1)
@@ -0,0 +1,213 @@
+/// Registry of actions (`do_*` functions which can be run by name)
+///
+/// Actions are registered by `register_actions!` (see the end of `strategy.rs`), which
+/// generates `ACTIONS` (name, description and type of parameter of every action) and
+/// `run_action_by_name()`. Command line (`only`, `list-actions`) and HTTP API use them.
+///
+
+use rust_decimal_macros::dec;
+
+use crate::consts::*;
+use crate::types::*;
+use crate::utils::*;
+
+
+/// Type of action parameter.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Param {
+    /// Action has no parameter
+    Nothing,
+    /// Amount of transferring coins (USDC)
+    Primary,
+    /// Amount of operating coins (ATOM)
+    Secondary,
+    /// Amount of operating coins which can be negative
+    SignedSecondary,
+    /// Percent (0..100)
+    Percent,
+}
+
+
+impl Param {
+    /// Name of value in help.
+    ///
+    pub fn value_name(&self) -> String {
+        match self {
+            Param::Nothing => String::new(),
+            Param::Primary => TRANSFERRING_COIN.to_owned(),
+            Param::Secondary | Param::SignedSecondary => OPERATING_COIN.to_owned(),
+            Param::Percent => "PERCENT".to_owned(),
+        }
+    }
+
+    /// Description of value in help.
+    ///
+    pub fn help(&self) -> String {
+        match self {
+            Param::Nothing => String::new(),
+            Param::Primary | Param::Secondary =>
+                format!("Amount with unit, for example `100{}` (optional)", self.value_name().to_lowercase()),
+            Param::SignedSecondary =>
+                format!("Amount with unit, positive or negative, for example `-5{}` (optional)", self.value_name().to_lowercase()),
+            Param::Percent => "Percent, for example `30%` (optional)".to_owned(),
+        }
+    }
+
+    /// Value with unit.
+    ///
+    pub fn format(&self, value: Value) -> String {
+        match self {
+            Param::Nothing => String::new(),
+            Param::Primary | Param::Secondary | Param::SignedSecondary => format!("{} {}", value, self.value_name()),
+            Param::Percent => format!("{}%", value),
+        }
+    }
+
+    /// Parse and check value of parameter.
+    ///
+    /// NOTE: amounts must have unit (`100usdc`, `5atom`), so ATOMs can't be passed instead of USDC,
+    /// and they must be positive (except `SignedSecondary`).
+    ///
+    pub fn parse(&self, s: &str) -> Result<Value, String> {
+        match self {
+            Param::Nothing => Err("action has no parameter".to_owned()),
+            Param::Primary | Param::Secondary | Param::SignedSecondary => {
+                let unit = self.value_name().to_lowercase();
+                let amount = match s.trim().to_lowercase().strip_suffix(&unit) {
+                    Some(amount) => amount.trim().parse::<Value>().map_err(|e| e.to_string())?,
+                    None => return Err(format!("amount of {} with unit is expected, for example `100{}`", self.value_name(), unit)),
+                };
+                if *self != Param::SignedSecondary && amount <= ZERO {
+                    return Err(format!("amount must be positive, not {}", amount));
+                }
+                Ok(amount)
+            },
+            Param::Percent => {
+                let percent = crate::cli::parse_percent(s)?;
+                if percent <= ZERO || percent > dec!(100) {
+                    return Err(format!("percent must be in (0, 100], not {}", percent));
+                }
+                Ok(percent)
+            },
+        }
+    }
+
+    /// Check value received without parsing (HTTP API, etc.)
+    ///
+    pub fn check(&self, value: Option<Value>) -> Result<(), String> {
+        match (self, value) {
+            (Param::Nothing, Some(v)) => Err(format!("action has no parameter, but {} is given", v)),
+            (_, Some(v)) => self.parse(&self.format(v)).map(|_| ()),
+            (_, None) => Ok(()),
+        }
+    }
+}
+
+
+/// Registered action.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct ActionInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub param: Param,
+}
+
+
+/// Call action `$func` with value converted to type of its parameter.
+///
+macro_rules! call_action {
+    ($func:ident, $ctx:ident, $value:ident, Nothing) => { $func($ctx).await };
+    ($func:ident, $ctx:ident, $value:ident, Primary) => { $func($ctx, $value.map($crate::types::PrimaryAsset)).await };
+    ($func:ident, $ctx:ident, $value:ident, Secondary) => { $func($ctx, $value.map($crate::types::SecondaryAsset)).await };
+    ($func:ident, $ctx:ident, $value:ident, SignedSecondary) => { $func($ctx, $value.map($crate::types::SecondaryAsset)).await };
+    ($func:ident, $ctx:ident, $value:ident, Percent) => { $func($ctx, $value).await };
+}
+pub(crate) use call_action;
+
+
+/// Register actions: `function(Param) "description";` for every action.
+///
+macro_rules! register_actions {
+    ($( $func:ident ( $param:ident ) $description:literal; )*) => {
+        /// All registered actions.
+        ///
+        pub static ACTIONS: &[$crate::actions::ActionInfo] = &[
+            $( $crate::actions::ActionInfo {
+                name: stringify!($func),
+                description: $description,
+                param: $crate::actions::Param::$param,
+            }, )*
+        ];
+
+        /// Run action by its name (value is checked by type of action parameter)
+        ///
+        pub async fn run_action_by_name(action_name: String, ctx: &mut Context, additional_value: Option<Value>) -> ActionResult {
+            let action = match $crate::actions::find_action(&action_name) {
+                Some(action) => action,
+                None => return err(format!("No such action \"{}\"", action_name)),
+            };
+            if let Err(msg) = action.param.check(additional_value) {
+                return err(format!("Action \"{}\": {}", action_name, msg));
+            }
+            debug!("Run \"{}\"", action_name);
+            match action.name {
+                $( stringify!($func) => $crate::actions::call_action!($func, ctx, additional_value, $param), )*
+                _ => unreachable!(),
+            }
+        }
+    };
+}
+pub(crate) use register_actions;
+
+
+pub fn find_action(name: &str) -> Option<&'static ActionInfo> {
+    crate::strategy::ACTIONS.iter().find(|a| a.name == name)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_need_unit() {
+        assert_eq!(Param::Primary.parse("100usdc"), Ok(dec!(100)));
+        assert_eq!(Param::Primary.parse(" 1.5 USDC "), Ok(dec!(1.5)));
+        assert_eq!(Param::Secondary.parse("5atom"), Ok(dec!(5)));
+        assert!(Param::Primary.parse("100").is_err());
+        assert!(Param::Primary.parse("5atom").is_err());
+        assert!(Param::Secondary.parse("100usdc").is_err());
+    }
+
+    #[test]
+    fn unsigned_amounts_are_positive() {
+        for param in [Param::Primary, Param::Secondary] {
+            let unit = param.value_name().to_lowercase();
+            assert!(param.parse(&format!("-100{}", unit)).is_err());
+            assert!(param.parse(&format!("0{}", unit)).is_err());
+            assert!(param.check(Some(dec!(-100))).is_err());
+            assert!(param.check(Some(ZERO)).is_err());
+            assert_eq!(param.check(Some(dec!(100))), Ok(()));
+            assert_eq!(param.check(None), Ok(()));
+        }
+    }
+
+    #[test]
+    fn signed_amount() {
+        assert_eq!(Param::SignedSecondary.parse("-5atom"), Ok(dec!(-5)));
+        assert_eq!(Param::SignedSecondary.parse("5atom"), Ok(dec!(5)));
+        assert!(Param::SignedSecondary.parse("-5usdc").is_err());
+        assert_eq!(Param::SignedSecondary.check(Some(dec!(-5))), Ok(()));
+    }
+
+    #[test]
+    fn percent_and_nothing() {
+        assert_eq!(Param::Percent.check(Some(dec!(30))), Ok(()));
+        assert!(Param::Percent.check(Some(dec!(101))).is_err());
+        assert!(Param::Percent.check(Some(ZERO)).is_err());
+        assert!(Param::Nothing.check(Some(dec!(1))).is_err());
+        assert_eq!(Param::Nothing.check(None), Ok(()));
+    }
+}
@@ -0,0 +1,282 @@
+/// Routing of alerts: deduplication, throttling, quiet hours and "resolved" messages
+///
+/// * identical warnings/errors within `dedup_window` are sent once, the next message
+///   has count of repeats;
+/// * every source (log target and place) can send at most `throttle_limit` messages
+///   within `throttle_window`;
+/// * during quiet hours only errors are sent;
+/// * if repeated alert isn't seen for `resolve_after` seconds, "resolved" message is sent.
+///
+
+use chrono::NaiveTime;
+use log::Level;
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+
+use crate::config::RoutingConfig;
+use crate::notifiers::Notification;
+
+
+/// Alert which was seen recently.
+///
+struct ActiveAlert {
+    notification: Notification,
+    last_seen: Instant,
+    last_sent: Instant,
+    /// Repeats which are not reported yet
+    repeats: u64,
+    total: u64,
+}
+
+
+/// Messages of one source within throttling window.
+///
+struct SourceWindow {
+    started: Instant,
+    count: u64,
+    suppressed: u64,
+}
+
+
+pub struct Router {
+    config: RoutingConfig,
+    quiet_hours: Option<(NaiveTime, NaiveTime)>,
+    alerts: HashMap<(Level, String), ActiveAlert>,
+    sources: HashMap<String, SourceWindow>,
+    /// Messages suppressed during quiet hours
+    quiet_suppressed: u64,
+}
+
+
+fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s.trim(), "%H:%M")
+        .map_err(|err| format!("Bad time \"{}\" in quiet hours (must be HH:MM): {}", s, err))
+}
+
+
+impl Router {
+    /// Error if quiet hours are misconfigured.
+    ///
+    pub fn new(config: RoutingConfig) -> Result<Self, String> {
+        let quiet_hours = match &config.quiet_hours {
+            Some(q) => Some((parse_time(&q.from)?, parse_time(&q.to)?)),
+            None => None,
+        };
+        Ok(Router { config, quiet_hours, alerts: HashMap::new(), sources: HashMap::new(), quiet_suppressed: 0 })
+    }
+
+    fn is_quiet_time(&self, time: NaiveTime) -> bool {
+        match self.quiet_hours {
+            None => false,
+            Some((from, to)) if from <= to => from <= time && time < to,
+            // Over midnight, e.g. 23:00 -- 07:00
+            Some((from, to)) => time >= from || time < to,
+        }
+    }
+
+    /// Decide whether notification `n` must be sent (possibly with added text) now.
+    ///
+    pub fn route(&mut self, mut n: Notification, now: Instant, time: NaiveTime) -> Option<Notification> {
+        let dedup_window = Duration::from_secs(self.config.dedup_window);
+        // Deduplication (of warnings and errors only)
+        if n.level <= Level::Warn {
+            match self.alerts.get_mut(&(n.level, n.text.clone())) {
+                Some(alert) if now.duration_since(alert.last_sent) < dedup_window => {
+                    alert.last_seen = now;
+                    alert.repeats += 1;
+                    alert.total += 1;
+                    return None;
+                },
+                Some(alert) => {
+                    if alert.repeats > 0 {
+                        n.text = format!("{}\n(repeated {} times in last {} seconds)",
+                                         n.text, alert.repeats, now.duration_since(alert.last_sent).as_secs());
+                    }
+                    alert.last_seen = now;
+                    alert.last_sent = now;
+                    alert.repeats = 0;
+                    alert.total += 1;
+                },
+                None => {
+                    self.alerts.insert((n.level, n.text.clone()), ActiveAlert {
+                        notification: n.clone(), last_seen: now, last_sent: now, repeats: 0, total: 1 });
+                },
+            }
+        }
+        // Quiet hours (for non-critical messages)
+        if n.level > Level::Error && self.is_quiet_time(time) {
+            self.quiet_suppressed += 1;
+            return None;
+        }
+        if self.quiet_suppressed > 0 {
+            n.text = format!("({} messages were suppressed during quiet hours)\n{}", self.quiet_suppressed, n.text);
+            self.quiet_suppressed = 0;
+        }
+        // Throttling per source
+        let throttle_window = Duration::from_secs(self.config.throttle_window);
+        let window = self.sources.entry(format!("{} {}", n.target, n.place))
+            .or_insert(SourceWindow { started: now, count: 0, suppressed: 0 });
+        if now.duration_since(window.started) >= throttle_window {
+            if window.suppressed > 0 {
+                n.text = format!("{}\n({} messages from this source were throttled)", n.text, window.suppressed);
+            }
+            *window = SourceWindow { started: now, count: 0, suppressed: 0 };
+        }
+        window.count += 1;
+        if window.count > self.config.throttle_limit {
+            window.suppressed += 1;
+            return None;
+        }
+        Some(n)
+    }
+
+    /// "Resolved" notifications for repeated alerts which are not seen for `resolve_after` seconds.
+    ///
+    pub fn take_resolved(&mut self, now: Instant) -> Vec<Notification> {
+        let resolve_after = Duration::from_secs(self.config.resolve_after);
+        let resolved_keys: Vec<(Level, String)> = self.alerts.iter()
+            .filter(|(_, a)| now.duration_since(a.last_seen) >= resolve_after)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut resolved = Vec::new();
+        for key in resolved_keys {
+            if let Some(alert) = self.alerts.remove(&key) {
+                // One-time alerts are not "conditions", so they are just forgotten
+                if alert.total > 1 {
+                    let mut n = alert.notification;
+                    n.text = format!("{}\n(seen {} times, not repeated for {} seconds)",
+                                     n.text, alert.total, now.duration_since(alert.last_seen).as_secs());
+                    n.resolved = true;
+                    resolved.push(n);
+                }
+            }
+        }
+        // Forget finished throttling windows
+        let throttle_window = Duration::from_secs(self.config.throttle_window);
+        self.sources.retain(|_, w| now.duration_since(w.started) < throttle_window || w.suppressed > 0);
+        resolved
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QuietHoursConfig;
+
+    fn config() -> RoutingConfig {
+        RoutingConfig { dedup_window: 900, throttle_window: 60, throttle_limit: 3, resolve_after: 300, quiet_hours: None }
+    }
+
+    fn notification(level: Level, text: &str, place: &str) -> Notification {
+        Notification { level, target: "NOTIFICATION".to_owned(), text: text.to_owned(), place: place.to_owned(),
+                       time: String::new(), resolved: false }
+    }
+
+    fn at(hour: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, 0).unwrap()
+    }
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    fn quiet(from: &str, to: &str) -> Router {
+        let quiet_hours = Some(QuietHoursConfig { from: from.to_owned(), to: to.to_owned() });
+        Router::new(RoutingConfig { quiet_hours, ..config() }).unwrap()
+    }
+
+    #[test]
+    fn bad_quiet_hours() {
+        let quiet_hours = Some(QuietHoursConfig { from: "23:00".to_owned(), to: "7am".to_owned() });
+        assert!(Router::new(RoutingConfig { quiet_hours, ..config() }).is_err());
+    }
+
+    #[test]
+    fn dedup_window() {
+        let mut router = Router::new(config()).unwrap();
+        let t0 = Instant::now();
+        assert!(router.route(notification(Level::Warn, "A", "a.rs:1"), t0, at(12, 0)).is_some());
+        assert!(router.route(notification(Level::Warn, "A", "a.rs:1"), t0 + secs(10), at(12, 0)).is_none());
+        assert!(router.route(notification(Level::Warn, "A", "a.rs:1"), t0 + secs(899), at(12, 0)).is_none());
+        // The same text of other level is other alert
+        assert!(router.route(notification(Level::Error, "A", "a.rs:1"), t0 + secs(899), at(12, 0)).is_some());
+        let n = router.route(notification(Level::Warn, "A", "a.rs:1"), t0 + secs(900), at(12, 0)).unwrap();
+        assert_eq!(n.text, "A\n(repeated 2 times in last 900 seconds)");
+        // Repeats are counted from the last sent message
+        let n = router.route(notification(Level::Warn, "A", "a.rs:1"), t0 + secs(1800), at(12, 0)).unwrap();
+        assert_eq!(n.text, "A");
+    }
+
+    #[test]
+    fn info_is_not_deduplicated() {
+        let mut router = Router::new(config()).unwrap();
+        let t0 = Instant::now();
+        assert!(router.route(notification(Level::Info, "A", "a.rs:1"), t0, at(12, 0)).is_some());
+        assert!(router.route(notification(Level::Info, "A", "a.rs:1"), t0 + secs(1), at(12, 0)).is_some());
+        assert!(router.take_resolved(t0 + secs(1000)).is_empty());
+    }
+
+    #[test]
+    fn throttle_per_source() {
+        let mut router = Router::new(config()).unwrap();
+        let t0 = Instant::now();
+        for i in 0..3 {
+            assert!(router.route(notification(Level::Info, &i.to_string(), "a.rs:1"), t0 + secs(i), at(12, 0)).is_some());
+        }
+        assert!(router.route(notification(Level::Info, "3", "a.rs:1"), t0 + secs(3), at(12, 0)).is_none());
+        assert!(router.route(notification(Level::Warn, "4", "a.rs:1"), t0 + secs(4), at(12, 0)).is_none());
+        // Other source isn't throttled
+        assert!(router.route(notification(Level::Info, "5", "b.rs:1"), t0 + secs(5), at(12, 0)).is_some());
+        // New window
+        let n = router.route(notification(Level::Info, "6", "a.rs:1"), t0 + secs(60), at(12, 0)).unwrap();
+        assert_eq!(n.text, "6\n(2 messages from this source were throttled)");
+        assert!(router.route(notification(Level::Info, "7", "a.rs:1"), t0 + secs(61), at(12, 0)).is_some());
+    }
+
+    #[test]
+    fn quiet_hours_across_midnight() {
+        let mut router = quiet("23:00", "07:00");
+        let t0 = Instant::now();
+        assert!(router.route(notification(Level::Warn, "A", "a.rs:1"), t0, at(22, 59)).is_some());
+        assert!(router.route(notification(Level::Warn, "B", "a.rs:2"), t0 + secs(1), at(23, 0)).is_none());
+        assert!(router.route(notification(Level::Info, "C", "a.rs:3"), t0 + secs(2), at(3, 0)).is_none());
+        // Errors are sent with count of suppressed messages
+        let n = router.route(notification(Level::Error, "D", "a.rs:4"), t0 + secs(3), at(3, 0)).unwrap();
+        assert_eq!(n.text, "(2 messages were suppressed during quiet hours)\nD");
+        assert!(router.route(notification(Level::Info, "E", "a.rs:5"), t0 + secs(4), at(6, 59)).is_none());
+        let n = router.route(notification(Level::Info, "F", "a.rs:6"), t0 + secs(5), at(7, 0)).unwrap();
+        assert_eq!(n.text, "(1 messages were suppressed during quiet hours)\nF");
+        assert_eq!(router.route(notification(Level::Info, "G", "a.rs:7"), t0 + secs(6), at(12, 0)).unwrap().text, "G");
+    }
+
+    #[test]
+    fn quiet_hours_within_day() {
+        let mut router = quiet("12:00", "13:00");
+        let t0 = Instant::now();
+        assert!(router.route(notification(Level::Info, "A", "a.rs:1"), t0, at(11, 59)).is_some());
+        assert!(router.route(notification(Level::Info, "B", "a.rs:1"), t0, at(12, 30)).is_none());
+        assert!(router.route(notification(Level::Info, "C", "a.rs:1"), t0, at(13, 0)).is_some());
+        assert!(router.route(notification(Level::Info, "D", "a.rs:1"), t0, at(0, 30)).is_some());
+    }
+
+    #[test]
+    fn resolved_only_repeated_alerts() {
+        let mut router = Router::new(config()).unwrap();
+        let t0 = Instant::now();
+        assert!(router.route(notification(Level::Warn, "once", "a.rs:1"), t0, at(12, 0)).is_some());
+        assert!(router.route(notification(Level::Warn, "repeated", "a.rs:2"), t0, at(12, 0)).is_some());
+        assert!(router.route(notification(Level::Warn, "repeated", "a.rs:2"), t0 + secs(10), at(12, 0)).is_none());
+        assert!(router.take_resolved(t0 + secs(299)).is_empty());
+        // One-time alert is forgotten silently
+        assert!(router.take_resolved(t0 + secs(300)).is_empty());
+        let resolved = router.take_resolved(t0 + secs(310));
+        assert_eq!(resolved.len(), 1);
+        assert!(resolved[0].resolved);
+        assert_eq!(resolved[0].level, Level::Warn);
+        assert_eq!(resolved[0].text, "repeated\n(seen 2 times, not repeated for 300 seconds)");
+        // It is forgotten, so the next one is new alert
+        assert!(router.take_resolved(t0 + secs(1000)).is_empty());
+        assert_eq!(router.route(notification(Level::Warn, "repeated", "a.rs:2"), t0 + secs(1000), at(12, 0)).unwrap().text, "repeated");
+    }
+}
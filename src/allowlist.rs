@@ -0,0 +1,495 @@
+/// Allowlist of withdrawal destinations
+///
+/// Every withdrawal (and deposit from wallet) is checked before its request is built:
+///
+/// * `[allowlist]` section must be signed (HMAC-SHA256 by `signing_key`, see `allowlist sign`),
+///   ETH addresses in it must be checksummed (EIP-55);
+/// * destination (asset, network, address, memo) must be in the allowlist, Kraken withdrawal keys
+///   are resolved to addresses (and memos) by Kraken API;
+/// * if destination is our account on venue, it must be one of deposit addresses (with the same
+///   memo) reported by this venue (`check_venues`).
+///
+/// Mismatch refuses withdrawal, pauses strategy and raises critical alert.
+///
+
+use hmac::{ Hmac, Mac };
+use log::{ debug, error };
+use sha2::{ Digest, Sha256, Sha512 };
+use sha3::Keccak256;
+use std::time::Duration;
+
+use crate::config::{ AllowedDestination, AllowlistConfig, ApiCredentials, Config };
+use crate::consts::*;
+use crate::control;
+use crate::monitoring;
+use crate::secrets::Secret;
+use crate::types::*;
+
+
+static KRAKEN_API_URL: &str = "https://api.kraken.com";
+static BINANCE_API_URL: &str = "https://api.binance.com";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// Where funds are sent.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target<'a> {
+    /// Address and memo (tag)
+    Address(&'a str, Option<&'a str>),
+    /// Name of withdrawal key on Kraken (address and memo are resolved by Kraken API)
+    KrakenKey(&'a str),
+}
+
+
+/// Resolved destination: address and memo (tag).
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Destination {
+    address: String,
+    memo: Option<String>,
+}
+
+
+impl Destination {
+    fn new(address: &str, memo: Option<&str>) -> Self {
+        // NOTE: venues report empty tag if memo isn't used
+        Destination { address: address.to_owned(), memo: memo.filter(|m| !m.is_empty()).map(str::to_owned) }
+    }
+
+    /// Address and memo of entry of venue response.
+    ///
+    fn from_json(entry: &serde_json::Value) -> Option<Self> {
+        let memo = ["memo", "tag"].iter().find_map(|field| match &entry[*field] {
+            serde_json::Value::String(s) => Some(s.clone()),
+            serde_json::Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        });
+        Some(Destination::new(entry["address"].as_str()?, memo.as_deref()))
+    }
+
+    fn matches(&self, address: &str, memo: Option<&str>) -> bool {
+        same_address(&self.address, address) && self.memo.as_deref() == memo.filter(|m| !m.is_empty())
+    }
+}
+
+
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.memo {
+            Some(memo) => write!(f, "{} (memo {})", self.address, memo),
+            None => write!(f, "{}", self.address),
+        }
+    }
+}
+
+
+/// Allowlist and credentials to query venues about addresses.
+///
+pub struct Allowlist {
+    cfg: AllowlistConfig,
+    kraken: Option<ApiCredentials>,
+    binance: Option<ApiCredentials>,
+}
+
+
+fn credentials(read_only: &Option<ApiCredentials>, key: &Option<Secret>, secret: &Option<Secret>) -> Option<ApiCredentials> {
+    match (read_only, key, secret) {
+        (Some(c), _, _) => Some(c.clone()),
+        (None, Some(key), Some(secret)) => Some(ApiCredentials { key: key.clone(), secret: secret.clone() }),
+        _ => None,
+    }
+}
+
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+
+fn is_eth_address(address: &str) -> bool {
+    address.len() == 42 && address.starts_with("0x") && address[2..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+
+/// EIP-55: case of letters is given by Keccak-256 of lowercase address.
+///
+fn is_checksummed(address: &str) -> bool {
+    let hex = &address[2..];
+    let hash = Keccak256::digest(hex.to_lowercase().as_bytes());
+    hex.chars().enumerate().all(|(i, c)| {
+        let nibble = if i % 2 == 0 { hash[i / 2] >> 4 } else { hash[i / 2] & 0x0f };
+        if c.is_ascii_digit() { true }
+        else if nibble >= 8 { c.is_ascii_uppercase() }
+        else { c.is_ascii_lowercase() }
+    })
+}
+
+
+fn same_address(a: &str, b: &str) -> bool {
+    // NOTE: case of ETH addresses is only checksum
+    if is_eth_address(a) && is_eth_address(b) { a.eq_ignore_ascii_case(b) } else { a == b }
+}
+
+
+/// Signed text: sorted lines "asset network address memo" (separated by tabs, memo is empty if it isn't set).
+///
+fn canonical(destinations: &[AllowedDestination]) -> String {
+    let mut lines: Vec<String> = destinations.iter()
+        .map(|d| format!("{}\t{}\t{}\t{}\n", d.asset, d.network, d.address, d.memo.as_deref().unwrap_or_default()))
+        .collect();
+    lines.sort();
+    lines.concat()
+}
+
+
+fn make_mac(cfg: &AllowlistConfig) -> Result<Hmac<Sha256>, String> {
+    let key = cfg.signing_key.as_ref().ok_or("allowlist.signing_key isn't set")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.expose().as_bytes()).map_err(|err| err.to_string())?;
+    mac.update(canonical(&cfg.destinations).as_bytes());
+    Ok(mac)
+}
+
+
+/// Signature of `destinations` (for `signature` field).
+///
+pub fn sign(cfg: &AllowlistConfig) -> Result<String, String> {
+    Ok(to_hex(&make_mac(cfg)?.finalize().into_bytes()))
+}
+
+
+/// Check signature and addresses of allowlist.
+///
+pub fn check_allowlist(cfg: &AllowlistConfig) -> Result<(), String> {
+    let signature = from_hex(cfg.signature.trim()).ok_or("allowlist.signature isn't hex")?;
+    make_mac(cfg)?.verify_slice(&signature).map_err(|_| "allowlist signature doesn't match destinations".to_owned())?;
+    for d in cfg.destinations.iter() {
+        // Separators of signed text must not be inside fields
+        let fields = [d.asset.as_str(), d.network.as_str(), d.address.as_str(), d.memo.as_deref().unwrap_or_default()];
+        if fields.iter().any(|f| f.contains(['\t', '\n'])) {
+            return Err(format!("destination {:?} in allowlist contains tab or newline", d));
+        }
+    }
+    for d in cfg.destinations.iter().filter(|d| d.network == TRANSFERRING_NETWORK) {
+        if !is_eth_address(&d.address) || !is_checksummed(&d.address) {
+            return Err(format!("{} address {} in allowlist isn't checksummed", d.network, d.address));
+        }
+    }
+    Ok(())
+}
+
+
+//
+// Venue APIs -------------------------------------------------------------------------
+//
+
+/// `application/x-www-form-urlencoded` value.
+///
+fn form_encode(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => (b as char).to_string(),
+        b' ' => "+".to_owned(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+
+fn kraken_private(creds: &ApiCredentials, method: &str, params: &[(&str, &str)]) -> Result<serde_json::Value, String> {
+    let path = format!("/0/private/{}", method);
+    let nonce = chrono::Utc::now().timestamp_millis().to_string();
+    let body = std::iter::once(("nonce", nonce.as_str())).chain(params.iter().copied())
+        .map(|(k, v)| format!("{}={}", k, form_encode(v)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let secret = base64::decode(creds.secret.expose()).map_err(|err| format!("Bad {} secret: {}", E2_NAME, err))?;
+    let mut mac = Hmac::<Sha512>::new_from_slice(&secret).map_err(|err| err.to_string())?;
+    mac.update(path.as_bytes());
+    mac.update(&Sha256::digest(format!("{}{}", nonce, body).as_bytes()));
+    let response: serde_json::Value = ureq::post(&format!("{}{}", KRAKEN_API_URL, path))
+        .timeout(REQUEST_TIMEOUT)
+        .set("API-Key", creds.key.expose())
+        .set("API-Sign", &base64::encode(mac.finalize().into_bytes()))
+        .set("Content-Type", "application/x-www-form-urlencoded")
+        .send_string(&body)
+        .map_err(|err| format!("{} {}: {}", E2_NAME, method, err))?
+        .into_json()
+        .map_err(|err| format!("{} {}: {}", E2_NAME, method, err))?;
+    match response["error"].as_array() {
+        Some(errors) if !errors.is_empty() => Err(format!("{} {}: {:?}", E2_NAME, method, errors)),
+        _ => Ok(response["result"].clone()),
+    }
+}
+
+
+/// All deposit addresses of `asset` on Kraken (of all deposit methods).
+///
+fn kraken_deposit_addresses(creds: &ApiCredentials, asset: &str) -> Result<Vec<Destination>, String> {
+    let methods = kraken_private(creds, "DepositMethods", &[("asset", asset)])?;
+    let mut addresses = Vec::new();
+    for method in methods.as_array().into_iter().flatten().filter_map(|m| m["method"].as_str()) {
+        let result = kraken_private(creds, "DepositAddresses", &[("asset", asset), ("method", method)])?;
+        addresses.extend(result.as_array().into_iter().flatten().filter_map(Destination::from_json));
+    }
+    Ok(addresses)
+}
+
+
+/// Address of Kraken withdrawal key `key` (it must be verified on Kraken).
+///
+fn kraken_withdrawal_address(creds: &ApiCredentials, asset: &str, key: &str) -> Result<Destination, String> {
+    let result = kraken_private(creds, "WithdrawAddresses", &[("asset", asset), ("key", key)])?;
+    let entry = result.as_array().and_then(|a| a.iter().find(|e| e["key"].as_str() == Some(key)))
+        .ok_or_else(|| format!("{}: no withdrawal key \"{}\" of {}", E2_NAME, key, asset))?;
+    if entry["verified"].as_bool() == Some(false) {
+        return Err(format!("{}: withdrawal key \"{}\" isn't verified", E2_NAME, key));
+    }
+    Destination::from_json(entry).ok_or_else(|| format!("{}: no address of withdrawal key \"{}\"", E2_NAME, key))
+}
+
+
+fn binance_deposit_address(creds: &ApiCredentials, coin: &str, network: &str) -> Result<Destination, String> {
+    let query = format!("coin={}&network={}&timestamp={}", form_encode(coin), form_encode(network), chrono::Utc::now().timestamp_millis());
+    let mut mac = Hmac::<Sha256>::new_from_slice(creds.secret.expose().as_bytes()).map_err(|err| err.to_string())?;
+    mac.update(query.as_bytes());
+    let url = format!("{}/sapi/v1/capital/deposit/address?{}&signature={}", BINANCE_API_URL, query, to_hex(&mac.finalize().into_bytes()));
+    let response: serde_json::Value = ureq::get(&url)
+        .timeout(REQUEST_TIMEOUT)
+        .set("X-MBX-APIKEY", creds.key.expose())
+        .call()
+        .map_err(|err| format!("{} deposit address: {}", EE_NAME, err))?
+        .into_json()
+        .map_err(|err| format!("{} deposit address: {}", EE_NAME, err))?;
+    Destination::from_json(&response).ok_or_else(|| format!("{}: no deposit address of {} in {}", EE_NAME, coin, response))
+}
+
+
+/// Run blocking request to venue.
+///
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, String> + Send + 'static) -> StrategyResult<T> {
+    tokio::task::spawn_blocking(f).await
+        .map_err(|err| StrategyError::Misc { msg: err.to_string() })?
+        .map_err(|msg| StrategyError::Misc { msg })
+}
+
+
+impl Allowlist {
+    /// Venues are queried with read-only credentials (or trading ones if they are not set).
+    ///
+    pub fn new(config: &Config) -> Self {
+        Allowlist {
+            cfg: config.allowlist.clone(),
+            kraken: credentials(&config.read_only.kraken, &config.kraken.key, &config.kraken.secret),
+            binance: credentials(&config.read_only.binance, &config.binance.funding_key, &config.binance.funding_secret),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.cfg.enabled
+    }
+
+    fn kraken(&self) -> StrategyResult<ApiCredentials> {
+        self.kraken.clone().ok_or_else(|| StrategyError::Misc { msg: format!("No {} credentials to check addresses", E2_NAME) })
+    }
+
+    fn binance(&self) -> StrategyResult<ApiCredentials> {
+        self.binance.clone().ok_or_else(|| StrategyError::Misc { msg: format!("No {} credentials to check addresses", EE_NAME) })
+    }
+
+    /// Refuse withdrawal: critical alert and pause.
+    ///
+    fn refuse(&self, asset: &str, msg: String) -> StrategyError {
+        error!(target: "NOTIFICATION", "CRITICAL: WITHDRAWAL REFUSED: {}", msg);
+        monitoring::WITHDRAWALS_REFUSED.with_label_values(&[asset]).inc();
+        control::set_paused(true, "allowlist check");
+        StrategyError::Misc { msg: format!("Withdrawal refused: {}", msg) }
+    }
+
+    /// Deposit addresses of our account on `owner` (`None` if venue doesn't report them).
+    ///
+    async fn reported_addresses(&self, asset: &str, network: &str, owner: WhichExchange) -> StrategyResult<Option<Vec<Destination>>> {
+        let (asset, network) = (asset.to_owned(), network.to_owned());
+        match owner {
+            WhichExchange::Second => {
+                let creds = self.kraken()?;
+                Ok(Some(blocking(move || kraken_deposit_addresses(&creds, &asset)).await?))
+            },
+            WhichExchange::Exchange => {
+                let creds = self.binance()?;
+                Ok(Some(vec![blocking(move || binance_deposit_address(&creds, &asset, &network)).await?]))
+            },
+            // Our wallet, dYdX deposits go through contract
+            WhichExchange::Wallet | WhichExchange::First => Ok(None),
+        }
+    }
+
+    /// Check destination of withdrawal of `asset` via `network` to our account on `owner`.
+    ///
+    pub async fn verify(&self, asset: &str, network: &str, target: Target<'_>, owner: WhichExchange) -> ActionResult {
+        if !self.cfg.enabled {
+            return Ok(());
+        }
+        let owner_name = get_exchange_name(owner);
+        check_allowlist(&self.cfg).map_err(|msg| self.refuse(asset, msg))?;
+        let destination = match target {
+            Target::Address(address, memo) => Destination::new(address, memo),
+            Target::KrakenKey(key) => {
+                let creds = self.kraken()?;
+                let (asset, key) = (asset.to_owned(), key.to_owned());
+                blocking(move || kraken_withdrawal_address(&creds, &asset, &key)).await?
+            },
+        };
+        debug!("Check destination of {} ({}) to {}: {:?} -> {}", asset, network, owner_name, target, destination);
+        if !self.cfg.destinations.iter().any(|d| d.asset == asset && d.network == network && destination.matches(&d.address, d.memo.as_deref())) {
+            return Err(self.refuse(asset, format!("{} ({}) address {} of {} isn't in allowlist", asset, network, destination, owner_name)));
+        }
+        if self.cfg.check_venues {
+            if let Some(reported) = self.reported_addresses(asset, network, owner).await? {
+                if !reported.iter().any(|r| destination.matches(&r.address, r.memo.as_deref())) {
+                    let reported: Vec<String> = reported.iter().map(|r| r.to_string()).collect();
+                    return Err(self.refuse(asset, format!("{} ({}) address {} isn't deposit address of our account on {} (reported: {:?})",
+                                                          asset, network, destination, owner_name, reported)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Check all destinations from config (`allowlist check`).
+///
+pub async fn check_config(config: &Config) -> ActionResult {
+    let mut allowlist = Allowlist::new(config);
+    allowlist.cfg.enabled = true;
+    let destinations = [
+        (TRANSFERRING_COIN, TRANSFERRING_NETWORK, Target::Address(&config.wallet.key, None), WhichExchange::Wallet),
+        (TRANSFERRING_COIN, TRANSFERRING_NETWORK, Target::KrakenKey(&config.kraken.withdrawal_key), WhichExchange::Wallet),
+        (TRANSFERRING_COIN, TRANSFERRING_NETWORK, Target::Address(&config.kraken.usdc_account, None), WhichExchange::Second),
+        (TRANSFERRING_COIN, TRANSFERRING_NETWORK, Target::Address(&config.binance.usdc_account, None), WhichExchange::Exchange),
+        (OPERATING_COIN, OPERATING_NETWORK, Target::Address(&config.kraken.atom_account, config.kraken.atom_memo.as_deref()), WhichExchange::Second),
+        (OPERATING_COIN, OPERATING_NETWORK, Target::KrakenKey(&config.kraken.atom_withdrawal_key), WhichExchange::Exchange),
+    ];
+    for (asset, network, target, owner) in destinations {
+        allowlist.verify(asset, network, target, owner).await?;
+        println!("OK: {} ({}) to {}: {:?}", asset, network, get_exchange_name(owner), target);
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destination(asset: &str, network: &str, address: &str, memo: Option<&str>) -> AllowedDestination {
+        AllowedDestination { asset: asset.to_owned(), network: network.to_owned(), address: address.to_owned(), memo: memo.map(str::to_owned) }
+    }
+
+    fn signed_config() -> AllowlistConfig {
+        let mut cfg = AllowlistConfig {
+            enabled: true,
+            signing_key: Some(Secret::new("signing key".to_owned())),
+            destinations: vec![
+                destination(TRANSFERRING_COIN, TRANSFERRING_NETWORK, "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", None),
+                destination(OPERATING_COIN, OPERATING_NETWORK, "cosmos1abcdef", Some("12345")),
+            ],
+            ..AllowlistConfig::default()
+        };
+        cfg.signature = sign(&cfg).unwrap();
+        cfg
+    }
+
+    #[test]
+    fn eip55_vectors() {
+        for address in ["0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+                        "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+                        "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+                        "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb"] {
+            assert!(is_eth_address(address), "{}", address);
+            assert!(is_checksummed(address), "{}", address);
+            assert!(!is_checksummed(&address.to_lowercase()), "{}", address);
+            assert!(!is_checksummed(&format!("0x{}", address[2..].to_uppercase())), "{}", address);
+        }
+        // One letter of wrong case
+        assert!(!is_checksummed("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD"));
+    }
+
+    #[test]
+    fn signature_round_trip() {
+        let cfg = signed_config();
+        assert_eq!(check_allowlist(&cfg), Ok(()));
+        // Order of destinations isn't signed
+        let mut reordered = cfg.clone();
+        reordered.destinations.reverse();
+        assert_eq!(check_allowlist(&reordered), Ok(()));
+    }
+
+    #[test]
+    fn tampered_allowlist_is_rejected() {
+        let cfg = signed_config();
+        let mut changed_address = cfg.clone();
+        changed_address.destinations[1].address = "cosmos1attacker".to_owned();
+        assert!(check_allowlist(&changed_address).is_err());
+        let mut changed_memo = cfg.clone();
+        changed_memo.destinations[1].memo = Some("54321".to_owned());
+        assert!(check_allowlist(&changed_memo).is_err());
+        let mut removed_memo = cfg.clone();
+        removed_memo.destinations[1].memo = None;
+        assert!(check_allowlist(&removed_memo).is_err());
+        let mut added = cfg.clone();
+        added.destinations.push(destination(OPERATING_COIN, OPERATING_NETWORK, "cosmos1attacker", None));
+        assert!(check_allowlist(&added).is_err());
+        let mut other_key = cfg;
+        other_key.signing_key = Some(Secret::new("other key".to_owned()));
+        assert!(check_allowlist(&other_key).is_err());
+    }
+
+    #[test]
+    fn unchecksummed_address_is_rejected() {
+        let mut cfg = signed_config();
+        cfg.destinations[0].address = cfg.destinations[0].address.to_lowercase();
+        cfg.signature = sign(&cfg).unwrap();
+        assert!(check_allowlist(&cfg).unwrap_err().contains("checksummed"));
+    }
+
+    #[test]
+    fn separator_in_field_is_rejected() {
+        let mut cfg = signed_config();
+        cfg.destinations[1].memo = Some("1\tcosmos1attacker".to_owned());
+        cfg.signature = sign(&cfg).unwrap();
+        assert!(check_allowlist(&cfg).unwrap_err().contains("tab or newline"));
+    }
+
+    #[test]
+    fn destination_matches() {
+        let eth = Destination::new("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed", Some(""));
+        assert_eq!(eth.memo, None);
+        assert!(eth.matches("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", None));
+        assert!(!eth.matches("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", Some("1")));
+        let atom = Destination::new("cosmos1abcdef", Some("12345"));
+        assert!(atom.matches("cosmos1abcdef", Some("12345")));
+        assert!(!atom.matches("cosmos1abcdef", None));
+        assert!(!atom.matches("cosmos1abcdef", Some("54321")));
+        assert!(!atom.matches("COSMOS1ABCDEF", Some("12345")));
+    }
+
+    #[test]
+    fn destination_from_json() {
+        let entry = serde_json::json!({ "address": "cosmos1abcdef", "tag": 12345 });
+        assert_eq!(Destination::from_json(&entry), Some(Destination::new("cosmos1abcdef", Some("12345"))));
+        let entry = serde_json::json!({ "address": "cosmos1abcdef", "memo": "777" });
+        assert_eq!(Destination::from_json(&entry), Some(Destination::new("cosmos1abcdef", Some("777"))));
+        let entry = serde_json::json!({ "address": "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", "tag": "" });
+        assert_eq!(Destination::from_json(&entry).unwrap().memo, None);
+        assert_eq!(Destination::from_json(&serde_json::json!({ "tag": "1" })), None);
+    }
+}
@@ -0,0 +1,422 @@
+/// HTTP control and status API (served alongside `/metrics`)
+///
+/// * `GET /status` -- current state, last cycle time, pause flag;
+/// * `GET /balances` -- the latest balances;
+/// * `GET /plan` -- what strategy would do next;
+/// * `GET /approvals` -- actions waiting for approval (see `approval.rs`);
+/// * `POST /pause`, `POST /resume`, `POST /actions/{name}`, `POST /approvals/{id}/approve`,
+///   `POST /approvals/{id}/reject` -- control (requires `Authorization: Bearer <token>`).
+///
+
+use lazy_static::*;
+use log::{ info, warn };
+use rust_decimal::prelude::ToPrimitive;
+use serde::{ Deserialize, Serialize };
+use std::collections::{ BTreeMap, VecDeque };
+use std::sync::{ Mutex, RwLock };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use warp::Filter;
+use warp::http::StatusCode;
+use warp::reply::{ json, with_status, Json, WithStatus };
+
+use crate::actions;
+use crate::approval;
+use crate::config::ApiConfig;
+use crate::consts::get_exchange_name;
+use crate::control;
+use crate::fees::Route;
+use crate::monitoring;
+use crate::strategy::StrategyState;
+use crate::types::*;
+
+
+/// Snapshot of strategy status, updated by strategy loop.
+///
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Status {
+    /// State processed in the last cycle
+    pub state: Option<StrategyState>,
+    /// Unix timestamp of the last finished cycle
+    pub last_cycle_timestamp: Option<u64>,
+    pub balances: Option<Balances>,
+    /// What strategy would do next
+    pub plan: Option<StrategyState>,
+    /// Human readable description of the plan (the same as in notification)
+    pub plan_message: Option<String>,
+    /// Connectivity of exchanges (by name)
+    pub venues: BTreeMap<String, VenueStatus>,
+    /// E1/E2 ratio and its bands (as fractions, not percents)
+    pub ratio: Option<Value>,
+    pub ratio_low: Option<Value>,
+    pub ratio_high: Option<Value>,
+    /// Average prices of operating coin (by exchange name)
+    pub prices: BTreeMap<String, Price>,
+    /// The latest actions run by strategy (the newest is the last)
+    pub recent_actions: VecDeque<ActionRecord>,
+    /// Transfers waiting for arrival
+    pub transfers: Vec<TransferInFlight>,
+}
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActionRecord {
+    pub name: String,
+    pub value: Option<Value>,
+    /// Unix timestamp of finish
+    pub timestamp: u64,
+    pub error: Option<String>,
+}
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferInFlight {
+    pub id: u64,
+    pub route: String,
+    pub coin: String,
+    pub amount: Value,
+    /// Unix timestamp of start
+    pub timestamp: u64,
+}
+
+
+/// Removes transfer from status and resets in-transit balance of destination when dropped
+/// (so failed waiting doesn't leave them).
+///
+pub struct InFlightGuard {
+    id: u64,
+    destination: WhichExchange,
+    coin: String,
+}
+
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        STATUS.write().unwrap().transfers.retain(|t| t.id != self.id);
+        monitoring::set_balance(self.destination, &self.coin, monitoring::BalanceKind::InTransit, 0.0);
+    }
+}
+
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VenueStatus {
+    /// Is the last request successful
+    pub ok: bool,
+    /// Unix timestamp of the last successful request
+    pub last_ok_timestamp: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+
+/// Action requested by operator.
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct ActionRequest {
+    #[serde(default)]
+    pub name: String,
+    pub value: Option<Value>,
+}
+
+
+/// Number of actions kept in `Status::recent_actions`
+const RECENT_ACTIONS_COUNT: usize = 20;
+
+
+lazy_static! {
+    static ref STATUS: RwLock<Status> = RwLock::new(Default::default());
+    static ref TRANSFER_ID: Mutex<u64> = Mutex::new(0);
+    static ref ACTIONS: Mutex<VecDeque<ActionRequest>> = Mutex::new(VecDeque::new());
+}
+
+
+pub fn now_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+pub fn status() -> Status {
+    STATUS.read().unwrap().clone()
+}
+
+
+pub fn set_balances(balances: Balances) {
+    STATUS.write().unwrap().balances = Some(balances);
+}
+
+
+pub fn set_venue_status<A>(we: WhichExchange, result: &StrategyResult<A>) {
+    let mut status = STATUS.write().unwrap();
+    let venue = status.venues.entry(get_exchange_name(we).to_owned()).or_default();
+    match result {
+        Ok(_) => {
+            venue.ok = true;
+            venue.last_ok_timestamp = Some(now_timestamp());
+            venue.last_error = None;
+        },
+        Err(err) => {
+            venue.ok = false;
+            venue.last_error = Some(err.to_string());
+        },
+    }
+}
+
+
+pub fn set_plan(plan: StrategyState, msg: &str) {
+    let mut status = STATUS.write().unwrap();
+    status.plan = Some(plan);
+    status.plan_message = Some(msg.to_owned());
+}
+
+
+pub fn set_state(state: StrategyState) {
+    STATUS.write().unwrap().state = Some(state);
+}
+
+
+pub fn set_market(ratio: Value, ratio_low: Value, ratio_high: Value, prices: &[(&str, Price)]) {
+    let mut status = STATUS.write().unwrap();
+    status.ratio = Some(ratio);
+    status.ratio_low = Some(ratio_low);
+    status.ratio_high = Some(ratio_high);
+    status.prices = prices.iter().map(|(name, price)| (name.to_string(), *price)).collect();
+}
+
+
+pub fn observe_action(name: &str, value: Option<Value>, result: &ActionResult) {
+    let mut status = STATUS.write().unwrap();
+    status.recent_actions.push_back(ActionRecord {
+        name: name.to_owned(),
+        value,
+        timestamp: now_timestamp(),
+        error: result.as_ref().err().map(|err| err.to_string()),
+    });
+    while status.recent_actions.len() > RECENT_ACTIONS_COUNT {
+        status.recent_actions.pop_front();
+    }
+}
+
+
+/// Add transfer to status and in-transit balance of `destination` until returned guard is dropped.
+///
+pub fn transfer_in_flight(route: Route, destination: WhichExchange, coin: &str, amount: Value) -> InFlightGuard {
+    let id = {
+        let mut last_id = TRANSFER_ID.lock().unwrap();
+        *last_id += 1;
+        *last_id
+    };
+    STATUS.write().unwrap().transfers.push(TransferInFlight {
+        id, route: route.to_string(), coin: coin.to_owned(), amount, timestamp: now_timestamp() });
+    monitoring::set_balance(destination, coin, monitoring::BalanceKind::InTransit, amount.to_f64().unwrap_or(f64::NAN));
+    InFlightGuard { id, destination, coin: coin.to_owned() }
+}
+
+
+pub fn set_cycle_done() {
+    STATUS.write().unwrap().last_cycle_timestamp = Some(now_timestamp());
+}
+
+
+/// Next action requested by operator (if any).
+///
+pub fn pop_action() -> Option<ActionRequest> {
+    ACTIONS.lock().unwrap().pop_front()
+}
+
+
+/// Queue action to be run by strategy loop, returns position in queue.
+///
+pub fn push_action(action: ActionRequest) -> usize {
+    let mut actions = ACTIONS.lock().unwrap();
+    actions.push_back(action);
+    actions.len()
+}
+
+
+type ApiReply = WithStatus<Json>;
+
+
+fn ok<T: Serialize>(value: &T) -> ApiReply {
+    with_status(json(value), StatusCode::OK)
+}
+
+
+fn is_authorized(token: &Option<String>, header: &Option<String>) -> bool {
+    match (token, header) {
+        (Some(token), Some(header)) => header.strip_prefix("Bearer ").map(|t| t.trim() == token).unwrap_or(false),
+        _ => false,
+    }
+}
+
+
+fn unauthorized() -> ApiReply {
+    with_status(json(&serde_json::json!({ "error": "unauthorized" })), StatusCode::UNAUTHORIZED)
+}
+
+
+fn status_handler() -> ApiReply {
+    let status = status();
+    ok(&serde_json::json!({
+        "state": status.state,
+        "last_cycle_timestamp": status.last_cycle_timestamp,
+        "paused": control::is_paused(),
+        "venues": status.venues,
+        "ratio": status.ratio,
+        "ratio_low": status.ratio_low,
+        "ratio_high": status.ratio_high,
+        "prices": status.prices,
+        "recent_actions": status.recent_actions,
+        "transfers": status.transfers,
+    }))
+}
+
+
+fn balances_handler() -> ApiReply {
+    ok(&serde_json::json!({ "balances": status().balances }))
+}
+
+
+fn plan_handler() -> ApiReply {
+    let status = status();
+    ok(&serde_json::json!({
+        "plan": status.plan,
+        "message": status.plan_message,
+        "paused": control::is_paused(),
+    }))
+}
+
+
+fn pause_handler(paused: bool, token: Option<String>, auth: Option<String>) -> ApiReply {
+    if !is_authorized(&token, &auth) {
+        warn!("HTTP API: unauthorized request to {}", if paused { "pause" } else { "resume" });
+        return unauthorized();
+    }
+    control::set_paused(paused, "HTTP API");
+    ok(&serde_json::json!({ "paused": control::is_paused() }))
+}
+
+
+/// Body of action request (empty body -- no value, so default amount is used).
+///
+fn parse_action_body(body: &[u8]) -> Result<Option<ActionRequest>, String> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    serde_json::from_slice(body).map(Some).map_err(|err| format!("bad request body: {}", err))
+}
+
+
+fn action_handler(name: String, token: Option<String>, auth: Option<String>, body: Result<Option<ActionRequest>, String>) -> ApiReply {
+    if !is_authorized(&token, &auth) {
+        warn!("HTTP API: unauthorized request to run action \"{}\"", name);
+        return unauthorized();
+    }
+    // NOTE: unparsed value must not turn into default (the whole amount for many actions)
+    let body = match body {
+        Ok(body) => body,
+        Err(msg) => {
+            warn!("HTTP API: action \"{}\" is refused: {}", name, msg);
+            return with_status(json(&serde_json::json!({ "error": msg })), StatusCode::BAD_REQUEST);
+        },
+    };
+    let value = body.and_then(|b| b.value);
+    let checked = actions::find_action(&name).ok_or_else(|| format!("No such action \"{}\"", name))
+        .and_then(|action| action.param.check(value));
+    if let Err(msg) = checked {
+        warn!("HTTP API: action \"{}\" is refused: {}", name, msg);
+        return with_status(json(&serde_json::json!({ "error": msg })), StatusCode::BAD_REQUEST);
+    }
+    info!(target: "NOTIFICATION", "HTTP API: action \"{}\" ({:?}) is queued", name, value);
+    let position = push_action(ActionRequest { name: name.clone(), value });
+    with_status(json(&serde_json::json!({ "queued": name, "value": value, "position": position })), StatusCode::ACCEPTED)
+}
+
+
+fn approvals_handler() -> ApiReply {
+    ok(&serde_json::json!({ "pending": approval::pending() }))
+}
+
+
+fn decision_handler(id: u64, decision: String, token: Option<String>, auth: Option<String>) -> ApiReply {
+    if !is_authorized(&token, &auth) {
+        warn!("HTTP API: unauthorized request to {} #{}", decision, id);
+        return unauthorized();
+    }
+    let is_approved = match decision.as_str() {
+        "approve" => true,
+        "reject" => false,
+        _ => return with_status(json(&serde_json::json!({ "error": "decision must be approve or reject" })), StatusCode::NOT_FOUND),
+    };
+    match approval::decide(id, is_approved, "HTTP API") {
+        Ok(request) => ok(&serde_json::json!({ "decided": request, "approved": is_approved })),
+        Err(err) => with_status(json(&serde_json::json!({ "error": err })), StatusCode::CONFLICT),
+    }
+}
+
+
+/// All routes of API.
+///
+pub fn routes(cfg: &ApiConfig) -> impl Filter<Extract = (ApiReply,), Error = warp::Rejection> + Clone {
+    let token = cfg.token.as_ref().map(|t| t.expose().to_owned());
+    let with_token = warp::any().map(move || token.clone());
+    let auth = warp::header::optional::<String>("authorization");
+
+    let status = warp::get().and(warp::path!("status")).map(status_handler);
+    let balances = warp::get().and(warp::path!("balances")).map(balances_handler);
+    let plan = warp::get().and(warp::path!("plan")).map(plan_handler);
+    let pause = warp::post().and(warp::path!("pause"))
+        .and(with_token.clone()).and(auth.clone())
+        .map(|token, auth| pause_handler(true, token, auth));
+    let resume = warp::post().and(warp::path!("resume"))
+        .and(with_token.clone()).and(auth.clone())
+        .map(|token, auth| pause_handler(false, token, auth));
+    let action = warp::post().and(warp::path!("actions" / String))
+        .and(with_token.clone()).and(auth.clone())
+        .and(warp::body::bytes())
+        .map(|name, token, auth, body: warp::hyper::body::Bytes| action_handler(name, token, auth, parse_action_body(&body)));
+    let approvals = warp::get().and(warp::path!("approvals")).map(approvals_handler);
+    let decision = warp::post().and(warp::path!("approvals" / u64 / String))
+        .and(with_token).and(auth)
+        .map(decision_handler);
+
+    status.or(balances).unify()
+        .or(plan).unify()
+        .or(pause).unify()
+        .or(resume).unify()
+        .or(action).unify()
+        .or(approvals).unify()
+        .or(decision).unify()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::Secret;
+
+    #[test]
+    fn action_body() {
+        assert!(parse_action_body(b"").unwrap().is_none());
+        assert!(parse_action_body(b" \n").unwrap().is_none());
+        assert_eq!(parse_action_body(br#"{"value":"30"}"#).unwrap().unwrap().value, Some(Value::from(30)));
+        assert_eq!(parse_action_body(br#"{}"#).unwrap().unwrap().value, None);
+        assert!(parse_action_body(br#"{"value":"30%"}"#).is_err());
+        assert!(parse_action_body(br#"{"value":"10usdc"}"#).is_err());
+        assert!(parse_action_body(br#"{"value":"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_action_body_is_refused() {
+        let cfg = ApiConfig { token: Some(Secret::new("token".to_owned())) };
+        let routes = routes(&cfg);
+        for body in [r#"{"value":"30%"}"#, r#"{"value":"10usdc"}"#, r#"{"value":1"#] {
+            let reply = warp::test::request()
+                .method("POST")
+                .path("/actions/do_unwind")
+                .header("authorization", "Bearer token")
+                .body(body)
+                .reply(&routes)
+                .await;
+            assert_eq!(reply.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        assert!(pop_action().is_none());
+    }
+}
@@ -0,0 +1,273 @@
+/// Human approval of large operations
+///
+/// Actions with notional above `notional_threshold` (in transferring coins) or listed in
+/// `actions` wait for decision of operator:
+///
+/// * Telegram inline buttons (see `bot.rs`),
+/// * HTTP API: `GET /approvals`, `POST /approvals/{id}/approve`, `POST /approvals/{id}/reject`.
+///
+/// Waiting doesn't block strategy: action is parked and checked again on the next cycles (with
+/// the current state and amount). Request which isn't decided within `timeout` is rejected.
+/// Every decision is appended to `log_file` (JSON lines) and notified.
+///
+
+use lazy_static::*;
+use log::{ debug, error, info, warn };
+use serde::Serialize;
+use std::collections::{ BTreeMap, HashMap };
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::api::now_timestamp;
+use crate::config::ApprovalConfig;
+use crate::consts::*;
+use crate::types::*;
+
+
+/// Value of funds moved by action.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notional {
+    /// Action doesn't move funds
+    NoFunds,
+    /// In transferring coins
+    Amount(Value),
+    /// Amount isn't given, so action can move the whole balance
+    Unknown,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, strum_macros::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Decision {
+    Approved,
+    Rejected,
+    /// Not decided in time (counts as rejection)
+    Expired,
+}
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApprovalRequest {
+    pub id: u64,
+    pub action: String,
+    pub value: Option<Value>,
+    /// In transferring coins (if known)
+    pub notional: Option<Value>,
+    /// Why approval is required
+    pub reason: String,
+    /// Requested by operator (not by strategy)
+    pub is_requested: bool,
+    pub created_timestamp: u64,
+    pub expires_timestamp: u64,
+}
+
+
+/// Record of decision (in `log_file`).
+///
+#[derive(Debug, Serialize)]
+struct DecisionRecord<'a> {
+    timestamp: u64,
+    decision: Decision,
+    /// Who decided
+    source: &'a str,
+    request: &'a ApprovalRequest,
+}
+
+
+struct PendingApproval {
+    request: ApprovalRequest,
+    notional: Notional,
+    decision: Option<(Decision, String)>,
+}
+
+
+lazy_static! {
+    static ref PENDING: Mutex<BTreeMap<u64, PendingApproval>> = Mutex::new(BTreeMap::new());
+    /// Timestamps of the last rejections by action names
+    static ref REJECTED: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+
+/// Reason of approval of `action` (`None` if it isn't required).
+///
+pub fn required(cfg: &ApprovalConfig, action: &str, notional: Notional) -> Option<String> {
+    if !cfg.enabled {
+        return None;
+    }
+    if cfg.actions.iter().any(|a| a == action) {
+        return Some(format!("\"{}\" always requires approval", action));
+    }
+    match (cfg.notional_threshold, notional) {
+        (Some(threshold), Notional::Amount(amount)) if amount > threshold =>
+            Some(format!("notional {:.2} {} is above {:.2} {}", amount, TRANSFERRING_COIN, threshold, TRANSFERRING_COIN)),
+        (Some(_), Notional::Unknown) => Some("amount isn't given (the whole balance can be moved)".to_owned()),
+        _ => None,
+    }
+}
+
+
+/// Requests waiting for decision.
+///
+pub fn pending() -> Vec<ApprovalRequest> {
+    PENDING.lock().unwrap().values()
+        .filter(|p| p.decision.is_none())
+        .map(|p| p.request.clone())
+        .collect()
+}
+
+
+/// Approve or reject request `id`, `source` is who decided (for records).
+///
+pub fn decide(id: u64, is_approved: bool, source: &str) -> Result<ApprovalRequest, String> {
+    let mut pending = PENDING.lock().unwrap();
+    match pending.get_mut(&id) {
+        None => Err(format!("No approval request #{}", id)),
+        Some(p) if p.decision.is_some() => Err(format!("Approval request #{} is already decided", id)),
+        Some(p) => {
+            let decision = if is_approved { Decision::Approved } else { Decision::Rejected };
+            p.decision = Some((decision, source.to_owned()));
+            Ok(p.request.clone())
+        },
+    }
+}
+
+
+fn record_decision(cfg: &ApprovalConfig, request: &ApprovalRequest, decision: Decision, source: &str) {
+    info!(target: "NOTIFICATION", "Approval #{} of \"{}\" ({:?}): {} by {}", request.id, request.action, request.value, decision, source);
+    let record = DecisionRecord { timestamp: now_timestamp(), decision, source, request };
+    let line = match serde_json::to_string(&record) {
+        Ok(line) => line,
+        Err(err) => {
+            error!("Can't serialize approval decision: {}", err);
+            return;
+        },
+    };
+    let result = OpenOptions::new().create(true).append(true).open(&cfg.log_file)
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(err) = result {
+        error!("Can't record approval decision to {}: {}", cfg.log_file, err);
+    }
+}
+
+
+/// Approval state of action.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Approval {
+    /// Action can be run
+    Approved,
+    /// Request is waiting for decision: action is parked, ask again on the next cycle
+    Pending,
+    Rejected,
+}
+
+
+/// Does approved `notional` cover the current one?
+///
+fn covers(approved: Notional, current: Notional) -> bool {
+    match (approved, current) {
+        (_, Notional::NoFunds) => true,
+        // Operator approved moving of the whole balance
+        (Notional::Unknown, _) => true,
+        (Notional::Amount(approved), Notional::Amount(current)) => current <= approved,
+        _ => false,
+    }
+}
+
+
+/// Remove decided request `id`: record decision and start cooldown if it isn't approved.
+///
+fn finish(cfg: &ApprovalConfig, id: u64, decision: Decision, source: &str) -> Option<PendingApproval> {
+    let finished = PENDING.lock().unwrap().remove(&id)?;
+    record_decision(cfg, &finished.request, decision, source);
+    if decision != Decision::Approved {
+        REJECTED.lock().unwrap().insert(finished.request.action.clone(), now_timestamp());
+    }
+    Some(finished)
+}
+
+
+/// Expire requests which aren't decided (or approved, but not run) in time.
+///
+fn expire(cfg: &ApprovalConfig) {
+    let now = now_timestamp();
+    let expired: Vec<(u64, Option<(Decision, String)>)> = PENDING.lock().unwrap().values()
+        .filter(|p| now >= p.request.expires_timestamp)
+        .map(|p| (p.request.id, p.decision.clone()))
+        .collect();
+    for (id, decision) in expired {
+        match decision {
+            None => { finish(cfg, id, Decision::Expired, "timeout"); },
+            Some((decision, source)) => {
+                if let Some(p) = finish(cfg, id, decision, &source) {
+                    if decision == Decision::Approved {
+                        warn!("Approved \"{}\" (#{}) isn't run in time, approval is dropped", p.request.action, id);
+                    }
+                }
+            },
+        }
+    }
+}
+
+
+/// Check approval of `action` (if it is required) without waiting.
+///
+/// The first call makes request and returns `Pending`, the next calls (on the next cycles)
+/// return decision. Approval is used only if approved notional covers the current one,
+/// otherwise approval is requested again. Requested actions (`is_requested`) and actions of
+/// strategy are approved separately.
+///
+pub fn approve(cfg: &ApprovalConfig, action: &str, value: Option<Value>, notional: Notional, is_requested: bool) -> Approval {
+    let reason = match required(cfg, action, notional) {
+        None => return Approval::Approved,
+        Some(reason) => reason,
+    };
+    expire(cfg);
+    let parked = PENDING.lock().unwrap().values()
+        .find(|p| p.request.action == action && p.request.is_requested == is_requested)
+        .map(|p| (p.request.id, p.decision.clone()));
+    match parked {
+        Some((_, None)) => return Approval::Pending,
+        Some((id, Some((decision, source)))) => {
+            let approved = match finish(cfg, id, decision, &source) {
+                Some(p) if decision == Decision::Approved => p,
+                _ => return Approval::Rejected,
+            };
+            if covers(approved.notional, notional) {
+                return Approval::Approved;
+            }
+            warn!(target: "NOTIFICATION", "Approval #{} of \"{}\" doesn't cover the current notional ({:?}, approved: {:?}), it is requested again",
+                  id, action, notional, approved.notional);
+        },
+        None => {
+            if let Some(rejected) = REJECTED.lock().unwrap().get(action) {
+                if now_timestamp() < rejected + cfg.reject_cooldown {
+                    debug!("\"{}\" was rejected recently, it isn't requested again", action);
+                    return Approval::Rejected;
+                }
+            }
+        },
+    }
+    let now = now_timestamp();
+    let request = ApprovalRequest {
+        id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+        action: action.to_owned(),
+        value,
+        notional: match notional { Notional::Amount(amount) => Some(amount), _ => None },
+        reason,
+        is_requested,
+        created_timestamp: now,
+        expires_timestamp: now + cfg.timeout,
+    };
+    warn!(target: "NOTIFICATION", "APPROVAL REQUIRED #{}: \"{}\" ({:?}), {}; expires in {} seconds",
+          request.id, action, value, request.reason, cfg.timeout);
+    PENDING.lock().unwrap().insert(request.id, PendingApproval { request, notional, decision: None });
+    Approval::Pending
+}
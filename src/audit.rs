@@ -0,0 +1,259 @@
+/// Audit log: one JSON line per external side effect (order, withdrawal, deposit, staking)
+///
+/// Record holds venue request and response, ID of operation, pre and post balances and
+/// triggering state (or requested action). Records are hash-chained: `hash` is SHA-256 of
+/// the record without `hash` field, and it includes `prev_hash` of the previous record, so
+/// changed, removed or inserted records are detected by `audit verify`.
+///
+
+use lazy_static::*;
+use log::error;
+use serde::Serialize;
+use sha2::{ Digest, Sha256 };
+use std::fs::OpenOptions;
+use std::io::{ BufRead, BufReader, Write };
+use std::sync::Mutex;
+
+use crate::api::now_timestamp;
+use crate::consts::get_exchange_name;
+use crate::strategy::StrategyState;
+use crate::types::*;
+
+
+/// `prev_hash` of the first record
+static GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+
+/// What caused side effects.
+///
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Trigger {
+    /// State processed by strategy (`None` for requested actions)
+    pub state: Option<StrategyState>,
+    pub action: String,
+}
+
+
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    seq: u64,
+    timestamp: u64,
+    venue: &'a str,
+    operation: &'a str,
+    request: String,
+    response: String,
+    id: Option<String>,
+    pre_balances: Option<Balances>,
+    post_balances: Option<Balances>,
+    trigger: Trigger,
+    prev_hash: String,
+}
+
+
+lazy_static! {
+    static ref TRIGGER: Mutex<Trigger> = Mutex::new(Trigger::default());
+    /// Sequence number and hash of the last record (`None` until read from file)
+    static ref LAST: Mutex<Option<(u64, String)>> = Mutex::new(None);
+}
+
+
+/// Set cause of the next side effects.
+///
+pub fn set_trigger(state: Option<StrategyState>, action: &str) {
+    *TRIGGER.lock().unwrap() = Trigger { state, action: action.to_owned() };
+}
+
+
+fn hash_of(record: &serde_json::Value) -> String {
+    Sha256::digest(record.to_string().as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+/// Sequence number and hash of the last record in `file`.
+///
+fn read_last(file: &str) -> Result<(u64, String), String> {
+    let last_line = match std::fs::File::open(file) {
+        Ok(f) => BufReader::new(f).lines().map_while(Result::ok).filter(|l| !l.trim().is_empty()).last(),
+        Err(_) => None,
+    };
+    match last_line {
+        None => Ok((0, GENESIS_HASH.to_owned())),
+        Some(line) => {
+            let record: serde_json::Value = serde_json::from_str(&line).map_err(|err| format!("Bad last record: {}", err))?;
+            Ok((record["seq"].as_u64().unwrap_or(0), record["hash"].as_str().unwrap_or_default().to_owned()))
+        },
+    }
+}
+
+
+/// Append record of side effect `operation` on `we` (post balances are `ctx.balances`).
+///
+/// `request` and `response` are `{:?}` of venue request and result of call (errors are recorded too).
+///
+pub fn record<A: Access>(ctx: &Context<A>, we: WhichExchange, operation: &str, request: String, response: String,
+                         id: Option<String>, pre_balances: Option<Balances>) {
+    let mut last = LAST.lock().unwrap();
+    if last.is_none() {
+        match read_last(&ctx.audit.file) {
+            Ok(l) => *last = Some(l),
+            Err(err) => {
+                error!(target: "NOTIFICATION", "Can't read audit log {}: {}, {} on {} isn't recorded", ctx.audit.file, err, operation, get_exchange_name(we));
+                return;
+            },
+        }
+    }
+    let (last_seq, last_hash) = last.clone().unwrap();
+    let record = AuditRecord {
+        seq: last_seq + 1,
+        timestamp: now_timestamp(),
+        venue: get_exchange_name(we),
+        operation,
+        request,
+        response,
+        id,
+        pre_balances,
+        post_balances: ctx.balances,
+        trigger: TRIGGER.lock().unwrap().clone(),
+        prev_hash: last_hash,
+    };
+    let mut value = match serde_json::to_value(&record) {
+        Ok(value) => value,
+        Err(err) => {
+            error!(target: "NOTIFICATION", "Can't serialize audit record of {} on {}: {}", operation, get_exchange_name(we), err);
+            return;
+        },
+    };
+    let hash = hash_of(&value);
+    value["hash"] = serde_json::Value::String(hash.clone());
+    let result = OpenOptions::new().create(true).append(true).open(&ctx.audit.file)
+        .and_then(|mut file| writeln!(file, "{}", value));
+    match result {
+        Ok(_) => *last = Some((record.seq, hash)),
+        Err(err) => error!(target: "NOTIFICATION", "Can't write audit record of {} on {} to {}: {}",
+                           operation, get_exchange_name(we), ctx.audit.file, err),
+    }
+}
+
+
+/// Check hash chain of `file`, returns number of records and hash of the last one
+/// (removed tail can be detected only by comparing it with previously known hash).
+///
+pub fn verify(file: &str) -> Result<(u64, String), String> {
+    let f = std::fs::File::open(file).map_err(|err| format!("Can't open {}: {}", file, err))?;
+    let mut prev_hash = GENESIS_HASH.to_owned();
+    let mut count = 0;
+    for (n, line) in BufReader::new(f).lines().enumerate() {
+        let line = line.map_err(|err| err.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_no = n + 1;
+        let mut record: serde_json::Value = serde_json::from_str(&line).map_err(|err| format!("Line {}: bad record: {}", line_no, err))?;
+        let hash = record.as_object_mut().and_then(|r| r.remove("hash"))
+            .and_then(|h| h.as_str().map(str::to_owned))
+            .ok_or_else(|| format!("Line {}: no hash", line_no))?;
+        if record["seq"].as_u64() != Some(count + 1) {
+            return Err(format!("Line {}: sequence number {} instead of {} (record is removed or inserted)", line_no, record["seq"], count + 1));
+        }
+        if record["prev_hash"].as_str() != Some(prev_hash.as_str()) {
+            return Err(format!("Line {}: previous hash doesn't match (record is removed or inserted)", line_no));
+        }
+        if hash_of(&record) != hash {
+            return Err(format!("Line {}: hash doesn't match (record is changed)", line_no));
+        }
+        prev_hash = hash;
+        count += 1;
+    }
+    Ok((count, prev_hash))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Chained lines as `record` writes them.
+    fn chain(responses: &[&str]) -> Vec<String> {
+        let mut prev_hash = GENESIS_HASH.to_owned();
+        responses.iter().enumerate().map(|(n, response)| {
+            let mut value = serde_json::json!({
+                "seq": n + 1,
+                "timestamp": 1_700_000_000 + n,
+                "venue": "Kraken",
+                "operation": "withdraw",
+                "request": "100 USDC",
+                "response": response,
+                "prev_hash": prev_hash,
+            });
+            prev_hash = hash_of(&value);
+            value["hash"] = serde_json::Value::String(prev_hash.clone());
+            value.to_string()
+        }).collect()
+    }
+
+    fn verify_lines(name: &str, lines: &[String]) -> Result<(u64, String), String> {
+        let file = std::env::temp_dir().join(format!("audit-{}-{}.jsonl", name, std::process::id()));
+        std::fs::write(&file, lines.join("\n") + "\n").unwrap();
+        let result = verify(file.to_str().unwrap());
+        std::fs::remove_file(&file).unwrap();
+        result
+    }
+
+    fn rehash(line: &str, change: impl FnOnce(&mut serde_json::Value)) -> String {
+        let mut value: serde_json::Value = serde_json::from_str(line).unwrap();
+        value.as_object_mut().unwrap().remove("hash");
+        change(&mut value);
+        value["hash"] = serde_json::Value::String(hash_of(&value));
+        value.to_string()
+    }
+
+    #[test]
+    fn intact_chain() {
+        let lines = chain(&["ok 1", "ok 2", "ok 3"]);
+        let (count, last_hash) = verify_lines("intact", &lines).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&lines[2]).unwrap()["hash"], last_hash);
+        assert_eq!(verify_lines("empty", &[]), Ok((0, GENESIS_HASH.to_owned())));
+    }
+
+    #[test]
+    fn edited_line() {
+        let mut lines = chain(&["ok 1", "ok 2", "ok 3"]);
+        lines[1] = lines[1].replace("ok 2", "ok 9");
+        assert!(verify_lines("edited", &lines).unwrap_err().starts_with("Line 2: hash"));
+        // Edited line with recomputed hash breaks the next link
+        let mut lines = chain(&["ok 1", "ok 2", "ok 3"]);
+        lines[1] = rehash(&lines[1], |v| v["response"] = "ok 9".into());
+        assert!(verify_lines("rehashed", &lines).unwrap_err().starts_with("Line 3: previous hash"));
+    }
+
+    #[test]
+    fn removed_line() {
+        let mut lines = chain(&["ok 1", "ok 2", "ok 3"]);
+        lines.remove(1);
+        assert!(verify_lines("removed", &lines).unwrap_err().starts_with("Line 2: sequence"));
+        // Renumbered records are still not linked
+        let mut lines = chain(&["ok 1", "ok 2", "ok 3"]);
+        lines.remove(1);
+        lines[1] = rehash(&lines[1], |v| v["seq"] = 2.into());
+        assert!(verify_lines("renumbered", &lines).unwrap_err().starts_with("Line 2: previous hash"));
+    }
+
+    #[test]
+    fn inserted_line() {
+        let mut lines = chain(&["ok 1", "ok 2", "ok 3"]);
+        let copy = lines[0].clone();
+        lines.insert(1, copy);
+        assert!(verify_lines("inserted", &lines).unwrap_err().starts_with("Line 2: sequence"));
+        // Forged record linked to the previous one breaks the next record
+        let mut lines = chain(&["ok 1", "ok 2", "ok 3"]);
+        let prev_hash = serde_json::from_str::<serde_json::Value>(&lines[1]).unwrap()["hash"].clone();
+        let forged = rehash(&lines[1], |v| {
+            v["seq"] = 3.into();
+            v["prev_hash"] = prev_hash;
+            v["response"] = "forged".into();
+        });
+        lines.insert(2, forged);
+        assert!(verify_lines("forged", &lines).unwrap_err().starts_with("Line 4: sequence"));
+    }
+}
@@ -0,0 +1,365 @@
+/// Telegram bot: commands from whitelisted chats
+///
+/// * `/status`, `/balances`, `/ratio`, `/plan` -- the same information as in notifications;
+/// * `/pause`, `/resume` -- pause and resume strategy;
+/// * `/unwind [percent]` -- unwind positions (must be confirmed by `/confirm_unwind <code>`);
+/// * `/approvals` -- actions waiting for approval.
+///
+/// Actions waiting for approval (see `approval.rs`) are sent to whitelisted chats with
+/// "Approve" and "Reject" buttons.
+///
+/// Bot must have its own token: Telegram gives updates to one poller only (others get
+/// `409 Conflict`), so instances sharing a token would steal commands from each other.
+///
+
+use log::{ debug, error, info, warn };
+use rand::Rng;
+use std::collections::{ HashMap, HashSet };
+use std::thread;
+use std::time::{ Duration, Instant };
+use strum::EnumMessage;
+
+use crate::api;
+use crate::approval;
+use crate::cli::parse_percent;
+use crate::config::{ BotConfig, Config };
+use crate::consts::TRANSFERRING_COIN;
+use crate::control;
+use crate::notifiers;
+use crate::types::*;
+
+
+static HELP_MESSAGE: &str = "\
+/status -- current state\n\
+/balances -- balances\n\
+/ratio -- current ratio\n\
+/plan -- what strategy would do next\n\
+/pause -- pause strategy\n\
+/resume -- resume strategy\n\
+/unwind [percent] -- close positions and move funds to wallet\n\
+/approvals -- actions waiting for approval";
+
+
+/// Minimal interval between `info` records about the same not allowed chat (the rest are `debug`).
+///
+const IGNORED_CHAT_LOG_INTERVAL: Duration = Duration::from_secs(3600);
+
+
+/// Unwinding waiting for confirmation.
+///
+struct PendingUnwind {
+    code: String,
+    percent: Value,
+    expires: Instant,
+}
+
+
+/// Notification message of the last detected state (see `detect_current_state()`)
+/// split to ratio and balances parts.
+///
+fn plan_message_parts() -> Option<(String, String)> {
+    let msg = api::status().plan_message?;
+    match msg.split_once("\n\n") {
+        Some((ratio, balances)) => Some((ratio.to_owned(), balances.to_owned())),
+        None => Some((msg.clone(), msg)),
+    }
+}
+
+
+fn not_ready_message() -> String {
+    "Strategy didn't detect its state yet, try later".to_owned()
+}
+
+
+fn status_message() -> String {
+    let status = api::status();
+    let last_cycle = match status.last_cycle_timestamp {
+        Some(ts) => format!("{} seconds ago", api::now_timestamp().saturating_sub(ts)),
+        None => "never".to_owned(),
+    };
+    let venues: Vec<String> = status.venues.iter()
+        .map(|(name, v)| if v.ok { format!("{}: OK", name) }
+                         else { format!("{}: {}", name, v.last_error.as_deref().unwrap_or("error")) })
+        .collect();
+    format!("State: {}\nLast cycle: {}\nPaused: {}\n\n{}",
+            status.state.map(|s| format!("{:?}", s)).unwrap_or_else(|| "unknown".to_owned()),
+            last_cycle,
+            control::is_paused(),
+            venues.join("\n"))
+}
+
+
+fn plan_message() -> String {
+    let status = api::status();
+    match (status.plan, status.plan_message) {
+        (Some(plan), Some(msg)) => format!("{}\n\n{}{}",
+                                           msg,
+                                           plan.get_message().unwrap_or("?"),
+                                           if control::is_paused() { " (PAUSED)" } else { "" }),
+        _ => not_ready_message(),
+    }
+}
+
+
+fn request_unwind(chat_id: i64, args: &str, cfg: &BotConfig, pending: &mut HashMap<i64, PendingUnwind>) -> String {
+    let percent = if args.is_empty() { Value::from(100) } else {
+        match parse_percent(args) {
+            Ok(percent) if percent > Value::ZERO && percent <= Value::from(100) => percent,
+            _ => return format!("Bad percent: \"{}\"", args),
+        }
+    };
+    let code = format!("{:04}", rand::thread_rng().gen_range(0..10000));
+    let msg = format!("To unwind {}% of positions send\n/confirm_unwind {}\nwithin {} seconds", percent, code, cfg.confirmation_timeout);
+    pending.insert(chat_id, PendingUnwind { code, percent, expires: Instant::now() + Duration::from_secs(cfg.confirmation_timeout) });
+    msg
+}
+
+
+fn confirm_unwind(chat_id: i64, args: &str, pending: &mut HashMap<i64, PendingUnwind>) -> String {
+    match pending.remove(&chat_id) {
+        None => "Nothing to confirm, send /unwind first".to_owned(),
+        Some(unwind) if unwind.expires < Instant::now() => "Confirmation is expired, send /unwind again".to_owned(),
+        Some(unwind) if unwind.code != args => "Wrong confirmation code, send /unwind again".to_owned(),
+        Some(unwind) => {
+            info!(target: "NOTIFICATION", "Telegram bot: unwinding {}% is confirmed in chat {}", unwind.percent, chat_id);
+            let position = api::push_action(api::ActionRequest { name: "do_unwind".to_owned(), value: Some(unwind.percent) });
+            format!("Unwinding {}% is queued (position: {})", unwind.percent, position)
+        },
+    }
+}
+
+
+fn approval_message(request: &approval::ApprovalRequest) -> String {
+    format!("APPROVAL REQUIRED #{}\n\nAction: {}\nAmount: {}\nNotional: {}\nReason: {}\nExpires in {} seconds",
+            request.id,
+            request.action,
+            request.value.map(|v| v.to_string()).unwrap_or_else(|| "all".to_owned()),
+            request.notional.map(|v| format!("{:.2} {}", v, TRANSFERRING_COIN)).unwrap_or_else(|| "unknown".to_owned()),
+            request.reason,
+            request.expires_timestamp.saturating_sub(api::now_timestamp()))
+}
+
+
+fn approvals_message() -> String {
+    let pending = approval::pending();
+    if pending.is_empty() {
+        "No actions wait for approval".to_owned()
+    }
+    else {
+        pending.iter().map(approval_message).collect::<Vec<_>>().join("\n\n")
+    }
+}
+
+
+fn handle_command(text: &str, chat_id: i64, cfg: &BotConfig, pending: &mut HashMap<i64, PendingUnwind>) -> String {
+    let (command, args) = text.trim().split_once(' ').unwrap_or((text.trim(), ""));
+    // In group chats commands can be addressed: `/status@some_bot`
+    let command = command.split('@').next().unwrap_or(command);
+    let args = args.trim();
+    debug!("Telegram bot: command \"{}\" ({}) from chat {}", command, args, chat_id);
+    match command {
+        "/status" => status_message(),
+        "/balances" => plan_message_parts().map(|(_, balances)| balances).unwrap_or_else(not_ready_message),
+        "/ratio" => plan_message_parts().map(|(ratio, _)| ratio).unwrap_or_else(not_ready_message),
+        "/plan" => plan_message(),
+        "/pause" => {
+            control::set_paused(true, &format!("Telegram chat {}", chat_id));
+            "Paused".to_owned()
+        },
+        "/resume" => {
+            control::set_paused(false, &format!("Telegram chat {}", chat_id));
+            "Resumed".to_owned()
+        },
+        "/unwind" => request_unwind(chat_id, args, cfg, pending),
+        "/confirm_unwind" => confirm_unwind(chat_id, args, pending),
+        "/approvals" => approvals_message(),
+        _ => HELP_MESSAGE.to_owned(),
+    }
+}
+
+
+/// Incoming updates (long polling).
+///
+enum UpdatesError {
+    /// Another poller uses the same token
+    Conflict,
+    Failed(String),
+}
+
+
+fn get_updates(api_url: &str, token: &str, offset: i64, timeout: u64) -> Result<Vec<serde_json::Value>, UpdatesError> {
+    let response = ureq::get(&format!("{}/bot{}/getUpdates", api_url.trim_end_matches('/'), token))
+        .query("offset", &offset.to_string())
+        .query("timeout", &timeout.to_string())
+        .query("allowed_updates", "[\"message\", \"callback_query\"]")
+        .timeout(Duration::from_secs(timeout + 10))
+        .call()
+        .map_err(|err| match err {
+            ureq::Error::Status(409, _) => UpdatesError::Conflict,
+            err => UpdatesError::Failed(err.to_string()),
+        })?;
+    let answer: serde_json::Value = response.into_json().map_err(|err| UpdatesError::Failed(err.to_string()))?;
+    Ok(answer["result"].as_array().cloned().unwrap_or_default())
+}
+
+
+/// Handle pressed button of approval request (`approve:<id>` or `reject:<id>`).
+///
+fn handle_callback(query: &serde_json::Value, api_url: &str, token: &str) {
+    let chat_id = query["message"]["chat"]["id"].as_i64().unwrap_or_default();
+    let user = query["from"]["username"].as_str().map(|u| format!("@{}", u))
+        .unwrap_or_else(|| query["from"]["id"].to_string());
+    let reply = match query["data"].as_str().and_then(|d| d.split_once(':')) {
+        Some((decision @ ("approve" | "reject"), id)) => match id.parse::<u64>() {
+            Ok(id) => match approval::decide(id, decision == "approve", &format!("Telegram {} in chat {}", user, chat_id)) {
+                Ok(_) => format!("#{}: {}", id, if decision == "approve" { "approved" } else { "rejected" }),
+                Err(err) => err,
+            },
+            Err(_) => format!("Bad approval request id: \"{}\"", id),
+        },
+        _ => "Unknown button".to_owned(),
+    };
+    let answer = notifiers::call_telegram(api_url, token, "answerCallbackQuery", serde_json::json!({
+        "callback_query_id": query["id"],
+        "text": reply,
+    }));
+    // Remove buttons and show decision
+    let edit = notifiers::call_telegram(api_url, token, "editMessageText", serde_json::json!({
+        "chat_id": chat_id,
+        "message_id": query["message"]["message_id"],
+        "text": format!("{}\n\n{}", query["message"]["text"].as_str().unwrap_or_default(), reply),
+    }));
+    if answer.is_err() || edit.is_err() {
+        debug!("Telegram bot: can't answer button in chat {}", chat_id);
+    }
+}
+
+
+/// Send new approval requests with buttons to all whitelisted chats.
+///
+fn announce_approvals(cfg: BotConfig, api_url: String, token: String) {
+    let mut announced: HashSet<u64> = HashSet::new();
+    loop {
+        let pending = approval::pending();
+        for request in pending.iter().filter(|r| !announced.contains(&r.id)) {
+            let keyboard = serde_json::json!({ "inline_keyboard": [[
+                { "text": "Approve", "callback_data": format!("approve:{}", request.id) },
+                { "text": "Reject", "callback_data": format!("reject:{}", request.id) },
+            ]]});
+            for chat_id in cfg.allowed_chat_ids.iter() {
+                let sent = notifiers::call_telegram(&api_url, &token, "sendMessage", serde_json::json!({
+                    "text": approval_message(request),
+                    "chat_id": chat_id,
+                    "reply_markup": keyboard,
+                }));
+                if let Err(err) = sent {
+                    warn!("Telegram bot: can't send approval request #{} to chat {}: {}", request.id, chat_id, err.msg);
+                }
+            }
+            announced.insert(request.id);
+        }
+        announced.retain(|id| pending.iter().any(|r| r.id == *id));
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+
+/// Log update from not allowed chat.
+///
+/// NOTE: not `warn!()`, because it is notified, so anyone could flood alerts.
+///
+fn log_ignored(logged: &mut HashMap<Option<i64>, Instant>, chat_id: Option<i64>, what: &str) {
+    let now = Instant::now();
+    match logged.get(&chat_id) {
+        Some(last) if now.duration_since(*last) < IGNORED_CHAT_LOG_INTERVAL =>
+            debug!("Telegram bot: {} from not allowed chat {:?} is ignored", what, chat_id),
+        _ => {
+            info!("Telegram bot: {} from not allowed chat {:?} is ignored (the next ones are logged with debug level within {} seconds)",
+                  what, chat_id, IGNORED_CHAT_LOG_INTERVAL.as_secs());
+            logged.retain(|_, last| now.duration_since(*last) < IGNORED_CHAT_LOG_INTERVAL);
+            logged.insert(chat_id, now);
+        },
+    }
+}
+
+
+fn poll_commands(cfg: BotConfig, api_url: String, token: String) {
+    let mut offset: i64 = 0;
+    let mut pending: HashMap<i64, PendingUnwind> = HashMap::new();
+    let mut ignored_chats: HashMap<Option<i64>, Instant> = HashMap::new();
+    let mut is_conflict_reported = false;
+    loop {
+        let updates = match get_updates(&api_url, &token, offset, cfg.poll_timeout) {
+            Ok(updates) => {
+                is_conflict_reported = false;
+                updates
+            },
+            Err(UpdatesError::Conflict) => {
+                if !is_conflict_reported {
+                    error!("Telegram bot: another instance gets updates of the same bot (409 Conflict), commands can be lost; \
+                            every instance must have its own `bot.token`");
+                    is_conflict_reported = true;
+                }
+                thread::sleep(Duration::from_secs(5));
+                continue;
+            },
+            Err(UpdatesError::Failed(err)) => {
+                // NOTE: not `warn!()`, because it is sent to Telegram which is probably unavailable
+                debug!("Telegram bot: can't get updates: {}", err);
+                thread::sleep(Duration::from_secs(5));
+                continue;
+            },
+        };
+        for update in updates {
+            if let Some(id) = update["update_id"].as_i64() {
+                offset = std::cmp::max(offset, id + 1);
+            }
+            if update["callback_query"].is_object() {
+                let query = &update["callback_query"];
+                match query["message"]["chat"]["id"].as_i64() {
+                    Some(chat_id) if cfg.allowed_chat_ids.contains(&chat_id) => handle_callback(query, &api_url, &token),
+                    chat_id => log_ignored(&mut ignored_chats, chat_id, "button"),
+                }
+                continue;
+            }
+            let (chat_id, text) = match (update["message"]["chat"]["id"].as_i64(), update["message"]["text"].as_str()) {
+                (Some(chat_id), Some(text)) => (chat_id, text),
+                _ => continue,
+            };
+            if !cfg.allowed_chat_ids.contains(&chat_id) {
+                log_ignored(&mut ignored_chats, Some(chat_id), &format!("command \"{}\"", text));
+                continue;
+            }
+            let reply = handle_command(text, chat_id, &cfg, &mut pending);
+            let reply = html_escape::encode_text(&reply).to_string();
+            if notifiers::send_telegram_message(&api_url, &token, chat_id, &reply, true).is_err() {
+                debug!("Telegram bot: can't reply to chat {}", chat_id);
+            }
+        }
+    }
+}
+
+
+/// Run bot (if enabled) in background thread.
+///
+pub fn run_bot(config: &Config) {
+    if !config.bot.enabled {
+        return;
+    }
+    let cfg = config.bot.clone();
+    let token = match &cfg.token {
+        Some(token) => token.expose().to_owned(),
+        // NOTE: it is checked by `read_config()`
+        None => return,
+    };
+    let api_url = config.notifications.telegram_api_url.clone();
+    let (announce_cfg, announce_api_url, announce_token) = (cfg.clone(), api_url.clone(), token.clone());
+    thread::Builder::new()
+        .name("telegram-bot".to_string())
+        .spawn(move || poll_commands(cfg, api_url, token))
+        .expect("Can't start Telegram bot thread");
+    thread::Builder::new()
+        .name("telegram-approvals".to_string())
+        .spawn(move || announce_approvals(announce_cfg, announce_api_url, announce_token))
+        .expect("Can't start Telegram approvals thread");
+}
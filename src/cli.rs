@@ -1,11 +1,13 @@
 /// Command line parsing
 ///
 
-use clap::Parser;
+use clap::{ Arg, ArgMatches, Command, FromArgMatches, Parser, Subcommand };
 use clap::builder::IntoResettable;
 use clap::builder::Resettable;
 use clap::builder::Str;
 
+use crate::actions::*;
+use crate::strategy::ACTIONS;
 use crate::utils::*;
 use crate::types::*;
 
@@ -37,8 +39,185 @@ pub enum Action {
     Run,
     /// Monitoring only
     Monitoring,
-    /// Run only specified action
-    Only { action_name: String, value: Option<Value> },
+    /// Run main strategy with terminal dashboard instead of log output
+    Tui,
+    /// Run only specified action (see `list-actions`), asks confirmation after summary
+    Only {
+        /// Don't ask confirmation (for scripts)
+        #[clap(long, global = true)]
+        yes: bool,
+        /// Show summary and run action until its first side effect (order, transfer, staking)
+        #[clap(long, global = true)]
+        dry_run: bool,
+        #[clap(subcommand)]
+        action: ActionCall,
+    },
+    /// Show registered actions
+    ListActions,
+    /// Close positions and move funds to wallet (resumes interrupted unwinding)
+    Unwind {
+        /// Part of positions to unwind, for example `30%`
+        #[clap(default_value = "100%", value_parser = parse_percent)]
+        percent: Value,
+        /// Forget interrupted unwinding and start new one
+        #[clap(long)]
+        reset: bool,
+    },
+    /// Derive dYdX credentials from ETH key of wallet and print them as `[dydx]` section of config
+    DeriveDydxKeys {
+        /// Store credentials to keystore (as `dydx_key`, `dydx_secret`, `dydx_passphrase`, `dydx_stark_private_key`)
+        #[clap(long)]
+        store: bool,
+    },
+    /// Manage encrypted keystore of secrets
+    Keystore {
+        #[clap(subcommand)]
+        command: KeystoreCommand,
+    },
+    /// Sign or check allowlist of withdrawal destinations
+    Allowlist {
+        #[clap(subcommand)]
+        command: AllowlistCommand,
+    },
+    /// Audit log of side effects
+    Audit {
+        #[clap(subcommand)]
+        command: AuditCommand,
+    },
+    /// Show history of balances, prices and ratio or export it to CSV
+    History {
+        /// Start of range: `7d`, `12h` (ago), `2024-01-31`, `2024-01-31 12:00` (UTC) or Unix timestamp
+        #[clap(long, default_value = "1d", value_parser = parse_time)]
+        from: i64,
+        /// End of range (the same formats)
+        #[clap(long, default_value = "now", value_parser = parse_time)]
+        to: i64,
+        /// Export all columns to CSV file (`-` for stdout)
+        #[clap(long)]
+        csv: Option<String>,
+    },
+}
+
+
+#[derive(clap::Subcommand, PartialEq, Debug)]
+pub enum KeystoreCommand {
+    /// Add or replace secret (its value is read from terminal)
+    Set { name: String },
+    /// Show names of stored secrets
+    List,
+}
+
+
+#[derive(clap::Subcommand, PartialEq, Debug)]
+pub enum AllowlistCommand {
+    /// Print signature of `[allowlist]` destinations (by `signing_key`)
+    Sign,
+    /// Check signature and all destinations of config (including addresses reported by venues)
+    Check,
+}
+
+
+#[derive(clap::Subcommand, PartialEq, Debug)]
+pub enum AuditCommand {
+    /// Check hash chain of audit log (file from config by default)
+    Verify { file: Option<String> },
+}
+
+
+/// Action with its value (subcommands of `only` are made from registered actions).
+///
+#[derive(PartialEq, Debug)]
+pub struct ActionCall {
+    pub name: String,
+    pub value: Option<Value>,
+}
+
+
+fn action_command(action: &ActionInfo) -> Command {
+    let cmd = Command::new(action.name).about(action.description);
+    if action.param == Param::Nothing {
+        return cmd;
+    }
+    let param = action.param;
+    cmd.arg(Arg::new("value")
+        .value_name(if param == Param::Percent { "PERCENT" } else { "AMOUNT" })
+        .help(param.help())
+        // NOTE: negative amounts (`-5atom`) look like flags
+        .allow_hyphen_values(true)
+        .value_parser(move |s: &str| param.parse(s)))
+}
+
+
+impl FromArgMatches for ActionCall {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        match matches.subcommand() {
+            Some((name, sub)) => Ok(ActionCall {
+                name: name.to_owned(),
+                value: find_action(name).filter(|a| a.param != Param::Nothing).and_then(|_| sub.get_one::<Value>("value").copied()),
+            }),
+            None => Err(clap::Error::raw(clap::error::ErrorKind::MissingSubcommand, "Action is required (see `list-actions`)")),
+        }
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+
+impl Subcommand for ActionCall {
+    fn augment_subcommands(cmd: Command) -> Command {
+        ACTIONS.iter().fold(cmd, |cmd, action| cmd.subcommand(action_command(action))).subcommand_required(true)
+    }
+
+    fn augment_subcommands_for_update(cmd: Command) -> Command {
+        Self::augment_subcommands(cmd)
+    }
+
+    fn has_subcommand(name: &str) -> bool {
+        find_action(name).is_some()
+    }
+}
+
+
+pub fn list_actions() {
+    let width = ACTIONS.iter().map(|a| a.name.len()).max().unwrap_or_default();
+    for action in ACTIONS {
+        let value = if action.param == Param::Nothing { String::new() } else { format!("[{}]", action.param.value_name()) };
+        println!("{:width$} {:9} {}", action.name, value, action.description, width = width);
+    }
+}
+
+
+pub fn parse_percent(s: &str) -> Result<Value, String> {
+    s.trim().trim_end_matches('%').parse::<Value>().map_err(|e| e.to_string())
+}
+
+
+/// Unix timestamp of relative (`7d`, `12h`, `30m` ago, `now`) or absolute (UTC) time.
+///
+pub fn parse_time(s: &str) -> Result<i64, String> {
+    let s = s.trim();
+    let now = chrono::Utc::now().timestamp();
+    if s == "now" {
+        return Ok(now);
+    }
+    for (suffix, secs) in [("d", 24 * 3600), ("h", 3600), ("m", 60)] {
+        if let Some(count) = s.strip_suffix(suffix).and_then(|n| n.parse::<i64>().ok()) {
+            return Ok(now - count * secs);
+        }
+    }
+    if let Ok(timestamp) = s.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp());
+    }
+    ["%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"].iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(s, format).ok())
+        .map(|t| t.and_utc().timestamp())
+        .ok_or_else(|| format!("can't parse time \"{}\"", s))
 }
 
 
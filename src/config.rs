@@ -1,7 +1,12 @@
 //
+use chrono::{ NaiveTime, Weekday };
 use rust_decimal::Decimal;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{ IpAddr, SocketAddr };
 
+use crate::fees::FeesConfig;
+use crate::secrets::Secret;
 #[allow(unused_imports)]
 use crate::types::*;
 
@@ -9,48 +14,175 @@ use crate::types::*;
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub kraken: KrakenConfig,
+    #[serde(default)]
     pub dydx: DydxConfig,
     pub binance: BinanceConfig,
     pub wallet: WalletConfig,
     pub strategy: StrategyConfig,
     pub notifications: NotificationsConfig,
+    #[serde(default)]
+    pub fees: FeesConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub watchdog: WatchdogConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub bot: BotConfig,
+    #[serde(default)]
+    pub digest: DigestConfig,
+    #[serde(default)]
+    pub approval: ApprovalConfig,
+    #[serde(default)]
+    pub read_only: ReadOnlyConfig,
+    #[serde(default)]
+    pub allowlist: AllowlistConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
 }
 
 
+/// NOTE: trading credentials are optional, because monitoring instance uses `[read_only]` ones
 #[derive(Debug, Clone, Deserialize)]
 pub struct KrakenConfig {
-    pub key: String,
-    pub secret: String,
+    #[serde(default)]
+    pub key: Option<Secret>,
+    #[serde(default)]
+    pub secret: Option<Secret>,
     pub withdrawal_key: String,
     pub atom_withdrawal_key: String,
     pub usdc_account: String,
     pub atom_account: String,
+    /// Memo of ATOM deposits to `atom_account` (if Kraken requires it)
+    #[serde(default)]
+    pub atom_memo: Option<String>,
 }
 
 
-#[derive(Debug, Clone, Deserialize)]
+/// Credentials which are not set are derived from ETH key of wallet
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
 pub struct DydxConfig {
-    pub key: String,
-    pub secret: String,
-    pub passphrase: String,
-    pub stark_private_key: String,
+    pub key: Option<Secret>,
+    pub secret: Option<Secret>,
+    pub passphrase: Option<Secret>,
+    pub stark_private_key: Option<Secret>,
 }
 
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct BinanceConfig {
     pub usdc_account: String,
-    pub trading_key: String,
-    pub trading_secret: String,
-    pub funding_key: String,
-    pub funding_secret: String,
+    #[serde(default)]
+    pub trading_key: Option<Secret>,
+    #[serde(default)]
+    pub trading_secret: Option<Secret>,
+    #[serde(default)]
+    pub funding_key: Option<Secret>,
+    #[serde(default)]
+    pub funding_secret: Option<Secret>,
 }
 
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct WalletConfig {
+    /// Address
     pub key: String,
-    pub secret: String,
+    /// Private key
+    #[serde(default)]
+    pub secret: Option<Secret>,
+}
+
+
+/// Read-only credentials of monitoring mode (API keys without trading and withdrawal permissions)
+///
+/// Monitoring refuses to start if some venue isn't set here, unless `allow_trading_credentials`
+/// is set (then trading credentials are used for it, with warning).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ReadOnlyConfig {
+    pub kraken: Option<ApiCredentials>,
+    pub dydx: Option<DydxReadOnlyCredentials>,
+    /// For both trading and funding accounts
+    pub binance: Option<ApiCredentials>,
+    /// Explicit opt-in to use trading credentials for venues which are not set
+    pub allow_trading_credentials: bool,
+}
+
+
+impl ReadOnlyConfig {
+    /// Error if monitoring would need trading credentials without opt-in.
+    ///
+    pub fn check(&self) -> Result<(), String> {
+        let missing: Vec<&str> = [("kraken", self.kraken.is_none()), ("dydx", self.dydx.is_none()), ("binance", self.binance.is_none())]
+            .iter().filter(|(_, is_missing)| *is_missing).map(|(name, _)| *name).collect();
+        if missing.is_empty() || self.allow_trading_credentials {
+            return Ok(());
+        }
+        Err(format!("read-only credentials of {} are not set in `[read_only]` \
+                     (set `allow_trading_credentials = true` there to use trading ones)", missing.join(", ")))
+    }
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiCredentials {
+    pub key: Secret,
+    pub secret: Secret,
+}
+
+
+/// STARK key isn't required for reading
+#[derive(Debug, Clone, Deserialize)]
+pub struct DydxReadOnlyCredentials {
+    pub key: Secret,
+    pub secret: Secret,
+    pub passphrase: Secret,
+}
+
+
+/// Allowed destinations of withdrawals
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AllowlistConfig {
+    pub enabled: bool,
+    /// Key of HMAC-SHA256 signature of `destinations` (keep it in keystore)
+    pub signing_key: Option<Secret>,
+    /// Hex, it is printed by `allowlist sign`
+    pub signature: String,
+    /// Compare destinations with deposit addresses which venues report for our accounts
+    pub check_venues: bool,
+    pub destinations: Vec<AllowedDestination>,
+}
+
+
+impl Default for AllowlistConfig {
+    fn default() -> Self {
+        AllowlistConfig {
+            enabled: false,
+            signing_key: None,
+            signature: String::new(),
+            check_venues: true,
+            destinations: Vec::new(),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AllowedDestination {
+    pub asset: String,
+    pub network: String,
+    /// ETH addresses must be checksummed (EIP-55)
+    pub address: String,
+    /// Memo (tag) of deposits to `address`
+    #[serde(default)]
+    pub memo: Option<String>,
 }
 
 
@@ -70,16 +202,437 @@ pub struct StrategyConfig {
     pub operations_timeout: u64,
     pub use_binance_for_exchange: bool,
     pub panics_to_log: bool,
+    /// Strategy is paused while this file exists (`./PAUSE` by default)
+    pub pause_file: Option<String>,
 }
 
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct NotificationsConfig {
     pub telegram_enabled: bool,
-    pub logs_telegram_token: String,
+    pub logs_telegram_token: Secret,
     pub logs_telegram_chat_id: i64,
-    pub alerts_telegram_token: String,
+    pub alerts_telegram_token: Secret,
     pub alerts_telegram_chat_id: i64,
+    /// Base URL of Telegram Bot API (can be changed to test with local stand-in)
+    #[serde(default = "default_telegram_api_url")]
+    pub telegram_api_url: String,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+    #[serde(default)]
+    pub routing: RoutingConfig,
+    /// Other destinations of notifications
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+}
+
+
+fn default_telegram_api_url() -> String {
+    "https://api.telegram.org".to_owned()
+}
+
+
+/// Deduplication, throttling and quiet hours of notifications (times are in seconds)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RoutingConfig {
+    /// Identical alerts within this time are sent once
+    pub dedup_window: u64,
+    pub throttle_window: u64,
+    /// Maximal number of messages from one source within `throttle_window`
+    pub throttle_limit: u64,
+    /// Repeated alert is considered resolved if it isn't seen for this time
+    pub resolve_after: u64,
+    /// Only errors are sent during quiet hours (local time)
+    pub quiet_hours: Option<QuietHoursConfig>,
+}
+
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        RoutingConfig {
+            dedup_window: 900,
+            throttle_window: 60,
+            throttle_limit: 10,
+            resolve_after: 300,
+            quiet_hours: None,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuietHoursConfig {
+    /// `HH:MM`
+    pub from: String,
+    pub to: String,
+}
+
+
+/// Maximal level of notifications sent to sink
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyLevel {
+    /// Notifications, warnings and errors
+    Info,
+    /// Warnings and errors
+    #[default]
+    Warn,
+    Error,
+}
+
+
+/// Destination of notifications
+#[derive(Debug, Clone, Deserialize)]
+pub struct SinkConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub level: NotifyLevel,
+    /// Prefixes of log targets sent to sink (e.g. `NOTIFICATION`, `hedged_staking::helpers`), all if empty
+    #[serde(default)]
+    pub targets: Vec<String>,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+}
+
+
+fn default_true() -> bool {
+    true
+}
+
+
+#[derive(Debug, Clone, Deserialize, strum_macros::Display)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SinkKind {
+    /// Generic JSON webhook: `{"source": "hedgedstaking", "level": "...", "text": "..."}`
+    Webhook { url: String },
+    /// Slack incoming webhook
+    Slack { url: String },
+    Discord { url: String },
+    Email(EmailSinkConfig),
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmailSinkConfig {
+    pub smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    /// Use TLS (disable to test with local SMTP stand-in)
+    #[serde(default = "default_true")]
+    pub tls: bool,
+    pub user: Option<String>,
+    pub password: Option<Secret>,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default = "default_email_subject")]
+    pub subject: String,
+}
+
+
+fn default_smtp_port() -> u16 {
+    465
+}
+
+
+fn default_email_subject() -> String {
+    "Hedged staking".to_owned()
+}
+
+
+/// Background delivery of notifications
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DeliveryConfig {
+    /// Messages above this number are dropped
+    pub queue_size: usize,
+    /// Messages arrived during this time are sent together
+    pub batch_delay_ms: u64,
+    /// Minimal interval between sent messages
+    pub min_interval_ms: u64,
+    pub max_retries: u32,
+    /// How long (in seconds) to wait for delivery of queued messages on exit
+    pub flush_timeout: u64,
+}
+
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        DeliveryConfig {
+            queue_size: 1000,
+            batch_delay_ms: 500,
+            min_interval_ms: 1000,
+            max_retries: 5,
+            flush_timeout: 5,
+        }
+    }
+}
+
+
+/// HTTP control API
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ApiConfig {
+    /// Bearer token for control requests (they are disabled if not set)
+    pub token: Option<Secret>,
+}
+
+
+/// Scheduled summary
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DigestConfig {
+    pub enabled: bool,
+    pub period: DigestPeriod,
+    /// Local time of summary, `HH:MM`
+    pub time: String,
+    /// Day of weekly summary (`Mon`, `Tue`, ...)
+    pub weekday: String,
+}
+
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        DigestConfig {
+            enabled: false,
+            period: DigestPeriod::Daily,
+            time: "09:00".to_owned(),
+            weekday: "Mon".to_owned(),
+        }
+    }
+}
+
+
+impl DigestConfig {
+    pub fn time_of_day(&self) -> Result<NaiveTime, String> {
+        NaiveTime::parse_from_str(self.time.trim(), "%H:%M")
+            .map_err(|err| format!("bad time \"{}\" (must be HH:MM): {}", self.time, err))
+    }
+
+    pub fn day_of_week(&self) -> Result<Weekday, String> {
+        self.weekday.trim().parse().map_err(|_| format!("bad weekday \"{}\" (must be Mon, Tue, ...)", self.weekday))
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestPeriod {
+    Daily,
+    Weekly,
+}
+
+
+/// Human approval of large operations
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ApprovalConfig {
+    pub enabled: bool,
+    /// Actions with larger notional (in USDC) wait for approval
+    pub notional_threshold: Option<Value>,
+    /// Actions which always wait for approval
+    pub actions: Vec<String>,
+    /// Time (in seconds) to wait for decision, request is rejected after it
+    pub timeout: u64,
+    /// Rejected action isn't requested again within this time (in seconds)
+    pub reject_cooldown: u64,
+    /// Decisions are appended to this file (JSON lines)
+    pub log_file: String,
+}
+
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        ApprovalConfig {
+            enabled: false,
+            notional_threshold: None,
+            // Withdrawals
+            actions: ["do_e1_to_wallet", "do_ee_to_wallet", "do_ee_to_e2", "do_e2_to_ee"].iter().map(|a| a.to_string()).collect(),
+            timeout: 900,
+            reject_cooldown: 3600,
+            log_file: "./approvals.jsonl".to_owned(),
+        }
+    }
+}
+
+
+/// Risk limits (not set limits are not checked)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Notional of one order (in USDC)
+    pub max_order_notional: Option<Value>,
+    /// Notional of one transfer (in USDC, ATOM amounts are converted by price)
+    pub max_transfer_notional: Option<Value>,
+    /// Volume transferred by every route within 24 hours (in USDC)
+    pub max_daily_route_volume: Option<Value>,
+    /// The same for specified routes (e.g. `ee_to_e2`), overrides `max_daily_route_volume`
+    pub max_daily_route_volumes: HashMap<String, Value>,
+    /// Orders and transfers within an hour
+    pub max_operations_per_hour: Option<usize>,
+    /// Change of short position on dYdX within 24 hours (in ATOM)
+    pub max_daily_hedge_change: Option<Value>,
+    /// Operations of the last 24 hours (to keep counters after restart)
+    pub state_file: String,
+}
+
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_order_notional: None,
+            max_transfer_notional: None,
+            max_daily_route_volume: None,
+            max_daily_route_volumes: HashMap::new(),
+            max_operations_per_hour: None,
+            max_daily_hedge_change: None,
+            state_file: "./limits-state.json".to_owned(),
+        }
+    }
+}
+
+
+/// Audit log of side effects
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// JSON lines, hash-chained
+    pub file: String,
+}
+
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig { file: "./audit.jsonl".to_owned() }
+    }
+}
+
+
+/// Local history of balances, prices and ratio (one sample per cycle)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    /// SQLite database
+    pub file: String,
+    /// Older samples are removed (kept forever if not set)
+    pub retention_days: Option<u64>,
+}
+
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig { enabled: true, file: "./history.sqlite".to_owned(), retention_days: Some(365) }
+    }
+}
+
+
+/// Telegram bot which accepts commands
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BotConfig {
+    pub enabled: bool,
+    /// Token of bot, required if bot is enabled (it must not be shared with other instances)
+    pub token: Option<Secret>,
+    /// Commands from other chats are ignored
+    pub allowed_chat_ids: Vec<i64>,
+    /// Timeout (in seconds) of long polling
+    pub poll_timeout: u64,
+    /// Time (in seconds) to confirm dangerous commands
+    pub confirmation_timeout: u64,
+}
+
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        BotConfig {
+            enabled: false,
+            token: None,
+            allowed_chat_ids: Vec::new(),
+            poll_timeout: 30,
+            confirmation_timeout: 60,
+        }
+    }
+}
+
+
+/// Server of metrics and API
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub enabled: bool,
+    /// Run server for `only` and `unwind` commands too
+    pub enabled_for_only: bool,
+    pub address: String,
+    pub port: u16,
+    pub basic_auth: Option<BasicAuthConfig>,
+    pub bearer_token: Option<Secret>,
+    /// Paths to PEM files to serve over HTTPS
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+}
+
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            enabled: true,
+            enabled_for_only: false,
+            address: "0.0.0.0".to_string(),
+            port: 8080,
+            basic_auth: None,
+            bearer_token: None,
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+
+impl ServerConfig {
+    /// Address to listen (IPv6 address is given without brackets).
+    ///
+    pub fn socket_address(&self) -> Result<SocketAddr, String> {
+        let ip: IpAddr = self.address.parse().map_err(|err| format!("bad server address \"{}\": {}", self.address, err))?;
+        Ok(SocketAddr::new(ip, self.port))
+    }
+
+    /// Paths to certificate and key (`None` if TLS isn't used).
+    ///
+    pub fn tls(&self) -> Result<Option<(&str, &str)>, String> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            _ => Err("both `tls_cert` and `tls_key` must be set to use TLS".to_owned()),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BasicAuthConfig {
+    pub user: String,
+    pub password: Secret,
+}
+
+
+/// Watchdog of strategy loop
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    pub enabled: bool,
+    /// Loop is stuck if no cycle was finished within `cycles * monitoring_timeout` seconds
+    pub cycles: u64,
+    /// Exit process (to be restarted) when loop is stuck
+    pub exit: bool,
+}
+
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig { enabled: true, cycles: 10, exit: false }
+    }
 }
 
 
@@ -91,7 +644,15 @@ impl From<toml::de::Error> for StrategyError {
 
 
 pub fn read_config(config_file_name: &str) -> Result<Config, StrategyError> {
-    Ok(toml::from_str(&std::fs::read_to_string(config_file_name)?)?)
+    let config: Config = toml::from_str(&std::fs::read_to_string(config_file_name)?)?;
+    config.server.socket_address().and(config.server.tls().map(|_| ()))
+        .map_err(|msg| StrategyError::Misc { msg: format!("{}: [server]: {}", config_file_name, msg) })?;
+    config.digest.time_of_day().and(config.digest.day_of_week().map(|_| ()))
+        .map_err(|msg| StrategyError::Misc { msg: format!("{}: [digest]: {}", config_file_name, msg) })?;
+    if config.bot.enabled && config.bot.token.is_none() {
+        return Err(StrategyError::Misc { msg: format!("{}: [bot]: `token` is required when bot is enabled", config_file_name) });
+    }
+    Ok(config)
 }
 
 
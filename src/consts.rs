@@ -1,5 +1,3 @@
-use rust_decimal_macros::dec;
-
 use crate::types::*;
 
 pub static TRANSFERRING_COIN: TokenName = "USDC";
@@ -20,10 +18,14 @@ pub static E2_TRANSFERRING_COIN: TokenName = TRANSFERRING_COIN;
 
 pub static E2_INTERMEDIATE_COIN: TokenName = INTERMEDIATE_COIN;
 
-pub static E2_WITHDRAW_COMMISSION: Value = dec!(3.5); // TODO get from config file
-
 pub static EE_TRANSFERRING_COIN: TokenName = "BUSD";
 
+/// Network of transferring coin withdrawals (ERC-20)
+pub static TRANSFERRING_NETWORK: &str = "ETH";
+
+/// Network of operating coin withdrawals (Cosmos Hub)
+pub static OPERATING_NETWORK: &str = "ATOM";
+
 
 /// Get apropriate market name pair in some exchange
 /// (for example for `ZUSD` on Kraken outputs `USD`).
@@ -73,3 +75,13 @@ pub fn get_exchange_name(we: WhichExchange) -> &'static str {
 }
 
 
+/// Role of exchange `we` in strategy (doesn't depend on exchange used).
+///
+pub fn get_exchange_role(we: WhichExchange) -> &'static str {
+    match we {
+        WhichExchange::First  => "hedge",
+        WhichExchange::Second => "staking",
+        WhichExchange::Exchange => "intermediate",
+        WhichExchange::Wallet => "wallet",
+    }
+}
@@ -0,0 +1,102 @@
+/// Runtime control of strategy: pause, resume and stop
+///
+/// When strategy is paused, balances, metrics and notifications keep updating,
+/// but no action is run. Pause can be toggled by:
+///
+/// * control file (strategy is paused while file exists),
+/// * signals (`SIGUSR1` -- pause, `SIGUSR2` -- resume),
+/// * HTTP endpoints (`POST /pause`, `POST /resume`).
+///
+/// Stop (quit of terminal dashboard) is done by strategy loop between cycles, so orders,
+/// transfers and waiting for them are not interrupted.
+///
+
+use log::{ info, warn };
+use std::path::PathBuf;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use tokio::signal::unix::{ signal, SignalKind };
+use tokio::time::{ sleep, Duration };
+
+use crate::config::StrategyConfig;
+use crate::monitoring;
+
+
+pub static DEFAULT_PAUSE_FILE_NAME: &str = "./PAUSE";
+
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+
+/// Pause or resume strategy, `source` is used for notification only.
+///
+pub fn set_paused(paused: bool, source: &str) {
+    let was_paused = PAUSED.swap(paused, Ordering::SeqCst);
+    monitoring::PAUSED.set(if paused { 1 } else { 0 });
+    if was_paused != paused {
+        if paused {
+            warn!(target: "NOTIFICATION", "PAUSED by {}: no actions will be run until resume", source);
+        }
+        else {
+            info!(target: "NOTIFICATION", "RESUMED by {}", source);
+        }
+    }
+}
+
+
+pub fn is_stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+
+/// Stop strategy after the current cycle, `source` is used for notification only.
+///
+pub fn request_stop(source: &str) {
+    if !STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+        warn!(target: "NOTIFICATION", "STOP requested by {}: strategy will exit after the current cycle", source);
+    }
+}
+
+
+async fn watch_pause_file(file_name: PathBuf) {
+    let mut file_existed = false;
+    loop {
+        let file_exists = file_name.exists();
+        if file_exists != file_existed {
+            set_paused(file_exists, &format!("control file {}", file_name.display()));
+            file_existed = file_exists;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+
+async fn watch_signals() {
+    let (mut sig_pause, mut sig_resume) = match (signal(SignalKind::user_defined1()), signal(SignalKind::user_defined2())) {
+        (Ok(p), Ok(r)) => (p, r),
+        (p, r) => {
+            warn!("Can't listen signals to pause/resume: {:?}, {:?}", p.err(), r.err());
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = sig_pause.recv() => set_paused(true, "SIGUSR1"),
+            _ = sig_resume.recv() => set_paused(false, "SIGUSR2"),
+        }
+    }
+}
+
+
+/// Run background tasks which watch control file and signals.
+///
+pub fn run_control_watchers(cfg: &StrategyConfig) {
+    let file_name = PathBuf::from(cfg.pause_file.as_deref().unwrap_or(DEFAULT_PAUSE_FILE_NAME));
+    tokio::task::spawn(watch_pause_file(file_name));
+    tokio::task::spawn(watch_signals());
+}
@@ -0,0 +1,232 @@
+/// Scheduled summary (daily or weekly)
+///
+/// Statistics are collected from data already observed by strategy (balances in
+/// `detect_current_state()`, realized transfer expenses, executed states) and are
+/// reset after every summary.
+///
+
+use chrono::{ DateTime, Datelike, Local, NaiveTime, TimeZone, Weekday };
+use lazy_static::*;
+use log::{ info, warn };
+use rust_decimal_macros::dec;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tokio::time::sleep;
+
+use crate::config::{ Config, DigestPeriod };
+use crate::consts::*;
+use crate::notifications::DIGEST_TARGET;
+use crate::fees::Route;
+use crate::types::*;
+use crate::utils::*;
+
+
+/// Values observed in one cycle of strategy.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct CycleSnapshot {
+    /// Total value of all accounts (in transferring coins)
+    pub nav: Value,
+    pub ratio: Value,
+    /// Staked coins on staking exchange
+    pub staked: Value,
+    /// Total value of hedging exchange
+    pub e1_total: Value,
+    pub e1_free: Value,
+    /// Value of short position
+    pub short_value: Value,
+}
+
+
+#[derive(Debug, Clone, Default)]
+struct RouteStats {
+    count: u64,
+    amount: Value,
+    expense: Value,
+}
+
+
+#[derive(Debug, Default)]
+struct DigestStats {
+    started: Option<DateTime<Local>>,
+    first: Option<CycleSnapshot>,
+    last: Option<CycleSnapshot>,
+    ratio_min: Option<Value>,
+    ratio_max: Option<Value>,
+    ratio_sum: Value,
+    ratio_count: u64,
+    /// Executed actions
+    states: BTreeMap<String, u64>,
+    transfers: BTreeMap<Route, RouteStats>,
+    gas: Value,
+    traded: Value,
+    trade_fees: Value,
+    /// Net amount staked by strategy (to separate rewards)
+    net_staked: Value,
+    /// Net amount transferred to hedging exchange (to separate funding and PnL)
+    net_e1_transfers: Value,
+}
+
+
+lazy_static! {
+    static ref STATS: Mutex<DigestStats> = Mutex::new(Default::default());
+}
+
+
+pub fn observe_cycle(snapshot: CycleSnapshot) {
+    let mut stats = STATS.lock().unwrap();
+    if stats.started.is_none() {
+        stats.started = Some(Local::now());
+    }
+    if stats.first.is_none() {
+        stats.first = Some(snapshot);
+    }
+    stats.last = Some(snapshot);
+    stats.ratio_min = Some(stats.ratio_min.map_or(snapshot.ratio, |r| r.min(snapshot.ratio)));
+    stats.ratio_max = Some(stats.ratio_max.map_or(snapshot.ratio, |r| r.max(snapshot.ratio)));
+    stats.ratio_sum += snapshot.ratio;
+    stats.ratio_count += 1;
+}
+
+
+pub fn observe_state(name: &str) {
+    *STATS.lock().unwrap().states.entry(name.to_owned()).or_default() += 1;
+}
+
+
+/// Completed transfer of `amount` by `route` with realized `expense` (in coins of route).
+///
+pub fn observe_transfer(route: Route, amount: Value, expense: Value) {
+    let mut stats = STATS.lock().unwrap();
+    let route_stats = stats.transfers.entry(route).or_default();
+    route_stats.count += 1;
+    route_stats.amount += amount;
+    route_stats.expense += expense;
+    match route {
+        Route::WalletToE1 => stats.net_e1_transfers += amount - expense,
+        Route::E1ToWallet => stats.net_e1_transfers -= amount,
+        _ => (),
+    }
+}
+
+
+pub fn observe_gas(gas: Value) {
+    STATS.lock().unwrap().gas += gas;
+}
+
+
+/// Completed trade of `value` (in transferring coins) with commission `fee_relative`.
+///
+pub fn observe_trade(value: Value, fee_relative: Value) {
+    let mut stats = STATS.lock().unwrap();
+    stats.traded += value.abs();
+    stats.trade_fees += value.abs() * fee_relative;
+}
+
+
+/// Staked (positive) or unstaked (negative) `amount` by strategy.
+///
+pub fn observe_staking(amount: Value) {
+    STATS.lock().unwrap().net_staked += amount;
+}
+
+
+fn format_summary(stats: &DigestStats, period: DigestPeriod) -> String {
+    let title = match period { DigestPeriod::Daily => "DAILY SUMMARY", DigestPeriod::Weekly => "WEEKLY SUMMARY" };
+    let since = stats.started.map(|t| t.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_else(|| "start".to_owned());
+    let (first, last) = match (stats.first, stats.last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return format!("{} (since {})\n\nNo data: strategy didn't finish any cycle", title, since),
+    };
+    let nav_change = last.nav - first.nav;
+    let nav_change_percent = if first.nav.is_zero() { ZERO } else { nav_change / first.nav * dec!(100) };
+    let ratio_avg = if stats.ratio_count == 0 { ZERO } else { stats.ratio_sum / Value::from(stats.ratio_count) };
+    let states = if stats.states.is_empty() { "none".to_owned() }
+                 else { stats.states.iter().map(|(s, n)| format!("{}: {}", s, n)).collect::<Vec<_>>().join(", ") };
+    let transfers = if stats.transfers.is_empty() { " none".to_owned() }
+                    else { stats.transfers.iter()
+                        .map(|(route, rs)| format!("\n  {}: {} times, {:.2} {}, cost {:.4} {}",
+                                                   route, rs.count, rs.amount, route.coin(), rs.expense, route.coin()))
+                        .collect::<String>() };
+    let rewards = (last.staked - first.staked) - stats.net_staked;
+    let funding = (last.e1_total - first.e1_total) - stats.net_e1_transfers;
+    let leverage = if last.e1_total.is_zero() { ZERO } else { last.short_value / last.e1_total };
+    format!("{} (since {})\n\
+             \n\
+             NAV: {:.2} {} ({:+.2}, {:+.2}%)\n\
+             Ratio: min {:.4}, max {:.4}, avg {:.4}\n\
+             Actions: {}\n\
+             Transfers:{}\n\
+             Gas: {:.6} {}\n\
+             Trades: {:.2} {}, fees ~{:.2} {}\n\
+             Staking rewards: ~{:.4} {}\n\
+             Funding and PnL on {}: {:+.2} {}\n\
+             Margin on {}: leverage {:.2}, free {:.2} {}",
+            title, since,
+            last.nav, TRANSFERRING_COIN, nav_change, nav_change_percent,
+            stats.ratio_min.unwrap_or(ZERO), stats.ratio_max.unwrap_or(ZERO), ratio_avg,
+            states,
+            transfers,
+            stats.gas, GAS_COIN,
+            stats.traded, TRANSFERRING_COIN, stats.trade_fees, TRANSFERRING_COIN,
+            rewards, OPERATING_COIN,
+            E1_NAME, funding, TRANSFERRING_COIN,
+            E1_NAME, leverage, last.e1_free, TRANSFERRING_COIN)
+}
+
+
+/// Make summary and start new period.
+///
+pub fn take_summary(period: DigestPeriod) -> String {
+    let mut stats = STATS.lock().unwrap();
+    let msg = format_summary(&stats, period);
+    let last = stats.last;
+    *stats = DigestStats { started: Some(Local::now()), first: last, last, ..Default::default() };
+    msg
+}
+
+
+/// Time of the next summary after `now`.
+///
+fn next_summary_time(period: DigestPeriod, time: NaiveTime, weekday: Weekday, now: DateTime<Local>) -> DateTime<Local> {
+    let mut date = now.date_naive();
+    loop {
+        if let Some(candidate) = Local.from_local_datetime(&date.and_time(time)).earliest() {
+            let is_right_day = match period {
+                DigestPeriod::Daily => true,
+                DigestPeriod::Weekly => candidate.weekday() == weekday,
+            };
+            if candidate > now && is_right_day {
+                return candidate;
+            }
+        }
+        date = date.succ_opt().expect("Date overflow");
+    }
+}
+
+
+async fn send_digests(period: DigestPeriod, time: NaiveTime, weekday: Weekday) {
+    loop {
+        let now = Local::now();
+        let next = next_summary_time(period, time, weekday, now);
+        match (next - now).to_std() {
+            Ok(delay) => sleep(delay).await,
+            Err(err) => warn!("Digest: bad delay: {}", err),
+        }
+        // NOTE: scheduled summary isn't routed, so it isn't suppressed in quiet hours or throttled
+        info!(target: DIGEST_TARGET, "{}", take_summary(period));
+    }
+}
+
+
+/// Run sending of summaries (if enabled).
+///
+pub fn run_digest(config: &Config) {
+    if config.digest.enabled {
+        // NOTE: config is checked by `read_config()`
+        match (config.digest.time_of_day(), config.digest.day_of_week()) {
+            (Ok(time), Ok(weekday)) => { tokio::task::spawn(send_digests(config.digest.period, time, weekday)); },
+            (time, weekday) => warn!("Digest is disabled: {:?}, {:?}", time.err(), weekday.err()),
+        }
+    }
+}
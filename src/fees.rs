@@ -0,0 +1,245 @@
+/// Fee model: expected commissions for transfers and exchanges
+///
+/// Initial values are taken from config file, then transfer fees are
+/// corrected by realized expenses (which are measured after every transfer).
+///
+
+use log::debug;
+use rust_decimal_macros::dec;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::consts::*;
+use crate::types::*;
+use crate::utils::*;
+
+
+/// Direction of transfer between accounts.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, strum_macros::Display, strum_macros::EnumIter)]
+#[strum(serialize_all = "snake_case")]
+pub enum Route {
+    E1ToWallet,
+    WalletToE1,
+    E2ToWallet,
+    WalletToE2,
+    WalletToEE,
+    EEToWallet,
+    EEToE2,
+    E2ToEE,
+}
+
+
+impl Route {
+    /// Route of withdrawal from exchange `we` to wallet.
+    ///
+    pub fn to_wallet(we: WhichExchange) -> Route {
+        match we {
+            WhichExchange::First => Route::E1ToWallet,
+            WhichExchange::Second => Route::E2ToWallet,
+            WhichExchange::Exchange => Route::EEToWallet,
+            WhichExchange::Wallet => unreachable!(),
+        }
+    }
+
+    /// Route of deposit from wallet to exchange `we`.
+    ///
+    pub fn from_wallet(we: WhichExchange) -> Route {
+        match we {
+            WhichExchange::First => Route::WalletToE1,
+            WhichExchange::Second => Route::WalletToE2,
+            WhichExchange::Exchange => Route::WalletToEE,
+            WhichExchange::Wallet => unreachable!(),
+        }
+    }
+
+    /// Coin transferred by route (fixed commission is set in these coins).
+    ///
+    pub fn coin(&self) -> TokenName<'static> {
+        match self {
+            Route::EEToE2 | Route::E2ToEE => OPERATING_COIN,
+            _ => TRANSFERRING_COIN,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RouteFee {
+    /// Part of transferred amount
+    #[serde(default)]
+    pub relative: Value,
+    /// Fixed commission in coins of route (see `Route::coin()`)
+    #[serde(default)]
+    pub fixed: Value,
+}
+
+
+impl RouteFee {
+    const fn new(relative: Value, fixed: Value) -> Self {
+        RouteFee { relative, fixed }
+    }
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RoutesFeesConfig {
+    pub e1_to_wallet: RouteFee,
+    pub wallet_to_e1: RouteFee,
+    pub e2_to_wallet: RouteFee,
+    pub wallet_to_e2: RouteFee,
+    pub wallet_to_ee: RouteFee,
+    pub ee_to_wallet: RouteFee,
+    pub ee_to_e2: RouteFee,
+    pub e2_to_ee: RouteFee,
+}
+
+
+impl Default for RoutesFeesConfig {
+    fn default() -> Self {
+        RoutesFeesConfig {
+            e1_to_wallet: RouteFee::new(dec!(0.005), ZERO),
+            wallet_to_e1: RouteFee::new(dec!(0.005), ZERO),
+            e2_to_wallet: RouteFee::new(dec!(0.005), dec!(3.5)),
+            wallet_to_e2: RouteFee::new(dec!(0.005), ZERO),
+            wallet_to_ee: RouteFee::new(dec!(0.005), ZERO),
+            ee_to_wallet: RouteFee::new(dec!(0.005), ZERO),
+            ee_to_e2: RouteFee::new(dec!(0.005), dec!(0.01)),
+            e2_to_ee: RouteFee::new(dec!(0.005), dec!(0.1)),
+        }
+    }
+}
+
+
+impl RoutesFeesConfig {
+    pub fn get(&self, route: Route) -> RouteFee {
+        match route {
+            Route::E1ToWallet => self.e1_to_wallet,
+            Route::WalletToE1 => self.wallet_to_e1,
+            Route::E2ToWallet => self.e2_to_wallet,
+            Route::WalletToE2 => self.wallet_to_e2,
+            Route::WalletToEE => self.wallet_to_ee,
+            Route::EEToWallet => self.ee_to_wallet,
+            Route::EEToE2 => self.ee_to_e2,
+            Route::E2ToEE => self.e2_to_ee,
+        }
+    }
+}
+
+
+/// Buy/sell commissions (part of order amount) on each venue.
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TradeFeesConfig {
+    pub e1: Value,
+    pub e2: Value,
+    pub ee: Value,
+}
+
+
+impl Default for TradeFeesConfig {
+    fn default() -> Self {
+        TradeFeesConfig { e1: dec!(0.005), e2: dec!(0.005), ee: dec!(0.005) }
+    }
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FeesConfig {
+    pub routes: RoutesFeesConfig,
+    pub trade: TradeFeesConfig,
+    /// "Insurance" for changing price during transfers
+    pub transfers_tolerance: Value,
+    /// Part of amount which must arrive to consider transfer as completed
+    pub arrival_factor: Value,
+    /// Weight of the last realized expense in the rolling estimation (`0` to disable learning)
+    pub learning_rate: Value,
+    /// Estimated relative fee can't exceed configured one more than in this times
+    pub max_learned_factor: Value,
+}
+
+
+impl Default for FeesConfig {
+    fn default() -> Self {
+        FeesConfig {
+            routes: Default::default(),
+            trade: Default::default(),
+            transfers_tolerance: dec!(0.10),
+            arrival_factor: dec!(0.9),
+            learning_rate: dec!(0.2),
+            max_learned_factor: dec!(4),
+        }
+    }
+}
+
+
+/// Fees for all routes and venues.
+///
+/// All allocation math must take commissions from here.
+///
+#[derive(Debug, Clone)]
+pub struct FeeModel {
+    cfg: FeesConfig,
+    /// Rolling estimation of relative fee on route
+    learned: HashMap<Route, Value>,
+}
+
+
+impl FeeModel {
+    pub fn new(cfg: FeesConfig) -> Self {
+        FeeModel { cfg, learned: HashMap::new() }
+    }
+
+    /// Expected relative commission on transfer by `route`.
+    ///
+    pub fn transfer_relative(&self, route: Route) -> Value {
+        self.learned.get(&route).copied().unwrap_or(self.cfg.routes.get(route).relative)
+    }
+
+    /// Expected fixed commission on transfer by `route` (in `route.coin()`).
+    ///
+    pub fn transfer_fixed(&self, route: Route) -> Value {
+        self.cfg.routes.get(route).fixed
+    }
+
+    /// Expected buy/sell commission on exchange `we`.
+    ///
+    pub fn trade_relative(&self, we: WhichExchange) -> Value {
+        match we {
+            WhichExchange::First => self.cfg.trade.e1,
+            WhichExchange::Second => self.cfg.trade.e2,
+            WhichExchange::Exchange => self.cfg.trade.ee,
+            WhichExchange::Wallet => ZERO,
+        }
+    }
+
+    pub fn tolerance(&self) -> Value {
+        self.cfg.transfers_tolerance
+    }
+
+    /// Minimal amount that must arrive after sending `amount` by `route`.
+    ///
+    pub fn expected_arrival(&self, route: Route, amount: Value) -> Value {
+        (amount - self.transfer_fixed(route)) * self.cfg.arrival_factor
+    }
+
+    /// Update rolling estimation with realized `expense` of transfer `amount` by the `route`.
+    ///
+    pub fn observe(&mut self, route: Route, amount: Value, expense: Value) {
+        if amount <= ZERO || expense < ZERO || self.cfg.learning_rate.is_zero() {
+            return;
+        }
+        let configured = self.cfg.routes.get(route).relative;
+        let realized = ((expense - self.transfer_fixed(route)).max(ZERO) / amount)
+            .min(configured * self.cfg.max_learned_factor);
+        let prev = self.transfer_relative(route);
+        let lr = self.cfg.learning_rate;
+        let estimated = prev * (ONE - lr) + realized * lr;
+        debug!("Fee on {}: realized {:.6} (expense {} {} of {}), estimation {:.6} -> {:.6}",
+               route, realized, expense, route.coin(), amount, prev, estimated);
+        self.learned.insert(route, estimated);
+    }
+}
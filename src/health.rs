@@ -0,0 +1,108 @@
+/// Liveness and readiness checks, watchdog
+///
+/// Strategy loop is considered stuck if no cycle was finished within `watchdog.cycles *
+/// strategy.monitoring_timeout` seconds (but not less than the longest cycle: operation, which
+/// waits for transfers, and approval of it).
+///
+
+use lazy_static::*;
+use log::{ error, info };
+use tokio::time::{ sleep, Duration };
+use warp::Filter;
+use warp::http::StatusCode;
+use warp::reply::{ json, with_status, Json, WithStatus };
+
+use crate::api;
+use crate::config::Config;
+
+
+lazy_static! {
+    static ref STARTED_TIMESTAMP: u64 = api::now_timestamp();
+}
+
+
+/// Maximal time (in seconds) between cycles of alive strategy.
+///
+fn stall_limit(config: &Config) -> u64 {
+    let longest_cycle = config.strategy.operations_timeout + config.approval.timeout;
+    (config.watchdog.cycles * config.strategy.monitoring_timeout).max(longest_cycle)
+}
+
+
+/// Seconds since the last finished cycle (or since start).
+///
+fn heartbeat_age() -> u64 {
+    let last = api::status().last_cycle_timestamp.unwrap_or(*STARTED_TIMESTAMP);
+    api::now_timestamp().saturating_sub(last)
+}
+
+
+fn healthz_handler(limit: u64) -> WithStatus<Json> {
+    let age = heartbeat_age();
+    let is_alive = age <= limit;
+    with_status(
+        json(&serde_json::json!({ "alive": is_alive, "heartbeat_age": age, "limit": limit })),
+        if is_alive { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE })
+}
+
+
+fn readyz_handler(limit: u64) -> WithStatus<Json> {
+    let status = api::status();
+    let age = heartbeat_age();
+    let failed_venues: Vec<&String> = status.venues.iter().filter(|(_, v)| !v.ok).map(|(name, _)| name).collect();
+    let is_ready = status.last_cycle_timestamp.is_some()
+                && age <= limit
+                && !status.venues.is_empty()
+                && failed_venues.is_empty();
+    with_status(
+        json(&serde_json::json!({
+            "ready": is_ready,
+            "heartbeat_age": age,
+            "limit": limit,
+            "failed_venues": failed_venues,
+            "venues": status.venues,
+        })),
+        if is_ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE })
+}
+
+
+/// `GET /healthz` (is strategy loop alive) and `GET /readyz` (and are all exchanges connected).
+///
+pub fn routes(config: &Config) -> impl Filter<Extract = (WithStatus<Json>,), Error = warp::Rejection> + Clone {
+    lazy_static::initialize(&STARTED_TIMESTAMP);
+    let limit = stall_limit(config);
+    let healthz = warp::get().and(warp::path!("healthz")).map(move || healthz_handler(limit));
+    let readyz = warp::get().and(warp::path!("readyz")).map(move || readyz_handler(limit));
+    healthz.or(readyz).unify()
+}
+
+
+async fn watchdog(limit: u64, is_exit: bool) {
+    let mut is_stuck = false;
+    loop {
+        sleep(Duration::from_secs(std::cmp::max(1, std::cmp::min(limit / 4, 10)))).await;
+        let age = heartbeat_age();
+        if age > limit && !is_stuck {
+            is_stuck = true;
+            error!("WATCHDOG: no strategy cycle was finished for {} seconds (limit: {})", age, limit);
+            if is_exit {
+                error!("WATCHDOG: exit to be restarted");
+                std::process::exit(2);
+            }
+        }
+        else if age <= limit && is_stuck {
+            is_stuck = false;
+            info!(target: "NOTIFICATION", "WATCHDOG: strategy loop is alive again");
+        }
+    }
+}
+
+
+/// Run watchdog (if enabled) which alerts (and optionally exits) when strategy loop is stuck.
+///
+pub fn run_watchdog(config: &Config) {
+    lazy_static::initialize(&STARTED_TIMESTAMP);
+    if config.watchdog.enabled {
+        tokio::task::spawn(watchdog(stall_limit(config), config.watchdog.exit));
+    }
+}
@@ -14,15 +14,23 @@ use std::cmp::max;
 use std::convert::From;
 use std::ffi::OsStr;
 use std::process::Command;
-use tokio::{ try_join, time::{ sleep, Duration } };
+use tokio::{ join, time::{ sleep, Duration } };
 
 use openlimits::dydx::{ decode_eth_address, decode_eth_key };
 use openlimits::errors::*;
 use openlimits::exchange::*;
 use openlimits::model::*;
 
+use crate::allowlist::Target;
+use crate::api;
+use crate::audit;
 use crate::consts::*;
+use crate::digest;
+use crate::fees::Route;
+use crate::limits;
+use crate::market_info::RFE;
 use crate::monitoring;
+use crate::secrets::Secret;
 use crate::types::*;
 use crate::utils::*;
 
@@ -54,64 +62,23 @@ pub fn err(msg: String) -> core::result::Result<(), StrategyError> { // TODO add
 }
 
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum RFE { // round for exchange
-    Price,
-    Amount,
-}
-
-
-/// Round up the `amount` to meet the requirements of the exchage `we`.
+/// Part of order amount which is really ordered on the exchange `we`.
 ///
-fn round_for_exchange(side: Side, we: WhichExchange, _from: &str, _to: &str, rfe: RFE, amount: Value) -> Value {
-    let rs = match (side, rfe) {
-        (Side::Buy, RFE::Price) => rust_decimal::RoundingStrategy::AwayFromZero,
-        (Side::Buy, RFE::Amount) => rust_decimal::RoundingStrategy::ToZero,
-        (Side::Sell, RFE::Price) => rust_decimal::RoundingStrategy::ToZero,
-        (Side::Sell, RFE::Amount) => rust_decimal::RoundingStrategy::ToZero, //???
-    };
+fn amount_safety_factor(we: WhichExchange) -> Value {
     match we {
-        WhichExchange::First => {
-            let dp = match rfe {
-                RFE::Price => 1,
-                RFE::Amount => 1,
-            };
-            amount.round_dp_with_strategy(dp, rs)
-        },
-        WhichExchange::Second => {
-            let dp = match rfe {
-                RFE::Price => 4,
-                RFE::Amount => 4,
-            };
-            let amnt = match rfe {
-                RFE::Price => amount,
-                RFE::Amount => amount * dec!(0.999), // sometimes Kraken for some reason throw
-                                                     // "Insufficient funds" error, so we slightly
-                                                     // decrease amount.
-            };
-            amnt.round_dp_with_strategy(dp, rs)
-        }
-        WhichExchange::Exchange => {
-            // TODO check again or move to config file
-            let dp = match rfe {
-                RFE::Price => 3,
-                RFE::Amount => 2,
-            };
-            amount.round_dp_with_strategy(dp, rs)
-        }
-        WhichExchange::Wallet => todo!(),
+        // Sometimes Kraken for some reason throw "Insufficient funds" error,
+        // so we slightly decrease amount.
+        WhichExchange::Second => dec!(0.999),
+        _ => ONE,
     }
 }
 
 
-pub async fn get_token_price(ctx: &mut Context, we: WhichExchange) -> StrategyResult<BuySellPrice> {
+pub async fn get_token_price<A: Access>(ctx: &mut Context<A>, we: WhichExchange) -> StrategyResult<BuySellPrice> {
     let market_pair = get_market_pair_name(we, OPERATING_COIN, INTERMEDIATE_COIN);
-    let ob = match we {
-            WhichExchange::First => &ctx.connections.e1 as &dyn ExchangeMarketData,
-            WhichExchange::Second => &ctx.connections.e2 as &dyn ExchangeMarketData,
-            WhichExchange::Exchange => &ctx.connections.ee_trade as &dyn ExchangeMarketData,
-            WhichExchange::Wallet => unreachable!(),
-        }.order_book(&OrderBookRequest { market_pair: market_pair.to_string() }).await?;
+    let exch_md = ctx.connections.market_data(we);
+    let ob = monitoring::timed_api_call(we, "order_book",
+        exch_md.order_book(&OrderBookRequest { market_pair: market_pair.to_string() })).await?;
     Ok(BuySellPrice {
         buy: ob.asks.first().expect("Some asks must be present").price,
         sell: ob.bids.first().expect("Some bids must be present").price
@@ -130,38 +97,50 @@ pub async fn change_tokens_ex(ctx: &mut Context, we: WhichExchange, side: Side,
     let we_name = get_exchange_name(we);
     debug!("{}: {} {} to {} (market pair: {})", we_name, op_name, asset, TRANSFERRING_COIN, market_pair) ;
     let (exch_md, exch_acc): (&dyn ExchangeMarketData, &dyn ExchangeAccount) = match we {
-        WhichExchange::First => (&ctx.connections.e1, &ctx.connections.e1),
-        WhichExchange::Second => (&ctx.connections.e2, &ctx.connections.e2),
-        WhichExchange::Exchange => (&ctx.connections.ee_trade, &ctx.connections.ee_trade),
+        WhichExchange::First => (ctx.connections.e1(), ctx.connections.e1()),
+        WhichExchange::Second => (ctx.connections.e2(), ctx.connections.e2()),
+        WhichExchange::Exchange => (ctx.connections.ee_trade(), ctx.connections.ee_trade()),
         WhichExchange::Wallet => unreachable!(),
     };
+    let order_call = if is_buying { "limit_buy" } else { "limit_sell" };
+    let pre_balances = ctx.balances;
+    let mut reservation = None;
     loop {
         debug!("Try to get orderbook for market pair {}", market_pair);
-        let ob = exch_md.order_book(&OrderBookRequest { market_pair: market_pair.to_string() }).await?;
+        let ob = monitoring::timed_api_call(we, "order_book",
+            exch_md.order_book(&OrderBookRequest { market_pair: market_pair.to_string() })).await?;
         trace!("Order book asks: {:?}", ob.asks);
         trace!("Order book bids: {:?}", ob.bids);
         let price = if is_buying { ob.asks } else { ob.bids }.first().expect("Some asks/bids must be present").price;
         debug!("Current {} price: {}", market_pair, price);
         let limit_rounding_coeff = dec!(0.01); // we slightly increase/decrease limit price to buy in one order
+        let market_info = *ctx.markets.get(we);
         let (fixed_amount, fixed_price) : (SecondaryAsset, Price) =
             if is_buying {
-                let limit_price = round_for_exchange(Side::Buy, we, TRANSFERRING_COIN, OPERATING_COIN, RFE::Price, price * (ONE + limit_rounding_coeff));
+                let limit_price = market_info.round(Side::Buy, RFE::Price, price * (ONE + limit_rounding_coeff));
                 let to_amount_raw = match asset {
                     Asset::Primary(amount) => amount.to_sec(limit_price),
                     Asset::Secondary(amount) => amount,
                 };
-                let to_amount = round_for_exchange(Side::Buy, we, TRANSFERRING_COIN, OPERATING_COIN, RFE::Amount, *to_amount_raw).into();
+                let to_amount = market_info.round(Side::Buy, RFE::Amount, *to_amount_raw * amount_safety_factor(we)).into();
                 (to_amount, limit_price)
             }
             else {
-                let limit_price = round_for_exchange(Side::Sell, we, TRANSFERRING_COIN, OPERATING_COIN, RFE::Price, price * (ONE - limit_rounding_coeff));
+                let limit_price = market_info.round(Side::Sell, RFE::Price, price * (ONE - limit_rounding_coeff));
                 let to_amount_raw = match asset {
                     Asset::Primary(amount) => amount.to_sec(limit_price),
                     Asset::Secondary(amount) => amount,
                 };
-                let to_amount = round_for_exchange(Side::Sell, we, TRANSFERRING_COIN, OPERATING_COIN, RFE::Amount, *to_amount_raw).into();
+                let to_amount = market_info.round(Side::Sell, RFE::Amount, *to_amount_raw * amount_safety_factor(we)).into();
                 (to_amount, limit_price)
             };
+        market_info.validate(we, fixed_amount, fixed_price)?;
+        stop_if_dry_run(ctx, format!("{} {} at {} on {}", op_name.to_lowercase(), fixed_amount, fixed_price, we_name))?;
+        // NOTE: retries of the same order are not counted again
+        if reservation.is_none() {
+            reservation = Some(limits::check(&ctx.limits, limits::Operation::Order {
+                we, side: if is_buying { Side::Buy } else { Side::Sell }, amount: fixed_amount, notional: *fixed_amount * fixed_price })?);
+        }
         let req = OpenLimitOrderRequest
                 { market_pair: market_pair.to_owned(),
                   size: *fixed_amount,
@@ -171,7 +150,12 @@ pub async fn change_tokens_ex(ctx: &mut Context, we: WhichExchange, side: Side,
                   client_order_id: Some(get_random_client_order_id()),
                 };
         debug!("{}: {} {} (price: {}, limit price: {}), request: {:?}", we_name, op_name, fixed_amount, price, fixed_price, req);
-        match if is_buying { exch_acc.limit_buy(&req).await } else { exch_acc.limit_sell(&req).await } {
+        let order_result = if is_buying { monitoring::timed_api_call(we, order_call, exch_acc.limit_buy(&req)).await }
+                           else { monitoring::timed_api_call(we, order_call, exch_acc.limit_sell(&req)).await };
+        // NOTE: order is recorded at once (it is on venue even if waiting for filling fails), filling is the next record
+        audit::record(ctx, we, order_call, format!("{:?}", req), format!("{:?}", order_result),
+                      order_result.as_ref().ok().map(|ord| ord.id.clone()), pre_balances);
+        match order_result {
             Err(err) => {
                 if is_error_insufficient_funds(&err) { // TODO
                     warn!("{}: buying {}: Can't execute order on price {}. Error: \"{}\". Retrying...",
@@ -183,13 +167,19 @@ pub async fn change_tokens_ex(ctx: &mut Context, we: WhichExchange, side: Side,
                 }
             },
             Ok(ord) => {
+                if let Some(reservation) = reservation.take() {
+                    reservation.commit();
+                }
                 debug!("Order info: {:?}", ord);
                 debug!("Order ID: {}", ord.id);
                 loop {
-                    let Order { status, .. } = exch_acc.get_order(&GetOrderRequest { id: ord.id.clone(), market_pair: Some(market_pair.to_owned()) }).await?;
-                    if status == OrderStatus::Filled {
+                    let filled = monitoring::timed_api_call(we, "get_order",
+                        exch_acc.get_order(&GetOrderRequest { id: ord.id.clone(), market_pair: Some(market_pair.to_owned()) })).await?;
+                    if filled.status == OrderStatus::Filled {
                         debug!("Order {} is filled", ord.id);
+                        digest::observe_trade(*fixed_amount * fixed_price, ctx.fees.trade_relative(we));
                         update_balances(ctx, Some(we)).await?;
+                        audit::record(ctx, we, "order_filled", format!("{:?}", req), format!("{:?}", filled), Some(ord.id.clone()), pre_balances);
                         break;
                     }
                     debug!("Waiting for filling order {}", ord.id);
@@ -203,9 +193,35 @@ pub async fn change_tokens_ex(ctx: &mut Context, we: WhichExchange, side: Side,
 }
 
 
-fn get_value_by_regex(s: &str, re: &str) -> String {
+/// Stop action before side effect `what` in dry run (risk limits aren't counted for it).
+///
+fn stop_if_dry_run(ctx: &Context, what: String) -> ActionResult {
+    if ctx.dry_run {
+        return Err(StrategyError::DryRun { what });
+    }
+    Ok(())
+}
+
+
+/// Record side effect to audit log, post balances are reloaded from `we`.
+///
+async fn audit_side_effect(ctx: &mut Context, we: WhichExchange, operation: &str, request: String, response: String,
+                           id: Option<String>, pre_balances: Option<Balances>) {
+    if let Err(err) = update_balances(ctx, Some(we)).await {
+        warn!("{}: can't load balances for audit record: {}", get_exchange_name(we), err.to_string());
+    }
+    audit::record(ctx, we, operation, request, response, id, pre_balances);
+}
+
+
+fn find_value_by_regex(s: &str, re: &str) -> Option<String> {
     let pattern = Regex::new(re).unwrap();
-    pattern.captures(s).unwrap()[1].to_owned()
+    pattern.captures(s).map(|c| c[1].to_owned())
+}
+
+
+fn get_value_by_regex(s: &str, re: &str) -> String {
+    find_value_by_regex(s, re).unwrap()
 }
 
 
@@ -230,18 +246,32 @@ pub async fn dydx_close_short_position(ctx: &mut Context, amount: SecondaryAsset
 }
 
 
-async fn run_script<I, S>(args: I) -> StrategyResult<String>
+/// Run wallet script, `secrets` are passed by environment variables.
+///
+/// Output is logged (trace) unless `is_secret_output` (output contains secrets).
+///
+async fn run_script<I, S>(args: I, secrets: &[(&str, &Secret)], is_secret_output: bool) -> StrategyResult<String>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
     let mut cmd_ = Command::new("python3");
     let cmd = cmd_.arg("-m").arg("eth-operations").args(args);
-    trace!("{}: cmd: {:?}", WALLET_NAME, cmd);
+    for (name, secret) in secrets {
+        cmd.env(name, secret.expose());
+    }
+    // NOTE: not `{:?}` of command, because it shows environment
+    let cmd_args: Vec<&OsStr> = cmd.get_args().collect();
+    trace!("{}: cmd: {:?}", WALLET_NAME, cmd_args);
     let output = cmd.output()?;
-    trace!("{}: cmd: {:?}, {}", WALLET_NAME, cmd, output.status);
+    trace!("{}: cmd: {:?}, {}", WALLET_NAME, cmd_args, output.status);
     let stdout: String = String::from_utf8(output.stdout).unwrap().trim().to_owned();
-    trace!("{}: STDOUT: \"{}\"", WALLET_NAME, stdout);
+    if is_secret_output {
+        trace!("{}: STDOUT: {} bytes (hidden)", WALLET_NAME, stdout.len());
+    }
+    else {
+        trace!("{}: STDOUT: \"{}\"", WALLET_NAME, stdout);
+    }
     let stderr: String = String::from_utf8(output.stderr).unwrap().trim().to_owned();
     trace!("{}: STDERR: \"{}\"", WALLET_NAME, stderr);
     if !output.status.success() {
@@ -257,8 +287,8 @@ where
 }
 
 
-async fn get_e1_balances(e1: &FirstExchange) -> StrategyResult<E1Balances> {
-    match e1.get_account_balances(None).await {
+async fn get_e1_balances<A: Access>(connections: &Connections<A>) -> StrategyResult<E1Balances> {
+    match connections.account_balances(WhichExchange::First, false).await {
         Err(err) => Err(StrategyError::Misc { msg: err.to_string() }),
         Ok(bals) => {
             trace!("Balances on {}: {:?}", get_exchange_name(WhichExchange::First), bals);
@@ -278,8 +308,8 @@ async fn get_e1_balances(e1: &FirstExchange) -> StrategyResult<E1Balances> {
 }
 
 
-async fn get_e2_balances(e2: &SecondExchange) -> StrategyResult<E2Balances> {
-    match e2.get_account_balances(None).await {
+async fn get_e2_balances<A: Access>(connections: &Connections<A>) -> StrategyResult<E2Balances> {
+    match connections.account_balances(WhichExchange::Second, false).await {
         Err(err) => Err(StrategyError::Misc { msg: err.to_string() }),
         Ok(bals) => {
             trace!("Balances on {}: {:?}", get_exchange_name(WhichExchange::Second), bals);
@@ -301,8 +331,8 @@ async fn get_e2_balances(e2: &SecondExchange) -> StrategyResult<E2Balances> {
 }
 
 
-async fn get_ee_balances(ee: & ExchangeExchange) -> StrategyResult<EEBalances> {
-    match ee.get_account_balances(None).await {
+async fn get_ee_balances<A: Access>(connections: &Connections<A>, is_funding: bool) -> StrategyResult<EEBalances> {
+    match connections.account_balances(WhichExchange::Exchange, is_funding).await {
         Err(err) => Err(StrategyError::Misc { msg: err.to_string() }),
         Ok(bals) => {
             //trace!("Balances on {}: {:?}", get_exchange_name(WhichExchange::Exchange), bals);
@@ -322,7 +352,7 @@ async fn get_ee_balances(ee: & ExchangeExchange) -> StrategyResult<EEBalances> {
 
 async fn get_wallet_balances() -> StrategyResult<WalletBalances> {
     trace!("{}: Getting balances via external script", WALLET_NAME);
-    let raw_balances = run_script(["--balance"]).await?;
+    let raw_balances = run_script(["--balance"], &[], false).await?;
     Ok(WalletBalances {
         transferring_coins: Decimal::new(get_amount_by_regex(&raw_balances, r"USDC: ([0-9]+)"), 6).into(),
         gas_coins: Decimal::new(get_amount_by_regex(&raw_balances, r"ETH: ([0-9]+)"), 18)
@@ -330,10 +360,41 @@ async fn get_wallet_balances() -> StrategyResult<WalletBalances> {
 }
 
 
+/// Credentials of dYdX.
+///
+#[derive(Debug, Clone)]
+pub struct DydxKeys {
+    pub key: Secret,
+    pub secret: Secret,
+    pub passphrase: Secret,
+    pub stark_private_key: Secret,
+    /// Known for derived keys only
+    pub stark_public_key: Option<String>,
+}
+
+
+/// Derive credentials of dYdX from ETH private key of wallet (like dYdX onboarding does).
+///
+pub async fn derive_dydx_keys(wallet_secret: &Secret) -> StrategyResult<DydxKeys> {
+    trace!("{}: Deriving keys via external script", E1_NAME);
+    let raw_keys = run_script(["--derive-dydx-keys"], &[("ETH_PRIVATE_KEY", wallet_secret)], true).await?;
+    // NOTE: output contains secrets, so it is never shown
+    let field = |name: &str, re: &str| find_value_by_regex(&raw_keys, re).ok_or_else(|| StrategyError::Misc {
+        msg: format!("no {} in output of script (is dydx-v3-python up to date?)", name) });
+    Ok(DydxKeys {
+        key: Secret::new(field("API_KEY", r"API_KEY: (\S+)")?),
+        secret: Secret::new(field("API_SECRET", r"API_SECRET: (\S+)")?),
+        passphrase: Secret::new(field("API_PASSPHRASE", r"API_PASSPHRASE: (\S+)")?),
+        stark_private_key: Secret::new(field("STARK_PRIVATE_KEY", r"STARK_PRIVATE_KEY: ([0-9a-fA-F]+)")?),
+        stark_public_key: Some(field("STARK_PUBLIC_KEY", r"STARK_PUBLIC_KEY: ([0-9a-fA-F]+)")?),
+    })
+}
+
+
 /// Load balances from one of exchanges
 ///
 #[async_recursion]
-pub async fn update_balances(ctx: &mut Context, we: Option<WhichExchange>) -> core::result::Result<Balances, StrategyError> {
+pub async fn update_balances<A: Access>(ctx: &mut Context<A>, we: Option<WhichExchange>) -> core::result::Result<Balances, StrategyError> {
     //
     // TODO caching
     // TODO additional functions for cache invalidation
@@ -344,40 +405,51 @@ pub async fn update_balances(ctx: &mut Context, we: Option<WhichExchange>) -> co
     match we {
         None => {
             let (e1, e2, ee, w) =
-                try_join!(
-                    get_e1_balances(&ctx.connections.e1),
-                    get_e2_balances(&ctx.connections.e2),
-                    get_ee_balances(&ctx.connections.ee_funding),
-                    get_wallet_balances())?;
+                join!(
+                    monitoring::timed_api_call(WhichExchange::First, "get_balances", get_e1_balances(&ctx.connections)),
+                    monitoring::timed_api_call(WhichExchange::Second, "get_balances", get_e2_balances(&ctx.connections)),
+                    monitoring::timed_api_call(WhichExchange::Exchange, "get_balances", get_ee_balances(&ctx.connections, true)),
+                    monitoring::timed_api_call(WhichExchange::Wallet, "get_balances", get_wallet_balances()));
+            api::set_venue_status(WhichExchange::First, &e1);
+            api::set_venue_status(WhichExchange::Second, &e2);
+            api::set_venue_status(WhichExchange::Exchange, &ee);
+            api::set_venue_status(WhichExchange::Wallet, &w);
+            let (e1, e2, ee, w) = (e1?, e2?, ee?, w?);
             if let Some(ref mut b) = ctx.balances {
                 b.e1 = e1;
                 b.e2 = e2;
                 b.ee = ee;
                 b.wallet = w;
             }
-            Ok(ctx.balances.unwrap())
         }
         Some(WhichExchange::First) => {
-            let e1_bal = get_e1_balances(&ctx.connections.e1).await?;
+            let e1_bal = monitoring::timed_api_call(WhichExchange::First, "get_balances", get_e1_balances(&ctx.connections)).await;
+            api::set_venue_status(WhichExchange::First, &e1_bal);
+            let e1_bal = e1_bal?;
             if let Some(ref mut b) = ctx.balances { b.e1 = e1_bal; }
-            Ok(ctx.balances.unwrap())
         },
         Some(WhichExchange::Second) => {
-            let e2_bal = get_e2_balances(&ctx.connections.e2).await?;
+            let e2_bal = monitoring::timed_api_call(WhichExchange::Second, "get_balances", get_e2_balances(&ctx.connections)).await;
+            api::set_venue_status(WhichExchange::Second, &e2_bal);
+            let e2_bal = e2_bal?;
             if let Some(ref mut b) = ctx.balances { b.e2 = e2_bal; }
-            Ok(ctx.balances.unwrap())
         },
         Some(WhichExchange::Exchange) => {
-            let ee_bal = get_ee_balances(&ctx.connections.ee_trade).await?;
+            let ee_bal = monitoring::timed_api_call(WhichExchange::Exchange, "get_balances", get_ee_balances(&ctx.connections, false)).await;
+            api::set_venue_status(WhichExchange::Exchange, &ee_bal);
+            let ee_bal = ee_bal?;
             if let Some(ref mut b) = ctx.balances { b.ee = ee_bal; }
-            Ok(ctx.balances.unwrap())
         },
         Some(WhichExchange::Wallet) =>  {
-            let wallet_bal = get_wallet_balances().await?;
+            let wallet_bal = monitoring::timed_api_call(WhichExchange::Wallet, "get_balances", get_wallet_balances()).await;
+            api::set_venue_status(WhichExchange::Wallet, &wallet_bal);
+            let wallet_bal = wallet_bal?;
             if let Some(ref mut b) = ctx.balances { b.wallet = wallet_bal; }
-            Ok(ctx.balances.unwrap())
         }
     }
+    let balances = ctx.balances.unwrap();
+    api::set_balances(balances);
+    Ok(balances)
 }
 
 
@@ -398,7 +470,7 @@ pub fn is_error_insufficient_funds(err: &OpenLimitsError) -> bool {
 
 /// Asks and outputs to log current balances on exchanges.
 ///
-pub async fn log_balances(ctx: &mut Context) -> ActionResult {
+pub async fn log_balances<A: Access>(ctx: &mut Context<A>) -> ActionResult {
     let balances = update_balances(ctx, None).await?;
     info!("{} balance: total: {}, free: {}, in operation: {}",
         E1_NAME,
@@ -427,17 +499,40 @@ pub async fn internal_do_deposit(ctx: &mut Context, we: WhichExchange, amount: P
     let we_name = get_exchange_name(we);
     let am : Value = *amount * dec!(1000000);
     let raw_amount = am.round_dp_with_strategy(0, RoundingStrategy::ToZero);
+    stop_if_dry_run(ctx, format!("deposit {} from {} to {}", amount, WALLET_NAME, we_name))?;
+    let reservation = limits::check(&ctx.limits, limits::Operation::Transfer { route: Route::from_wallet(we), notional: *amount })?;
     let bal = update_balances(ctx, None).await?;
     let wallet_initial_prim_amount = bal.wallet.transferring_coins;
     let wallet_initial_gas_amount = bal.wallet.gas_coins;
     debug!("{}: Make deposit {} to {}", WALLET_NAME, amount, get_exchange_name(we));
     let number = match we { WhichExchange::First => "1", WhichExchange::Second => "2", WhichExchange::Exchange => "3", _ => unimplemented!() };
-    let raw_answer = run_script(["--deposit", number, &raw_amount.to_string()]).await?;
+    // NOTE: dYdX deposit goes to its contract
+    let to_address = match we {
+        WhichExchange::Second => ctx.e2_transferring_token_deposit_address.as_str(),
+        WhichExchange::Exchange => ctx.ee_transferring_token_deposit_address.as_str(),
+        _ => "",
+    };
+    if !to_address.is_empty() {
+        ctx.allowlist.verify(TRANSFERRING_COIN, TRANSFERRING_NETWORK, Target::Address(to_address, None), we).await?;
+    }
+    let pre_balances = ctx.balances;
+    let request = format!("deposit {} {} to {} {}", raw_amount, TRANSFERRING_COIN, we_name, to_address);
+    let raw_answer = monitoring::timed_api_call(WhichExchange::Wallet, "deposit",
+        run_script(["--deposit", number, &raw_amount.to_string(), to_address],
+                   &[("ETH_PRIVATE_KEY", ctx.connections.wallet_secret()), ("DYDX_STARK_PRIVATE_KEY", ctx.connections.e1_stark_private_key())],
+                   false)).await;
+    let txhash_re = Regex::new(r"TXHASH: (0x[0-9a-fA-F]+)").unwrap();
+    audit_side_effect(ctx, WhichExchange::Wallet, "deposit", request, format!("{:?}", raw_answer),
+                      raw_answer.as_ref().ok().and_then(|a| txhash_re.captures(a)).map(|c| c[1].to_owned()), pre_balances).await;
+    let raw_answer = raw_answer?;
+    reservation.commit();
     let txhash = get_value_by_regex(&raw_answer, r"TXHASH: (0x[0-9a-fA-F]+)");
     info!("{}: {} sent to {}, txhash: https://etherscan.io/tx/{}", WALLET_NAME, amount, get_exchange_name(we), txhash);
-    let amount_after_comission = amount * dec!(0.9); // commisions etc...
+    let route = Route::from_wallet(we);
+    let amount_after_comission = PrimaryAsset(ctx.fees.expected_arrival(route, *amount));
     let initial_we_amount = get_free_transferring_coins(&bal, we);
     let expected_amount = amount_after_comission + initial_we_amount;
+    let _in_flight = api::transfer_in_flight(route, we, TRANSFERRING_COIN, *amount);
     loop {
         let current_free = get_free_transferring_coins(&update_balances(ctx, Some(we)).await?, we);
         if current_free >= expected_amount {
@@ -461,18 +556,12 @@ pub async fn internal_do_deposit(ctx: &mut Context, we: WhichExchange, amount: P
     // That is why we limit spending to "0".
     let expense = max(wallet_prim_delta - we_prim_delta, ZERO_P);
     info!("{}: topped up to {}, expense: {}, gas: {} ETH", we_name, now_free, expense, gas_expense);
+    ctx.fees.observe(route, *amount, *expense);
+    digest::observe_transfer(route, *amount, *expense);
+    monitoring::add_expense(route, route.coin(), expense.to_f());
     if gas_expense >= ZERO { 
-        match we {
-            WhichExchange::First => {
-                monitoring::WALLET_TO_E1_GAS_EXPENSE.inc_by(gas_expense.to_f64().unwrap());
-                monitoring::WALLET_TO_E1_PRIM_EXPENSE.inc_by(expense.to_f());
-            },
-            WhichExchange::Exchange => {
-                monitoring::WALLET_TO_EE_GAS_EXPENSE.inc_by(gas_expense.to_f64().unwrap());
-                monitoring::WALLET_TO_EE_PRIM_EXPENSE.inc_by(expense.to_f());
-            },
-            _ => unimplemented!(),
-        }
+        digest::observe_gas(gas_expense);
+        monitoring::add_expense(route, GAS_COIN, gas_expense.to_f64().unwrap());
     }
     Ok(())
 }
@@ -486,6 +575,12 @@ pub async fn internal_do_e1_deposit(ctx: &mut Context, amount: PrimaryAsset) ->
 pub async fn internal_do_ee_to_e2_deposit_operating(ctx: &mut Context, amount: SecondaryAsset) -> ActionResult {
     // TODO DRY: unite code with `internal_do_withdraw`
     let init_ee_coins = update_balances(ctx, Some(WhichExchange::Exchange)).await?.ee.operational_coins;
+    let price = avg_price(&get_token_price(ctx, WhichExchange::Exchange).await?);
+    stop_if_dry_run(ctx, format!("withdraw {} from {} to {}", amount, EE_NAME, E2_NAME))?;
+    let reservation = limits::check(&ctx.limits, limits::Operation::Transfer { route: Route::EEToE2, notional: *amount.to_prim(price) })?;
+    ctx.allowlist.verify(OPERATING_COIN, OPERATING_NETWORK,
+                         Target::Address(&ctx.e2_operational_token_deposit_address, ctx.e2_operational_token_deposit_memo.as_deref()),
+                         WhichExchange::Second).await?;
     let wreq = WithdrawRequest {
         asset: OPERATING_COIN.to_string(),
         amount: *amount,
@@ -493,18 +588,23 @@ pub async fn internal_do_ee_to_e2_deposit_operating(ctx: &mut Context, amount: S
             client_id: Some(get_random_client_order_id()),
             network: None,
             address: ctx.e2_operational_token_deposit_address.to_owned(),
-            address_tag: None,
+            address_tag: ctx.e2_operational_token_deposit_memo.to_owned(),
             transaction_fee_flag: None,
             name: None,
             wallet_type: None,
             }};
     debug!("{}: withdraw request to {}: {:?}", EE_NAME, E2_NAME, &wreq);
-    let wres = ctx.connections.ee_funding.withdraw(&wreq).await?;
+    let pre_balances = ctx.balances;
+    let wres = monitoring::timed_api_call(WhichExchange::Exchange, "withdraw", ctx.connections.ee_funding().withdraw(&wreq)).await;
+    audit_side_effect(ctx, WhichExchange::Exchange, "withdraw", format!("{:?}", wreq), format!("{:?}", wres),
+                      wres.as_ref().ok().map(|r| r.refid.to_string()), pre_balances).await;
+    let wres = wres?;
+    reservation.commit();
     debug!("{}: withdraw response: {:?}", EE_NAME, &wres);
     let mut bal = update_balances(ctx, Some(WhichExchange::Second)).await?;
     let init_e2_coins = bal.e2.unstaked_coins;
-    let commission = dec!(0.01);
-    let expected_coins = init_e2_coins + (amount - commission.into()) * dec!(0.9); // commissions, etc.
+    let expected_coins = init_e2_coins + SecondaryAsset(ctx.fees.expected_arrival(Route::EEToE2, *amount));
+    let _in_flight = api::transfer_in_flight(Route::EEToE2, WhichExchange::Second, OPERATING_COIN, *amount);
     loop {
         sleep(Duration::from_secs(5)).await;
         bal = update_balances(ctx, Some(WhichExchange::Second)).await?;
@@ -526,7 +626,9 @@ pub async fn internal_do_ee_to_e2_deposit_operating(ctx: &mut Context, amount: S
         warn!("{}: withdrawn: negative expense: {}", EE_NAME, expense);
     }
     else {
-        monitoring::EE_TO_E2_SEC_EXPENSE.inc_by(expense.to_f());
+        monitoring::add_expense(Route::EEToE2, Route::EEToE2.coin(), expense.to_f());
+        ctx.fees.observe(Route::EEToE2, *amount, *expense);
+        digest::observe_transfer(Route::EEToE2, *amount, *expense);
     }
     Ok(())
 }
@@ -536,17 +638,27 @@ pub async fn internal_do_e2_to_ee_deposit_operating(ctx: &mut Context, amount: S
     // TODO DRY: unite code with `internal_do_withdraw`
     let bal = update_balances(ctx, Some(WhichExchange::Second)).await?;
     let init_e2_coins = bal.e2.unstaked_coins;
+    let price = avg_price(&get_token_price(ctx, WhichExchange::Exchange).await?);
+    stop_if_dry_run(ctx, format!("withdraw {} from {} to {}", amount, E2_NAME, EE_NAME))?;
+    let reservation = limits::check(&ctx.limits, limits::Operation::Transfer { route: Route::E2ToEE, notional: *amount.to_prim(price) })?;
+    ctx.allowlist.verify(OPERATING_COIN, OPERATING_NETWORK,
+                         Target::KrakenKey(&ctx.e2_withdrawal_key_operational), WhichExchange::Exchange).await?;
     let wreq = WithdrawRequest {
         asset: OPERATING_COIN.to_string(),
         amount: amount.round(6).into(),
         details: WithdrawalRequestDetails::Kraken { key: ctx.e2_withdrawal_key_operational.to_owned() } };
     debug!("{}: {} withdraw request to {}: {:?}", E2_NAME, OPERATING_COIN, EE_NAME, &wreq);
-    let wres = ctx.connections.e2.withdraw(&wreq).await?;
+    let pre_balances = ctx.balances;
+    let wres = monitoring::timed_api_call(WhichExchange::Second, "withdraw", ctx.connections.e2().withdraw(&wreq)).await;
+    audit_side_effect(ctx, WhichExchange::Second, "withdraw", format!("{:?}", wreq), format!("{:?}", wres),
+                      wres.as_ref().ok().map(|r| r.refid.to_string()), pre_balances).await;
+    let wres = wres?;
+    reservation.commit();
     debug!("{}: withdraw response: {:?}", E2_NAME, &wres);
     let mut bal = update_balances(ctx, Some(WhichExchange::Exchange)).await?;
     let init_ee_coins = bal.ee.operational_coins;
-    let commission = SecondaryAsset(dec!(0.1));
-    let expected_coins = init_ee_coins + (amount - commission) * dec!(0.9); // commissions, etc.
+    let expected_coins = init_ee_coins + SecondaryAsset(ctx.fees.expected_arrival(Route::E2ToEE, *amount));
+    let _in_flight = api::transfer_in_flight(Route::E2ToEE, WhichExchange::Exchange, OPERATING_COIN, *amount);
     loop {
         sleep(Duration::from_secs(5)).await;
         bal = update_balances(ctx, Some(WhichExchange::Exchange)).await?;
@@ -564,17 +676,27 @@ pub async fn internal_do_e2_to_ee_deposit_operating(ctx: &mut Context, amount: S
         warn!("{}: withdrawn: negative expense: {}", EE_NAME, expense);
     }
     else {
-        monitoring::E2_TO_EE_SEC_EXPENSE.inc_by(expense.to_f());
+        monitoring::add_expense(Route::E2ToEE, Route::E2ToEE.coin(), expense.to_f());
+        ctx.fees.observe(Route::E2ToEE, *amount, *expense);
+        digest::observe_transfer(Route::E2ToEE, *amount, *expense);
     }
     Ok(())
 }
 
 
 async fn make_withdraw_request(ctx: &mut Context, amount: PrimaryAsset, we: WhichExchange) -> StrategyResult<WithdrawRequest> {
+    let target = match we {
+        WhichExchange::First => Target::Address(&ctx.e1_eth_withdraw_address, None),
+        WhichExchange::Second => Target::KrakenKey(&ctx.e2_withdrawal_key),
+        WhichExchange::Exchange => Target::Address(&ctx.wallet_withdraw_address, None),
+        WhichExchange::Wallet => unreachable!(),
+    };
+    ctx.allowlist.verify(TRANSFERRING_COIN, TRANSFERRING_NETWORK, target, WhichExchange::Wallet).await?;
     match we {
         WhichExchange::First => {
             let amount_rounded = Value::from(amount).round_dp_with_strategy(2, RoundingStrategy::ToZero);
-            let info = ctx.connections.e1.get_fast_withdrawal_info(Some(amount_rounded), None).await.unwrap();
+            let info = monitoring::timed_api_call(we, "get_fast_withdrawal_info",
+                ctx.connections.e1().get_fast_withdrawal_info(Some(amount_rounded), None)).await.unwrap();
             debug!("{}: fast withdrawal info: {:?}", E1_NAME, info);
             let lp = info.liquidity_providers.values().next().unwrap();
             let deb_amount: Value = lp.quote.as_ref().unwrap().debit_amount.parse().unwrap();
@@ -633,24 +755,31 @@ pub async fn internal_do_withdraw(ctx: &mut Context, amount: PrimaryAsset, we: W
               we_name, amount, withdrawal_min);
         return Ok(()); // TODO
     }
+    stop_if_dry_run(ctx, format!("withdraw {} from {} to {}", amount, we_name, WALLET_NAME))?;
+    let reservation = limits::check(&ctx.limits, limits::Operation::Transfer { route: Route::to_wallet(we), notional: *amount })?;
     let wreq = make_withdraw_request(ctx, amount, we).await?;
     let mut bal = update_balances(ctx, Some(WhichExchange::Wallet)).await?;
     let init_coins = bal.wallet.transferring_coins;
-    let commission = (if we == WhichExchange::Second { E2_WITHDRAW_COMMISSION } else { ZERO }).into();
-    let expected_coins = init_coins + (amount - commission) * dec!(0.9); // commissions, etc.
+    let route = Route::to_wallet(we);
+    let expected_coins = init_coins + PrimaryAsset(ctx.fees.expected_arrival(route, *amount));
     debug!("{}: trying to withdraw: request: {:?}, current balances: {:?}", we_name, wreq, bal);
-    let wreqid = match we {
-        WhichExchange::First => ctx.connections.e1.withdraw(&wreq).await?,
-        WhichExchange::Second => ctx.connections.e2.withdraw(&wreq).await?,
-        WhichExchange::Exchange => ctx.connections.ee_funding.withdraw(&wreq).await?,
+    let pre_balances = ctx.balances;
+    let wres = match we {
+        WhichExchange::First => monitoring::timed_api_call(we, "withdraw", ctx.connections.e1().withdraw(&wreq)).await,
+        WhichExchange::Second => monitoring::timed_api_call(we, "withdraw", ctx.connections.e2().withdraw(&wreq)).await,
+        WhichExchange::Exchange => monitoring::timed_api_call(we, "withdraw", ctx.connections.ee_funding().withdraw(&wreq)).await,
         _ => unreachable!(),
     };
+    audit_side_effect(ctx, we, "withdraw", format!("{:?}", wreq), format!("{:?}", wres),
+                      wres.as_ref().ok().map(|r| r.refid.to_string()), pre_balances).await;
+    let wreqid = wres?;
+    reservation.commit();
     debug!("{}: withdraw {:?} refid: {}", we_name, wreq, wreqid.refid);
+    let _in_flight = api::transfer_in_flight(route, WhichExchange::Wallet, TRANSFERRING_COIN, *amount);
     loop {
         sleep(Duration::from_secs(5)).await;
         bal = update_balances(ctx, Some(WhichExchange::Wallet)).await?;
         if bal.wallet.transferring_coins > expected_coins {
-
             break;
         };
         debug!("{}: waiting for withdraw from {}, current balances: {}",
@@ -665,11 +794,9 @@ pub async fn internal_do_withdraw(ctx: &mut Context, amount: PrimaryAsset, we: W
     info!("{}: {} withdrawn, wallet: {}, expense: {}",
           we_name, amount, bal.wallet.transferring_coins, expense);
     if expense >= ZERO_P {
-        match we {
-            WhichExchange::First => monitoring::E1_TO_WALLET_PRIM_EXPENSE.inc_by(expense.to_f()),
-            WhichExchange::Exchange => monitoring::EE_TO_WALLET_PRIM_EXPENSE.inc_by(expense.to_f()),
-            _ => unimplemented!(),
-        }
+        ctx.fees.observe(route, *amount, *expense);
+        digest::observe_transfer(route, *amount, *expense);
+        monitoring::add_expense(route, route.coin(), expense.to_f());
     }
     else {
         warn!("{}: negative transfer expense! {}", we_name, expense);
@@ -689,21 +816,27 @@ pub async fn stake_unstake_impl(ctx: &mut Context, is_stake: bool, amount: Optio
     let prev_opposite_amount = if is_stake { bal.e2.staked_coins } else { bal.e2.unstaked_coins }; // TODO
     let op_prefix = if is_stake { "" } else { "un" };
     if su_amount < ctx.staking_min_limit { return err(format!("Too low {} tokens: {}!", E2_STAKED_COIN, su_amount)); }
+    stop_if_dry_run(ctx, format!("{}stake {} on {}", op_prefix, su_amount, E2_NAME))?;
     info!("{}: trying to {}stake {}...", E2_NAME, op_prefix, su_amount);
+    let pre_balances = ctx.balances;
     let order_id = if is_stake {
         debug!("{}: trying to stake {:?}...", E2_NAME, su_amount);
-        ctx.connections.e2.stake_assets(
-            &StakeAssetRequest {
+        let req = StakeAssetRequest {
                 method: "cosmos-staked".to_string(), // TODO select depends on E2_UNSTAKED_COIN
                 asset: E2_UNSTAKED_COIN.to_string(),
-                amount: (*su_amount).to_string() }).await?;
+                amount: (*su_amount).to_string() };
+        let res = monitoring::timed_api_call(WhichExchange::Second, "stake_assets", ctx.connections.e2().stake_assets(&req)).await;
+        audit_side_effect(ctx, WhichExchange::Second, "stake", format!("{:?}", req), format!("{:?}", res), None, pre_balances).await;
+        res?;
         "unknown".to_string() // TODO `stake_assets` must return ref_id to output to log
     }
     else {
-        ctx.connections.e2.unstake_assets(
-            &UnstakeAssetRequest {
+        let req = UnstakeAssetRequest {
                 asset: E2_STAKED_COIN.to_string(),
-                amount: (*su_amount).to_string() }).await?;
+                amount: (*su_amount).to_string() };
+        let res = monitoring::timed_api_call(WhichExchange::Second, "unstake_assets", ctx.connections.e2().unstake_assets(&req)).await;
+        audit_side_effect(ctx, WhichExchange::Second, "unstake", format!("{:?}", req), format!("{:?}", res), None, pre_balances).await;
+        res?;
         "unknown".to_string()
     };
     loop { // TODO waiting on special transactions, not balances
@@ -716,13 +849,14 @@ pub async fn stake_unstake_impl(ctx: &mut Context, is_stake: bool, amount: Optio
         }
     };
     info!("{}: {} {} {}staked", E2_NAME, su_amount, E2_UNSTAKED_COIN, op_prefix);
+    digest::observe_staking(if is_stake { *su_amount } else { -*su_amount });
     Ok(())
 }
 
 
 /// Generates random order size (according to config).
 ///
-fn rnd_order_size(ctx: &Context, to_change: PrimaryAsset) -> PrimaryAsset {
+fn rnd_order_size<A: Access>(ctx: &Context<A>, to_change: PrimaryAsset) -> PrimaryAsset {
     // TODO how to use rand::Normal with Decimal??
     let max_order_usdc: f64 = ctx.consts.max_order_usdc.to_f64().unwrap();
     let max_order_std_dev_usdc: f64 = ctx.consts.max_order_std_dev_usdc.to_f64().unwrap();
@@ -737,7 +871,7 @@ fn rnd_order_size(ctx: &Context, to_change: PrimaryAsset) -> PrimaryAsset {
 }
 
 
-pub fn get_single_order_size(ctx: &Context, to_change: PrimaryAsset) -> PrimaryAsset {
+pub fn get_single_order_size<A: Access>(ctx: &Context<A>, to_change: PrimaryAsset) -> PrimaryAsset {
     if ctx.consts.max_order_usdc.is_zero() {
         to_change
     }
@@ -747,7 +881,7 @@ pub fn get_single_order_size(ctx: &Context, to_change: PrimaryAsset) -> PrimaryA
 }
 
 
-pub fn get_single_order_size_sec(ctx: &Context, to_change: SecondaryAsset, price: Price) -> SecondaryAsset {
+pub fn get_single_order_size_sec<A: Access>(ctx: &Context<A>, to_change: SecondaryAsset, price: Price) -> SecondaryAsset {
     ONE_S.max(get_single_order_size(ctx, to_change.to_prim(price)).to_sec(price))
 }
 
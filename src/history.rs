@@ -0,0 +1,219 @@
+/// Local history of balances, prices and ratio
+///
+/// Every cycle of strategy (and monitoring) stores one sample to SQLite database, so history
+/// is available without Prometheus (for reports, backtesting, etc.). Samples older than
+/// `retention_days` are removed. `history` subcommand shows range of samples or exports it
+/// to CSV.
+///
+
+use chrono::{ TimeZone, Utc };
+use lazy_static::*;
+use log::warn;
+use rusqlite::{ params_from_iter, Connection };
+use rusqlite::types::Value as SqlValue;
+use rust_decimal::prelude::ToPrimitive;
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::config::HistoryConfig;
+use crate::types::*;
+
+
+/// Defines `Sample` with `f64` fields and the same columns of table (in the same order).
+///
+macro_rules! sample {
+    ($( $(#[$doc:meta])* $field:ident, )*) => {
+        /// Values observed in one cycle.
+        ///
+        #[derive(Debug, Clone, Copy, Default)]
+        pub struct Sample {
+            /// Unix timestamp
+            pub timestamp: i64,
+            $( $(#[$doc])* pub $field: f64, )*
+        }
+
+        const COLUMNS: &[&str] = &[ $( stringify!($field), )* ];
+
+        impl Sample {
+            fn values(&self) -> Vec<f64> {
+                vec![ $( self.$field, )* ]
+            }
+
+            fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Sample> {
+                let mut column = 0;
+                Ok(Sample {
+                    timestamp: row.get(0)?,
+                    $( $field: { column += 1; row.get(column)? }, )*
+                })
+            }
+        }
+    };
+}
+
+
+sample! {
+    e1_total,
+    e1_free,
+    /// Operating coins on E1 (negative for short position)
+    e1_operational,
+    e2_transferring,
+    e2_intermediate,
+    e2_staked,
+    e2_unstaked,
+    ee_transferring,
+    ee_operational,
+    wallet_transferring,
+    wallet_gas,
+    /// Average prices of operating coin
+    e1_price,
+    e2_price,
+    ee_price,
+    /// Values of venues in transferring coins
+    e1_value,
+    e2_value,
+    ee_value,
+    wallet_value,
+    total,
+    ratio,
+}
+
+
+impl Sample {
+    /// `prices` and `values` are in order E1, E2, EE (and wallet for `values`).
+    ///
+    pub fn new(timestamp: i64, bal: &Balances, prices: [Price; 3], values: [Value; 4], ratio: Value) -> Sample {
+        let f = |v: Value| v.to_f64().unwrap_or(f64::NAN);
+        Sample {
+            timestamp,
+            e1_total: f(*bal.e1.total),
+            e1_free: f(*bal.e1.free),
+            e1_operational: f(*bal.e1.operational_coins),
+            e2_transferring: f(*bal.e2.transferring_coins),
+            e2_intermediate: f(*bal.e2.intermediate_coins),
+            e2_staked: f(*bal.e2.staked_coins),
+            e2_unstaked: f(*bal.e2.unstaked_coins),
+            ee_transferring: f(*bal.ee.transferring_coins),
+            ee_operational: f(*bal.ee.operational_coins),
+            wallet_transferring: f(*bal.wallet.transferring_coins),
+            wallet_gas: f(bal.wallet.gas_coins),
+            e1_price: f(prices[0]),
+            e2_price: f(prices[1]),
+            ee_price: f(prices[2]),
+            e1_value: f(values[0]),
+            e2_value: f(values[1]),
+            ee_value: f(values[2]),
+            wallet_value: f(values[3]),
+            total: f(values.iter().sum()),
+            ratio: f(ratio),
+        }
+    }
+}
+
+
+lazy_static! {
+    /// Connection of strategy (`None` until the first sample)
+    static ref DB: Mutex<Option<Connection>> = Mutex::new(None);
+}
+
+
+fn open(file: &str) -> rusqlite::Result<Connection> {
+    let db = Connection::open(file)?;
+    db.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS samples (timestamp INTEGER NOT NULL, {});
+         CREATE INDEX IF NOT EXISTS samples_timestamp ON samples (timestamp);",
+        COLUMNS.iter().map(|c| format!("{} REAL", c)).collect::<Vec<_>>().join(", ")))?;
+    Ok(db)
+}
+
+
+fn insert(db: &Connection, cfg: &HistoryConfig, sample: &Sample) -> rusqlite::Result<()> {
+    db.execute(
+        &format!("INSERT INTO samples (timestamp, {}) VALUES (?{})", COLUMNS.join(", "), ", ?".repeat(COLUMNS.len())),
+        params_from_iter(std::iter::once(SqlValue::Integer(sample.timestamp)).chain(sample.values().into_iter().map(SqlValue::Real))))?;
+    if let Some(days) = cfg.retention_days {
+        db.execute("DELETE FROM samples WHERE timestamp < ?", [sample.timestamp - (days * 24 * 3600) as i64])?;
+    }
+    Ok(())
+}
+
+
+/// Store sample (errors are only logged, history isn't required for strategy).
+///
+pub fn record(cfg: &HistoryConfig, sample: &Sample) {
+    if !cfg.enabled {
+        return;
+    }
+    let mut db = DB.lock().unwrap();
+    if db.is_none() {
+        match open(&cfg.file) {
+            Ok(connection) => *db = Some(connection),
+            Err(err) => {
+                warn!("Can't open history {}: {}", cfg.file, err);
+                return;
+            },
+        }
+    }
+    if let Err(err) = insert(db.as_ref().unwrap(), cfg, sample) {
+        warn!("Can't store sample to history {}: {}", cfg.file, err);
+    }
+}
+
+
+/// Samples within range of Unix timestamps (inclusive).
+///
+pub fn query(file: &str, from: i64, to: i64) -> Result<Vec<Sample>, String> {
+    if !std::path::Path::new(file).exists() {
+        return Err(format!("{} doesn't exist", file));
+    }
+    let db = open(file).map_err(|err| err.to_string())?;
+    let mut statement = db.prepare(&format!("SELECT timestamp, {} FROM samples WHERE timestamp >= ? AND timestamp <= ? ORDER BY timestamp",
+                                            COLUMNS.join(", ")))
+        .map_err(|err| err.to_string())?;
+    let samples = statement.query_map([from, to], Sample::from_row).map_err(|err| err.to_string())?
+        .collect::<rusqlite::Result<Vec<Sample>>>()
+        .map_err(|err| err.to_string())?;
+    Ok(samples)
+}
+
+
+fn format_time(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0).single().map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default()
+}
+
+
+pub fn write_csv<W: Write>(samples: &[Sample], out: &mut W) -> std::io::Result<()> {
+    writeln!(out, "timestamp,time,{}", COLUMNS.join(","))?;
+    for sample in samples {
+        let values: Vec<String> = sample.values().iter().map(|v| v.to_string()).collect();
+        writeln!(out, "{},{},{}", sample.timestamp, format_time(sample.timestamp), values.join(","))?;
+    }
+    Ok(())
+}
+
+
+fn print_table(samples: &[Sample]) {
+    println!("{:19} {:>12} {:>8} {:>9} {:>9} {:>9} {:>10} {:>10}",
+             "time (UTC)", "total", "ratio", "e1 price", "e2 price", "ee price", "staked", "short");
+    for s in samples {
+        println!("{:19} {:>12.2} {:>8.4} {:>9.4} {:>9.4} {:>9.4} {:>10.4} {:>10.4}",
+                 format_time(s.timestamp), s.total, s.ratio, s.e1_price, s.e2_price, s.ee_price, s.e2_staked, -s.e1_operational);
+    }
+    println!("{} samples", samples.len());
+}
+
+
+/// `history` subcommand: show samples or export them to CSV (`-` -- stdout).
+///
+pub fn history_command(cfg: &HistoryConfig, from: i64, to: i64, csv: Option<&str>) -> Result<(), String> {
+    let samples = query(&cfg.file, from, to)?;
+    match csv {
+        None => print_table(&samples),
+        Some("-") => write_csv(&samples, &mut std::io::stdout().lock()).map_err(|err| err.to_string())?,
+        Some(file) => {
+            let mut out = std::io::BufWriter::new(std::fs::File::create(file).map_err(|err| format!("Can't create {}: {}", file, err))?);
+            write_csv(&samples, &mut out).and_then(|_| out.flush()).map_err(|err| err.to_string())?;
+            eprintln!("{} samples are exported to {}", samples.len(), file);
+        },
+    }
+    Ok(())
+}
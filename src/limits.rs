@@ -0,0 +1,328 @@
+/// Risk limits
+///
+/// Every order and transfer is checked before it is made:
+///
+/// * notional of one order or transfer,
+/// * volume transferred by route within 24 hours,
+/// * number of operations within an hour,
+/// * change of hedge (short position on E1) within 24 hours.
+///
+/// Breach refuses the operation, pauses strategy and raises alert. Passed operation is reserved,
+/// and it is counted only after venue accepts it (see `Reservation`). Counted operations are
+/// stored to `state_file`, so counters are kept after restart.
+///
+
+use lazy_static::*;
+use log::{ debug, error, warn };
+use openlimits::model::Side;
+use serde::{ Deserialize, Serialize };
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::api::now_timestamp;
+use crate::config::LimitsConfig;
+use crate::consts::*;
+use crate::control;
+use crate::fees::Route;
+use crate::monitoring;
+use crate::types::*;
+use crate::utils::*;
+
+
+const HOUR: u64 = 3600;
+const DAY: u64 = 24 * HOUR;
+
+
+/// Operation to check.
+///
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Order { we: WhichExchange, side: Side, amount: SecondaryAsset, notional: Value },
+    /// `notional` is in transferring coins
+    Transfer { route: Route, notional: Value },
+}
+
+
+/// Passed operation.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    timestamp: u64,
+    /// Route of transfer (`None` for order)
+    route: Option<String>,
+    notional: Value,
+    /// Change of short position on E1 (in operating coins)
+    hedge_change: Value,
+    /// ID of reservation (0 for counted operations)
+    #[serde(skip)]
+    reservation: u64,
+}
+
+
+lazy_static! {
+    /// Operations of the last 24 hours (`None` until loaded from file)
+    static ref RECORDS: Mutex<Option<Vec<Record>>> = Mutex::new(None);
+}
+
+
+static NEXT_RESERVATION: AtomicU64 = AtomicU64::new(1);
+
+
+fn load_records(cfg: &LimitsConfig) -> Vec<Record> {
+    match std::fs::read_to_string(&cfg.state_file) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|err| {
+            warn!("Bad state of limits {}: {}, counters are reset", cfg.state_file, err);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+
+/// NOTE: reserved operations are not saved
+///
+fn save_records(state_file: &str, records: &[Record]) {
+    let counted: Vec<&Record> = records.iter().filter(|r| r.reservation == 0).collect();
+    let result = serde_json::to_string_pretty(&counted).map_err(|err| err.to_string())
+        .and_then(|content| std::fs::write(state_file, content).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        error!("Can't save state of limits to {}: {}", state_file, err);
+    }
+}
+
+
+fn to_record(op: &Operation, timestamp: u64) -> Record {
+    match op {
+        Operation::Order { we, side, amount, notional } => Record {
+            timestamp,
+            route: None,
+            notional: *notional,
+            // Selling on E1 enlarges short position
+            hedge_change: match (*we, side) {
+                (WhichExchange::First, Side::Sell) => **amount,
+                (WhichExchange::First, Side::Buy) => -**amount,
+                _ => ZERO,
+            },
+            reservation: 0,
+        },
+        Operation::Transfer { route, notional } =>
+            Record { timestamp, route: Some(route.to_string()), notional: *notional, hedge_change: ZERO, reservation: 0 },
+    }
+}
+
+
+/// Breached limit: name (for metric) and message.
+///
+fn find_breach(cfg: &LimitsConfig, records: &[Record], new: &Record) -> Option<(&'static str, String)> {
+    match &new.route {
+        None => {
+            if let Some(max) = cfg.max_order_notional.filter(|max| new.notional > *max) {
+                return Some(("order_notional", format!("order notional {:.2} {} is above {:.2}", new.notional, TRANSFERRING_COIN, max)));
+            }
+        },
+        Some(route) => {
+            if let Some(max) = cfg.max_transfer_notional.filter(|max| new.notional > *max) {
+                return Some(("transfer_notional", format!("transfer notional {:.2} {} ({}) is above {:.2}", new.notional, TRANSFERRING_COIN, route, max)));
+            }
+            if let Some(max) = cfg.max_daily_route_volumes.get(route).copied().or(cfg.max_daily_route_volume) {
+                let volume: Value = records.iter().filter(|r| r.route.as_ref() == Some(route)).map(|r| r.notional).sum::<Value>() + new.notional;
+                if volume > max {
+                    return Some(("daily_route_volume", format!("volume of {} within 24 hours {:.2} {} is above {:.2}", route, volume, TRANSFERRING_COIN, max)));
+                }
+            }
+        },
+    }
+    if let Some(max) = cfg.max_operations_per_hour {
+        let count = records.iter().filter(|r| r.timestamp + HOUR > new.timestamp).count() + 1;
+        if count > max {
+            return Some(("operations_per_hour", format!("{} operations within an hour, maximum is {}", count, max)));
+        }
+    }
+    if let Some(max) = cfg.max_daily_hedge_change {
+        let before: Value = records.iter().map(|r| r.hedge_change).sum();
+        let change = before + new.hedge_change;
+        // NOTE: operation which returns hedge closer to its size of 24 hours ago is allowed
+        if change.abs() > max && change.abs() > before.abs() {
+            return Some(("daily_hedge_change", format!("change of short position within 24 hours {:.4} {} is above {:.4}", change, OPERATING_COIN, max)));
+        }
+    }
+    None
+}
+
+
+/// Operation which passed limits: it is counted by `commit()` after venue accepts it, and it is
+/// released if dropped without commit (so failed operations are not counted).
+///
+#[must_use]
+pub struct Reservation {
+    id: u64,
+    state_file: String,
+}
+
+
+impl Reservation {
+    pub fn commit(self) {
+        if let Some(records) = RECORDS.lock().unwrap().as_mut() {
+            if let Some(record) = records.iter_mut().find(|r| r.reservation == self.id) {
+                record.reservation = 0;
+            }
+            save_records(&self.state_file, records);
+        }
+        // NOTE: `drop` finds nothing to release now
+    }
+}
+
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(records) = RECORDS.lock().unwrap().as_mut() {
+            records.retain(|r| r.reservation != self.id);
+        }
+    }
+}
+
+
+/// Check operation `op`, it is reserved if it is allowed.
+///
+pub fn check(cfg: &LimitsConfig, op: Operation) -> StrategyResult<Reservation> {
+    let now = now_timestamp();
+    let mut records = RECORDS.lock().unwrap();
+    let records = records.get_or_insert_with(|| load_records(cfg));
+    records.retain(|r| r.timestamp + DAY > now);
+    let mut new = to_record(&op, now);
+    if let Some((limit, msg)) = find_breach(cfg, records, &new) {
+        error!(target: "NOTIFICATION", "RISK LIMIT BREACHED: {} ({:?} is refused)", msg, op);
+        monitoring::LIMIT_BREACHES.with_label_values(&[limit]).inc();
+        control::set_paused(true, "risk limits");
+        return Err(StrategyError::Misc { msg: format!("Risk limit breached: {}", msg) });
+    }
+    debug!("Risk limits passed: {:?}", op);
+    let id = NEXT_RESERVATION.fetch_add(1, Ordering::SeqCst);
+    new.reservation = id;
+    records.push(new);
+    Ok(Reservation { id, state_file: cfg.state_file.clone() })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn order(we: WhichExchange, side: Side, amount: Value, notional: Value, timestamp: u64) -> Record {
+        to_record(&Operation::Order { we, side, amount: SecondaryAsset(amount), notional }, timestamp)
+    }
+
+    fn transfer(route: Route, notional: Value, timestamp: u64) -> Record {
+        to_record(&Operation::Transfer { route, notional }, timestamp)
+    }
+
+    fn breach(cfg: &LimitsConfig, records: &[Record], new: &Record) -> Option<&'static str> {
+        find_breach(cfg, records, new).map(|(limit, _)| limit)
+    }
+
+    #[test]
+    fn no_limits() {
+        let records = vec![transfer(Route::EEToE2, dec!(1000000), NOW - 10)];
+        assert_eq!(breach(&LimitsConfig::default(), &records, &order(WhichExchange::First, Side::Sell, dec!(1000000), dec!(1000000), NOW)), None);
+    }
+
+    #[test]
+    fn order_notional() {
+        let cfg = LimitsConfig { max_order_notional: Some(dec!(1000)), ..LimitsConfig::default() };
+        assert_eq!(breach(&cfg, &[], &order(WhichExchange::Second, Side::Buy, dec!(100), dec!(1000), NOW)), None);
+        assert_eq!(breach(&cfg, &[], &order(WhichExchange::Second, Side::Buy, dec!(100), dec!(1000.01), NOW)), Some("order_notional"));
+        // Transfers are limited separately
+        assert_eq!(breach(&cfg, &[], &transfer(Route::EEToE2, dec!(5000), NOW)), None);
+    }
+
+    #[test]
+    fn transfer_notional() {
+        let cfg = LimitsConfig { max_transfer_notional: Some(dec!(500)), ..LimitsConfig::default() };
+        assert_eq!(breach(&cfg, &[], &transfer(Route::WalletToE1, dec!(500), NOW)), None);
+        assert_eq!(breach(&cfg, &[], &transfer(Route::WalletToE1, dec!(501), NOW)), Some("transfer_notional"));
+        assert_eq!(breach(&cfg, &[], &order(WhichExchange::First, Side::Sell, dec!(100), dec!(5000), NOW)), None);
+    }
+
+    #[test]
+    fn daily_route_volume() {
+        let cfg = LimitsConfig { max_daily_route_volume: Some(dec!(1000)), ..LimitsConfig::default() };
+        let records = vec![
+            transfer(Route::WalletToE1, dec!(600), NOW - 20 * HOUR),
+            transfer(Route::E1ToWallet, dec!(900), NOW - HOUR),
+            order(WhichExchange::First, Side::Sell, dec!(100), dec!(900), NOW - HOUR),
+        ];
+        assert_eq!(breach(&cfg, &records, &transfer(Route::WalletToE1, dec!(400), NOW)), None);
+        assert_eq!(breach(&cfg, &records, &transfer(Route::WalletToE1, dec!(401), NOW)), Some("daily_route_volume"));
+        assert_eq!(breach(&cfg, &records, &transfer(Route::EEToE2, dec!(1000), NOW)), None);
+    }
+
+    #[test]
+    fn daily_route_volume_override() {
+        let mut cfg = LimitsConfig { max_daily_route_volume: Some(dec!(1000)), ..LimitsConfig::default() };
+        cfg.max_daily_route_volumes.insert(Route::EEToE2.to_string(), dec!(100));
+        cfg.max_daily_route_volumes.insert(Route::WalletToE1.to_string(), dec!(5000));
+        assert_eq!(breach(&cfg, &[], &transfer(Route::EEToE2, dec!(101), NOW)), Some("daily_route_volume"));
+        assert_eq!(breach(&cfg, &[], &transfer(Route::WalletToE1, dec!(4000), NOW)), None);
+        assert_eq!(breach(&cfg, &[], &transfer(Route::E2ToEE, dec!(1001), NOW)), Some("daily_route_volume"));
+    }
+
+    #[test]
+    fn operations_per_hour() {
+        let cfg = LimitsConfig { max_operations_per_hour: Some(2), ..LimitsConfig::default() };
+        let mut records = vec![
+            transfer(Route::WalletToEE, dec!(10), NOW - 2 * HOUR),
+            order(WhichExchange::Exchange, Side::Buy, dec!(1), dec!(10), NOW - HOUR + 1),
+        ];
+        assert_eq!(breach(&cfg, &records, &transfer(Route::EEToE2, dec!(10), NOW)), None);
+        records.push(transfer(Route::EEToE2, dec!(10), NOW - 10));
+        assert_eq!(breach(&cfg, &records, &order(WhichExchange::Second, Side::Sell, dec!(1), dec!(10), NOW)), Some("operations_per_hour"));
+        // The first of them leaves the hour
+        assert_eq!(breach(&cfg, &records, &order(WhichExchange::Second, Side::Sell, dec!(1), dec!(10), NOW + 1)), None);
+    }
+
+    #[test]
+    fn daily_hedge_change() {
+        let cfg = LimitsConfig { max_daily_hedge_change: Some(dec!(100)), ..LimitsConfig::default() };
+        let records = vec![order(WhichExchange::First, Side::Sell, dec!(80), dec!(800), NOW - 5 * HOUR)];
+        assert_eq!(breach(&cfg, &records, &order(WhichExchange::First, Side::Sell, dec!(20), dec!(200), NOW)), None);
+        assert_eq!(breach(&cfg, &records, &order(WhichExchange::First, Side::Sell, dec!(21), dec!(210), NOW)), Some("daily_hedge_change"));
+        // Only orders on E1 change hedge
+        assert_eq!(breach(&cfg, &records, &order(WhichExchange::Exchange, Side::Sell, dec!(500), dec!(5000), NOW)), None);
+        // Closing of short position within 24 hours is limited as well
+        assert_eq!(breach(&cfg, &[], &order(WhichExchange::First, Side::Buy, dec!(101), dec!(1010), NOW)), Some("daily_hedge_change"));
+    }
+
+    #[test]
+    fn hedge_back_toward_size_of_24_hours_ago() {
+        let cfg = LimitsConfig { max_daily_hedge_change: Some(dec!(100)), ..LimitsConfig::default() };
+        // Limit is already exceeded (e.g. it was lowered), so hedge can only return
+        let records = vec![
+            order(WhichExchange::First, Side::Sell, dec!(90), dec!(900), NOW - 10 * HOUR),
+            order(WhichExchange::First, Side::Sell, dec!(60), dec!(600), NOW - 5 * HOUR),
+        ];
+        assert_eq!(breach(&cfg, &records, &order(WhichExchange::First, Side::Buy, dec!(30), dec!(300), NOW)), None);
+        assert_eq!(breach(&cfg, &records, &order(WhichExchange::First, Side::Buy, dec!(150), dec!(1500), NOW)), None);
+        assert_eq!(breach(&cfg, &records, &order(WhichExchange::First, Side::Sell, dec!(1), dec!(10), NOW)), Some("daily_hedge_change"));
+        // Overshooting to the other side by more than it was moved is refused
+        assert_eq!(breach(&cfg, &records, &order(WhichExchange::First, Side::Buy, dec!(310), dec!(3100), NOW)), Some("daily_hedge_change"));
+    }
+
+    #[test]
+    fn reserved_records_are_not_saved() {
+        let file = std::env::temp_dir().join(format!("limits-state-{}.json", std::process::id()));
+        let state_file = file.to_str().unwrap();
+        let mut reserved = transfer(Route::EEToE2, dec!(10), NOW);
+        reserved.reservation = 7;
+        save_records(state_file, &[transfer(Route::WalletToEE, dec!(20), NOW), reserved]);
+        let cfg = LimitsConfig { state_file: state_file.to_owned(), ..LimitsConfig::default() };
+        let loaded = load_records(&cfg);
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].route, Some(Route::WalletToEE.to_string()));
+        assert_eq!(loaded[0].notional, dec!(20));
+    }
+}
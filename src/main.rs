@@ -10,59 +10,182 @@ use rust_decimal_macros::dec;
 use tokio::time::{sleep,Duration};
 
 
+mod actions;
+mod alerts;
+mod allowlist;
+mod api;
+mod approval;
+mod audit;
+mod bot;
 mod cli;
 mod config;
 mod consts;
+mod control;
+mod digest;
+mod fees;
+mod health;
 mod helpers;
+mod history;
+mod limits;
+mod market_info;
 mod monitoring;
 mod notifications;
+mod notifiers;
+mod secrets;
 mod strategy;
+mod tui;
 mod types;
 mod types_ex;
+mod unwind;
 mod utils;
 
 
-async fn init_exchange(cfg: &config::Config) -> types::Connections {
-    let e1 = {
-        let credentials = Some(DydxCredentials {
-            blockchain_network: BlockchainNetwork::Mainnet,
-            api_key: cfg.dydx.key.clone(),
-            api_secret: cfg.dydx.secret.clone(),
-            passphrase: cfg.dydx.passphrase.clone(),
-            stark_private_key: decode_eth_key(&cfg.dydx.stark_private_key).unwrap(),
-        });
-        let parameters = DydxParameters { credentials };
-        Dydx::new(parameters).await.unwrap()
-    };
+/// Trading credential from config (it can be omitted only for monitoring).
+///
+fn trading_secret(secret: &Option<secrets::Secret>, name: &str) -> secrets::Secret {
+    secret.clone().unwrap_or_else(|| panic!("{} isn't set in config (it is required for trading)", name))
+}
+
 
-    let e2 = {
-        let credentials = Some(KrakenCredentials { api_key: cfg.kraken.key.clone(), api_secret: cfg.kraken.secret.clone() });
-        let parameters = KrakenParameters {
-            credentials,
-            validate_orders: false,
+/// Credentials of dYdX: from config, or (if some are not set) derived from ETH key of wallet.
+///
+async fn get_dydx_keys(cfg: &config::Config) -> helpers::DydxKeys {
+    let dydx = &cfg.dydx;
+    if let (Some(key), Some(secret), Some(passphrase), Some(stark_private_key)) = (&dydx.key, &dydx.secret, &dydx.passphrase, &dydx.stark_private_key) {
+        return helpers::DydxKeys {
+            key: key.clone(),
+            secret: secret.clone(),
+            passphrase: passphrase.clone(),
+            stark_private_key: stark_private_key.clone(),
+            stark_public_key: None,
         };
-        Kraken::new(parameters).await.unwrap()
+    }
+    info!("{}: some credentials are not set, they are derived from ETH key of {}", types::E1_NAME, types::WALLET_NAME);
+    let derived = helpers::derive_dydx_keys(&trading_secret(&cfg.wallet.secret, "wallet.secret")).await.expect("Can't derive dYdX keys");
+    helpers::DydxKeys {
+        key: dydx.key.clone().unwrap_or(derived.key),
+        secret: dydx.secret.clone().unwrap_or(derived.secret),
+        passphrase: dydx.passphrase.clone().unwrap_or(derived.passphrase),
+        stark_private_key: dydx.stark_private_key.clone().unwrap_or(derived.stark_private_key),
+        stark_public_key: derived.stark_public_key,
+    }
+}
+
+
+/// Print dYdX credentials derived from ETH key of wallet as `[dydx]` section of config
+/// (with references to keystore if they are stored there).
+///
+async fn derive_dydx_keys_command(cfg: &config::Config, store: bool) -> Result<(), String> {
+    let wallet_secret = cfg.wallet.secret.as_ref().ok_or("wallet.secret isn't set in config")?;
+    let keys = helpers::derive_dydx_keys(wallet_secret).await.map_err(|err| err.to_string())?;
+    let fields = [
+        ("key", "dydx_key", &keys.key),
+        ("secret", "dydx_secret", &keys.secret),
+        ("passphrase", "dydx_passphrase", &keys.passphrase),
+        ("stark_private_key", "dydx_stark_private_key", &keys.stark_private_key),
+    ];
+    if store {
+        secrets::keystore_store(&fields.iter().map(|(_, name, value)| (*name, value.expose())).collect::<Vec<_>>())?;
+    }
+    println!("[dydx]");
+    for (field, name, value) in fields.iter() {
+        if store { println!("{} = {{ keystore = \"{}\" }}", field, name) }
+        else { println!("{} = \"{}\"", field, value.expose()) }
+    }
+    println!("# STARK public key: {}", keys.stark_public_key.unwrap_or_default());
+    Ok(())
+}
+
+
+static LOG_FILE_NAME: &str = "./vfhedgedstaking.log";
+
+
+/// Placeholder of STARK key for read-only connection (dYdX rejects everything signed by it).
+///
+const READ_ONLY_STARK_PRIVATE_KEY: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+
+
+async fn connect_e1(key: &secrets::Secret, secret: &secrets::Secret, passphrase: &secrets::Secret, stark_private_key: &str) -> Dydx {
+    let credentials = Some(DydxCredentials {
+        blockchain_network: BlockchainNetwork::Mainnet,
+        api_key: key.expose().to_owned(),
+        api_secret: secret.expose().to_owned(),
+        passphrase: passphrase.expose().to_owned(),
+        stark_private_key: decode_eth_key(stark_private_key).unwrap(),
+    });
+    let parameters = DydxParameters { credentials };
+    Dydx::new(parameters).await.unwrap()
+}
+
+
+async fn connect_e2(key: &secrets::Secret, secret: &secrets::Secret) -> Kraken {
+    let credentials = Some(KrakenCredentials { api_key: key.expose().to_owned(), api_secret: secret.expose().to_owned() });
+    let parameters = KrakenParameters {
+        credentials,
+        validate_orders: false,
     };
+    Kraken::new(parameters).await.unwrap()
+}
+
 
+async fn connect_ee(key: &secrets::Secret, secret: &secrets::Secret) -> Binance {
     let auto_cancel = BinanceAutoCancelSettings { interval_ms: 5000, redundancy_percent: 100, retry: 10 };
-    let ee_trade = {
-        let credentials = Some(BinanceCredentials { api_key: cfg.binance.trading_key.to_owned(), api_secret: cfg.binance.trading_secret.to_owned() });
-        let parameters = BinanceParameters { sandbox: false, credentials, auto_cancel: auto_cancel.clone(), };
-        Binance::new(parameters).await.unwrap()
-    };
+    let credentials = Some(BinanceCredentials { api_key: key.expose().to_owned(), api_secret: secret.expose().to_owned() });
+    let parameters = BinanceParameters { sandbox: false, credentials, auto_cancel, };
+    Binance::new(parameters).await.unwrap()
+}
 
-    let ee_funding = {
-        let credentials = Some(BinanceCredentials { api_key: cfg.binance.funding_key.to_owned(), api_secret: cfg.binance.funding_secret.to_owned() });
-        let parameters = BinanceParameters { sandbox: false, credentials, auto_cancel, };
-        Binance::new(parameters).await.unwrap()
-    };
 
-    types::Connections { e1, e2, ee_trade, ee_funding }
+async fn init_exchange(cfg: &config::Config, dydx_keys: &helpers::DydxKeys) -> types::Connections {
+    let e1 = connect_e1(&dydx_keys.key, &dydx_keys.secret, &dydx_keys.passphrase, dydx_keys.stark_private_key.expose()).await;
+    let e2 = connect_e2(&trading_secret(&cfg.kraken.key, "kraken.key"), &trading_secret(&cfg.kraken.secret, "kraken.secret")).await;
+    let ee_trade = connect_ee(&trading_secret(&cfg.binance.trading_key, "binance.trading_key"),
+                              &trading_secret(&cfg.binance.trading_secret, "binance.trading_secret")).await;
+    let ee_funding = connect_ee(&trading_secret(&cfg.binance.funding_key, "binance.funding_key"),
+                                &trading_secret(&cfg.binance.funding_secret, "binance.funding_secret")).await;
+    types::Connections::new(e1, e2, ee_trade, ee_funding,
+                            trading_secret(&cfg.wallet.secret, "wallet.secret"), dydx_keys.stark_private_key.clone())
 }
 
 
-async fn make_ctx(config: &config::Config) -> types::Context {
-    let connections = init_exchange(config).await;
+/// Connections of monitoring mode: `[read_only]` credentials (or trading ones if they are not set
+/// and it is allowed, see `ReadOnlyConfig::check()`).
+///
+/// NOTE: ETH key of wallet is never used here (dYdX API keys must be set explicitly).
+///
+async fn init_read_only_exchange(cfg: &config::Config) -> types::Connections<types::ReadOnly> {
+    let ro = &cfg.read_only;
+    let e1 = match &ro.dydx {
+        Some(c) => connect_e1(&c.key, &c.secret, &c.passphrase, READ_ONLY_STARK_PRIVATE_KEY).await,
+        None => {
+            warn!("{}: read-only credentials are not set, trading ones are used for monitoring", types::E1_NAME);
+            connect_e1(&trading_secret(&cfg.dydx.key, "dydx.key"), &trading_secret(&cfg.dydx.secret, "dydx.secret"),
+                       &trading_secret(&cfg.dydx.passphrase, "dydx.passphrase"), READ_ONLY_STARK_PRIVATE_KEY).await
+        },
+    };
+    let e2 = match &ro.kraken {
+        Some(c) => connect_e2(&c.key, &c.secret).await,
+        None => {
+            warn!("{}: read-only credentials are not set, trading ones are used for monitoring", types::E2_NAME);
+            connect_e2(&trading_secret(&cfg.kraken.key, "kraken.key"), &trading_secret(&cfg.kraken.secret, "kraken.secret")).await
+        },
+    };
+    let (ee_trade, ee_funding) = match &ro.binance {
+        Some(c) => (connect_ee(&c.key, &c.secret).await, connect_ee(&c.key, &c.secret).await),
+        None => {
+            warn!("{}: read-only credentials are not set, trading ones are used for monitoring", types::EE_NAME);
+            (connect_ee(&trading_secret(&cfg.binance.trading_key, "binance.trading_key"),
+                        &trading_secret(&cfg.binance.trading_secret, "binance.trading_secret")).await,
+             connect_ee(&trading_secret(&cfg.binance.funding_key, "binance.funding_key"),
+                        &trading_secret(&cfg.binance.funding_secret, "binance.funding_secret")).await)
+        },
+    };
+    types::Connections::new_read_only(e1, e2, ee_trade, ee_funding)
+}
+
+
+async fn make_context<A: types::Access>(config: &config::Config, connections: types::Connections<A>) -> types::Context<A> {
+    let markets = market_info::load_markets_info(&connections).await;
     types::Context {
             connections,
             transferring_min_limit: dec!(10.0).into(),
@@ -72,25 +195,73 @@ async fn make_ctx(config: &config::Config) -> types::Context {
             e2_withdrawal_key: config.kraken.withdrawal_key.to_owned(),
             e2_withdrawal_key_operational: config.kraken.atom_withdrawal_key.to_owned(),
             e2_operational_token_deposit_address: config.kraken.atom_account.to_owned(),
+            e2_operational_token_deposit_memo: config.kraken.atom_memo.to_owned(),
+            e2_transferring_token_deposit_address: config.kraken.usdc_account.to_owned(),
+            ee_transferring_token_deposit_address: config.binance.usdc_account.to_owned(),
             wallet_withdraw_address: config.wallet.key.to_owned(),
             balances: None,
             consts: config.strategy.clone(),
+            fees: fees::FeeModel::new(config.fees.clone()),
+            approval: config.approval.clone(),
+            allowlist: allowlist::Allowlist::new(config),
+            limits: config.limits.clone(),
+            audit: config.audit.clone(),
+            history: config.history.clone(),
+            dry_run: false,
+            markets,
         }
 }
 
 
+async fn make_ctx(config: &config::Config) -> types::Context {
+    let dydx_keys = get_dydx_keys(config).await;
+    let ctx = make_context(config, init_exchange(config, &dydx_keys).await).await;
+    if !ctx.allowlist.is_enabled() {
+        warn!("Allowlist of withdrawal destinations is disabled");
+    }
+    ctx
+}
+
+
+async fn make_read_only_ctx(config: &config::Config) -> types::Context<types::ReadOnly> {
+    make_context(config, init_read_only_exchange(config).await).await
+}
+
+
 #[tokio::main]
 async fn main() {
     let args = cli::parse();
+    if args.action == cli::Action::ListActions {
+        cli::list_actions();
+        return;
+    }
+    if let cli::Action::Keystore{command} = &args.action {
+        // NOTE: config isn't required (and probably refers to this keystore)
+        let result = match command {
+            cli::KeystoreCommand::Set{name} => secrets::keystore_set(name).map(|_| println!("Secret \"{}\" is stored", name)),
+            cli::KeystoreCommand::List => secrets::keystore_list().map(|names| names.iter().for_each(|n| println!("{}", n))),
+        };
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
     let config = config::read_config("config.toml").unwrap();
+    // NOTE: logger isn't started yet
+    let notifications_writer = notifications::NotificationsLogWriter::new(config.notifications.to_owned()).unwrap_or_else(|err| {
+        eprintln!("config.toml: {}", err);
+        std::process::exit(1);
+    });
 
     //Logger::try_with_env_or_str("trace").unwrap()
-    Logger::try_with_env_or_str("warn,hedged_staking=debug,NOTIFICATION=info").unwrap()
+    let logger = Logger::try_with_env_or_str("warn,hedged_staking=debug,NOTIFICATION=info").unwrap()
         .log_to_file_and_writer(
-            FileSpec::try_from("./vfhedgedstaking.log").unwrap(),
-            Box::new(notifications::TelegramLogWriter { config: config.notifications.to_owned() }))
+            FileSpec::try_from(LOG_FILE_NAME).unwrap(),
+            Box::new(notifications_writer))
         .append()
-        .duplicate_to_stdout(Duplicate::Trace)
+        // NOTE: dashboard shows tail of log file itself
+        .duplicate_to_stdout(if args.action == cli::Action::Tui { Duplicate::None } else { Duplicate::Trace })
         .adaptive_format_for_stdout(AdaptiveFormat::Detailed)
         .format_for_stdout(flexi_logger::colored_detailed_format)
         .format_for_files(flexi_logger::detailed_format)
@@ -109,29 +280,119 @@ async fn main() {
     assert!(config.strategy.low_ratio_percent <= config.strategy.initial_ratio_percent);
     assert!(config.strategy.high_ratio_percent >= config.strategy.initial_ratio_percent);
 
-    monitoring::run_metrics_web_server().await;
+    if let cli::Action::DeriveDydxKeys{store} = args.action {
+        if let Err(err) = derive_dydx_keys_command(&config, store).await {
+            error!("Can't derive keys: {}", err);
+        }
+        return;
+    }
+
+    if let cli::Action::Audit{command: cli::AuditCommand::Verify{file}} = &args.action {
+        let file = file.as_ref().unwrap_or(&config.audit.file);
+        match audit::verify(file) {
+            Ok((count, hash)) => println!("{}: {} records, chain is valid, the last hash: {}", file, count, hash),
+            Err(err) => {
+                eprintln!("{}: {}", file, err);
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    if let cli::Action::History{from, to, csv} = &args.action {
+        if let Err(err) = history::history_command(&config.history, *from, *to, csv.as_deref()) {
+            eprintln!("History: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let cli::Action::Allowlist{command} = &args.action {
+        let result = match command {
+            cli::AllowlistCommand::Sign => allowlist::sign(&config.allowlist).map(|signature| println!("signature = \"{}\"", signature)),
+            cli::AllowlistCommand::Check => allowlist::check_config(&config).await.map_err(|err| err.to_string()),
+        };
+        if let Err(err) = result {
+            error!("Allowlist: {}", err);
+        }
+        return;
+    }
+
+    let is_one_off = matches!(args.action, cli::Action::Only{..} | cli::Action::Unwind{..});
+    if config.server.enabled && (config.server.enabled_for_only || !is_one_off) {
+        monitoring::run_metrics_web_server(&config).await;
+    }
+
+    if args.action == cli::Action::Monitoring {
+        if let Err(err) = config.read_only.check() {
+            error!("Monitoring: {}", err);
+            std::process::exit(1);
+        }
+    }
 
     // TODO: make correct error handling
     match args.action {
-        cli::Action::Run | cli::Action::Monitoring => {
+        cli::Action::Run | cli::Action::Monitoring | cli::Action::Tui => {
+            control::run_control_watchers(&config.strategy);
+            health::run_watchdog(&config);
+            bot::run_bot(&config);
+            digest::run_digest(&config);
+            if args.action == cli::Action::Tui {
+                tui::run_tui(LOG_FILE_NAME);
+            }
+            // Fee estimates learned by previous context (they are kept when context is rebuilt after failure)
+            let mut learned_fees: Option<fees::FeeModel> = None;
             loop {
-                match strategy::strategy(&mut make_ctx(&config).await, args.action == cli::Action::Monitoring).await {
+                let result = match args.action {
+                    cli::Action::Monitoring => strategy::monitoring(&mut make_read_only_ctx(&config).await).await,
+                    _ => {
+                        let mut ctx = make_ctx(&config).await;
+                        if let Some(fees) = learned_fees.take() {
+                            ctx.fees = fees;
+                        }
+                        let result = strategy::strategy(&mut ctx).await;
+                        learned_fees = Some(ctx.fees);
+                        result
+                    },
+                };
+                match result {
                     Ok(_) => { break },
                     Err(err) => {
                         error!("{}", err.to_string());
+                        if control::is_stop_requested() {
+                            break;
+                        }
                         let timeout = 60;
                         info!("Take a pause ({} secs) in the hope that things will get better next time...", timeout);
                         sleep(Duration::from_secs(60)).await;
                     },
                 }
             }
+            logger.flush();
         },
-        cli::Action::Only{action_name, value} => {
-            match strategy::run_action_by_name(action_name, &mut make_ctx(&config).await, value).await {
+        cli::Action::Unwind{percent, reset} => {
+            if reset {
+                if let Err(err) = unwind::reset_unwind() {
+                    error!(">>> {:?}", err);
+                    return;
+                }
+            }
+            match unwind::unwind(&mut make_ctx(&config).await, percent).await {
                 Ok(_) => { },
                 Err(err) => error!(">>> {:?}", err),
             }
-        }
+        },
+        cli::Action::Only{yes, dry_run, action} => {
+            audit::set_trigger(None, &action.name);
+            let mut ctx = make_ctx(&config).await;
+            ctx.dry_run = dry_run;
+            match strategy::run_single_action(&mut ctx, &action.name, action.value, yes).await {
+                Ok(_) => { },
+                Err(err) => error!(">>> {:?}", err),
+            }
+        },
+        cli::Action::ListActions | cli::Action::Keystore{..} | cli::Action::DeriveDydxKeys{..} | cli::Action::Allowlist{..} | cli::Action::Audit{..}
+            | cli::Action::History{..} => unreachable!(),
     }
 
 }
@@ -0,0 +1,153 @@
+/// Exchange precision and lot-size metadata
+///
+/// Metadata is loaded from instrument endpoints of the exchanges at startup,
+/// so rounding follows the current rules of the exchange.
+///
+
+use log::{ debug, info, warn };
+use openlimits::model::Side;
+use openlimits::exchange::*;
+use rust_decimal::RoundingStrategy;
+use rust_decimal_macros::dec;
+
+use crate::consts::*;
+use crate::types::*;
+use crate::utils::*;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RFE { // round for exchange
+    Price,
+    Amount,
+}
+
+
+/// Trading rules of `OPERATING_COIN`/`TRANSFERRING_COIN` market on one exchange.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarketInfo {
+    /// Minimal price increment
+    pub tick_size: Price,
+    /// Minimal amount increment (in `OPERATING_COIN`)
+    pub step_size: Value,
+    /// Minimal order value (in `TRANSFERRING_COIN`)
+    pub min_notional: Value,
+    /// Minimal order amount (in `OPERATING_COIN`)
+    pub min_quantity: Value,
+}
+
+
+impl MarketInfo {
+    /// Rules used when exchange doesn't provide metadata.
+    ///
+    pub fn fallback(we: WhichExchange) -> MarketInfo {
+        match we {
+            WhichExchange::First => MarketInfo {
+                tick_size: dec!(0.1), step_size: dec!(0.1), min_notional: ZERO, min_quantity: ONE },
+            WhichExchange::Second => MarketInfo {
+                tick_size: dec!(0.0001), step_size: dec!(0.0001), min_notional: ZERO, min_quantity: ZERO },
+            WhichExchange::Exchange => MarketInfo {
+                tick_size: dec!(0.001), step_size: dec!(0.01), min_notional: ZERO, min_quantity: ZERO },
+            // USDC token has 6 decimals
+            WhichExchange::Wallet => MarketInfo {
+                tick_size: dec!(0.000001), step_size: dec!(0.000001), min_notional: ZERO, min_quantity: ZERO },
+        }
+    }
+
+    /// Round up the `value` to meet the requirements of the exchange.
+    ///
+    pub fn round(&self, side: Side, rfe: RFE, value: Value) -> Value {
+        let rs = match (side, rfe) {
+            (Side::Buy, RFE::Price) => RoundingStrategy::AwayFromZero,
+            (Side::Buy, RFE::Amount) => RoundingStrategy::ToZero,
+            (Side::Sell, RFE::Price) => RoundingStrategy::ToZero,
+            (Side::Sell, RFE::Amount) => RoundingStrategy::ToZero, //???
+        };
+        let increment = match rfe {
+            RFE::Price => self.tick_size,
+            RFE::Amount => self.step_size,
+        };
+        if increment <= ZERO {
+            return value;
+        }
+        ((value / increment).round_dp_with_strategy(0, rs) * increment).normalize()
+    }
+
+    /// Check order for exchange limits.
+    ///
+    pub fn validate(&self, we: WhichExchange, amount: SecondaryAsset, price: Price) -> ActionResult {
+        let we_name = get_exchange_name(we);
+        if *amount < self.min_quantity {
+            return Err(StrategyError::Misc { msg:
+                format!("{}: order amount {} is less than minimal quantity {} {}",
+                        we_name, amount, self.min_quantity, OPERATING_COIN) });
+        }
+        if *amount.to_prim(price) < self.min_notional {
+            return Err(StrategyError::Misc { msg:
+                format!("{}: order value {} is less than minimal notional {} {}",
+                        we_name, amount.to_prim(price), self.min_notional, TRANSFERRING_COIN) });
+        }
+        Ok(())
+    }
+}
+
+
+/// Trading rules on all exchanges.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarketsInfo {
+    pub e1: MarketInfo,
+    pub e2: MarketInfo,
+    pub ee: MarketInfo,
+    pub wallet: MarketInfo,
+}
+
+
+impl MarketsInfo {
+    pub fn get(&self, we: WhichExchange) -> &MarketInfo {
+        match we {
+            WhichExchange::First => &self.e1,
+            WhichExchange::Second => &self.e2,
+            WhichExchange::Exchange => &self.ee,
+            WhichExchange::Wallet => &self.wallet,
+        }
+    }
+}
+
+
+async fn load_market_info(exch: &dyn ExchangeInfoRetrieval, we: WhichExchange) -> MarketInfo {
+    let market_pair = get_market_pair_name(we, OPERATING_COIN, TRANSFERRING_COIN);
+    let we_name = get_exchange_name(we);
+    let fallback = MarketInfo::fallback(we);
+    match exch.get_pair(&market_pair).await.and_then(|handle| handle.read()) {
+        Ok(pair) => {
+            debug!("{}: market pair {}: {:?}", we_name, market_pair, pair);
+            let info = MarketInfo {
+                tick_size: pair.quote_increment,
+                step_size: pair.base_increment,
+                min_notional: pair.min_quote_trade_size.unwrap_or(fallback.min_notional),
+                min_quantity: pair.min_base_trade_size.unwrap_or(fallback.min_quantity),
+            };
+            info!("{}: {} rules: tick size: {}, step size: {}, min. notional: {}, min. quantity: {}",
+                  we_name, market_pair, info.tick_size, info.step_size, info.min_notional, info.min_quantity);
+            info
+        },
+        Err(err) => {
+            warn!("{}: can't load rules of market {}, use defaults {:?}. Error: {:?}",
+                  we_name, market_pair, fallback, err);
+            fallback
+        }
+    }
+}
+
+
+/// Load trading rules from all exchanges.
+///
+pub async fn load_markets_info<A: Access>(connections: &Connections<A>) -> MarketsInfo {
+    MarketsInfo {
+        e1: load_market_info(connections.info(WhichExchange::First), WhichExchange::First).await,
+        e2: load_market_info(connections.info(WhichExchange::Second), WhichExchange::Second).await,
+        ee: load_market_info(connections.info(WhichExchange::Exchange), WhichExchange::Exchange).await,
+        wallet: MarketInfo::fallback(WhichExchange::Wallet),
+    }
+}
@@ -1,11 +1,21 @@
 /// Monitoring of strategy working
 ///
 
-use prometheus::{register_gauge, Gauge, register_counter, register_int_counter, gather, opts, Counter, IntCounter, Encoder};
+use prometheus::{register_gauge, Gauge, register_int_counter, register_int_gauge, gather, opts, IntCounter, IntGauge, Encoder};
+use prometheus::{register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, histogram_opts, HistogramVec, IntCounterVec, IntGaugeVec};
+use prometheus::{register_gauge_vec, register_counter_vec, GaugeVec, CounterVec};
+use strum::IntoEnumIterator;
 use warp::Filter;
 use warp::*;
 use lazy_static::*;
-
+use std::future::Future;
+use std::sync::{ Arc, Mutex };
+use std::time::Instant;
+
+use crate::api;
+use crate::config::{Config, ServerConfig};
+use crate::fees::Route;
+use crate::health;
 use crate::types::*;
 use crate::consts::*;
 
@@ -15,18 +25,10 @@ pub fn prefix(s: &str) -> String {
     format!("{}_{}", METRIC_PREFIX, s)
 }
 
-pub fn prefix_balance(s: String) -> String {
-    prefix(format!("balance_{}", s).as_str())
-}
-
 pub fn prefix_event(s: String) -> String {
     prefix(format!("event_{}", s).as_str())
 }
 
-pub fn prefix_expense(s: &str) -> String {
-    prefix(format!("expense_{}", s).as_str())
-}
-
 lazy_static! {
     pub static ref PRICE: Gauge =
         register_gauge!(opts!(
@@ -52,145 +54,185 @@ lazy_static! {
                 format!("{}/{} ratio low bound", E1_NAME, E2_NAME)
                 )).unwrap();
 
-    pub static ref E1_BALANCE_TOTAL: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_total", E1_NAME)),
-                format!("{} total balance in {}", E1_NAME, TRANSFERRING_COIN)
-                )).unwrap();
-
-    pub static ref E1_BALANCE_FREE: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_free", E1_NAME)),
-                format!("{} free balance in {}", E1_NAME, TRANSFERRING_COIN)
-                )).unwrap();
-
-    pub static ref E1_BALANCE_SHORTED: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_shorted", E1_NAME)),
-                format!("{} shorted in {}", E1_NAME, OPERATING_COIN)
-                )).unwrap();
-
-    pub static ref E2_BALANCE_TOTAL: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_total", E2_NAME)),
-                format!("{} total balance in {}", E2_NAME, TRANSFERRING_COIN)
-                )).unwrap();
-
-    pub static ref E2_BALANCE_TRANSFERRING: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_{}", E2_NAME, TRANSFERRING_COIN)),
-                format!("{} {} balance", E2_NAME, TRANSFERRING_COIN)
-                )).unwrap();
-
-    pub static ref E2_BALANCE_INTERMEDIATE: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_{}", E2_NAME, INTERMEDIATE_COIN)),
-                format!("{} {} balance", E2_NAME, INTERMEDIATE_COIN)
-                )).unwrap();
+    pub static ref BALANCE: GaugeVec =
+        register_gauge_vec!(opts!(
+                prefix("balance"),
+                "Balance of asset on venue"
+                ), &["role", "venue", "asset", "kind"]).unwrap();
 
-    pub static ref E2_BALANCE_UNSTAKED: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_unstaked_{}", E2_NAME, OPERATING_COIN)),
-                format!("{} unstaked {} balance", E2_NAME, OPERATING_COIN)
+    pub static ref PAUSED: IntGauge =
+        register_int_gauge!(opts!(
+                prefix("paused"),
+                "Strategy is paused (no actions are run)"
                 )).unwrap();
 
-    pub static ref E2_BALANCE_STAKED: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_staked_{}", E2_NAME, OPERATING_COIN)),
-                format!("{} staked {} balance", E2_NAME, OPERATING_COIN)
-                )).unwrap();
+    pub static ref NOTIFICATIONS_DROPPED: IntCounterVec =
+        register_int_counter_vec!(opts!(
+                prefix("notifications_dropped"),
+                "Notifications which were not delivered (queue is full or sending failed)"
+                ), &["sink"]).unwrap();
 
-    pub static ref EE_BALANCE_TOTAL: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_total", EE_NAME)),
-                format!("{} total balance in {}", EE_NAME, TRANSFERRING_COIN)
+    pub static ref EVENT_ABOVE_HIGH: IntCounter =
+        register_int_counter!(opts!(
+                prefix_event("above_high".to_string()),
+                "New 'above high' event"
                 )).unwrap();
 
-    pub static ref EE_BALANCE_TRANSFERRING: Gauge =
+    // Operational metrics
+
+    pub static ref API_LATENCY: HistogramVec =
+        register_histogram_vec!(histogram_opts!(
+                prefix("api_latency_seconds"),
+                "Latency of exchange API calls",
+                vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+                ), &["venue", "call"]).unwrap();
+
+    pub static ref API_ERRORS: IntCounterVec =
+        register_int_counter_vec!(opts!(
+                prefix("api_errors"),
+                "Errors of exchange API calls"
+                ), &["venue", "kind"]).unwrap();
+
+    pub static ref STATE_TRANSITIONS: IntCounterVec =
+        register_int_counter_vec!(opts!(
+                prefix("state_transitions"),
+                "Transitions to strategy state"
+                ), &["state"]).unwrap();
+
+    pub static ref CURRENT_STATE: IntGaugeVec =
+        register_int_gauge_vec!(opts!(
+                prefix("state"),
+                "Current strategy state (1 for current one)"
+                ), &["state"]).unwrap();
+
+    pub static ref TIME_IN_STATE: Gauge =
         register_gauge!(opts!(
-                prefix_balance(format!("{}_{}", EE_NAME, TRANSFERRING_COIN)),
-                format!("{} {} balance", EE_NAME, TRANSFERRING_COIN)
+                prefix("time_in_state_seconds"),
+                "Time spent in the current strategy state"
                 )).unwrap();
 
-    pub static ref EE_BALANCE_OPERATIONAL: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_{}", EE_NAME, OPERATING_COIN)),
-                format!("{} {} balance", EE_NAME, OPERATING_COIN)
-                )).unwrap();
+    pub static ref ACTION_DURATION: HistogramVec =
+        register_histogram_vec!(histogram_opts!(
+                prefix("action_duration_seconds"),
+                "Duration of strategy actions",
+                vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0]
+                ), &["action"]).unwrap();
 
-    pub static ref WALLET_BALANCE_TOTAL: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_{}", WALLET_NAME, TRANSFERRING_COIN)),
-                format!("{} {} balance", WALLET_NAME, TRANSFERRING_COIN)
-                )).unwrap();
+    static ref STATE_SINCE: Mutex<Option<(&'static str, Instant)>> = Mutex::new(None);
 
-    pub static ref WALLET_BALANCE_GAS: Gauge =
-        register_gauge!(opts!(
-                prefix_balance(format!("{}_{}", WALLET_NAME, GAS_COIN)),
-                format!("{} {} balance", WALLET_NAME, GAS_COIN)
-                )).unwrap();
+    // NOTE: Counter values can be obtained by query: curl --request GET http://172.16.57.3:9090/api/v1/query\?query\="expense"
+    pub static ref EXPENSE: CounterVec =
+        register_counter_vec!(opts!(
+                prefix("expense"),
+                "Expenses of transfers by route"
+                ), &["route", "asset"]).unwrap();
+
+    pub static ref WITHDRAWALS_REFUSED: IntCounterVec =
+        register_int_counter_vec!(opts!(
+                prefix("withdrawals_refused"),
+                "Withdrawals refused because destination isn't allowed"
+                ), &["asset"]).unwrap();
+
+    pub static ref LIMIT_BREACHES: IntCounterVec =
+        register_int_counter_vec!(opts!(
+                prefix("limit_breaches"),
+                "Operations refused by risk limits"
+                ), &["limit"]).unwrap();
 
-    pub static ref EVENT_ABOVE_HIGH: IntCounter =
-        register_int_counter!(opts!(
-                prefix_event("above_high".to_string()),
-                "New 'above high' event"
-                )).unwrap();
+}
 
-    // NOTE: Counter values can be obtained by query: curl --request GET http://172.16.57.3:9090/api/v1/query\?query\="expense"
-    // E1 <--> Wallet (USDC, ETH)
 
-    pub static ref E1_TO_WALLET_PRIM_EXPENSE: Counter =
-        register_counter!(opts!(
-                prefix_expense("e1_to_wallet_prim"),
-                "E1->Wallet expense"
-                )).unwrap();
+/// Kind of balance.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum BalanceKind {
+    Free,
+    /// Total value of venue (in transferring coins)
+    Total,
+    Staked,
+    Shorted,
+    /// Sent to the venue, but not arrived yet
+    InTransit,
+}
 
-    pub static ref WALLET_TO_E1_PRIM_EXPENSE: Counter =
-        register_counter!(opts!(
-                prefix_expense("wallet_to_e1_prim"),
-                "Wallet->E1 expense"
-                )).unwrap();
 
-    pub static ref WALLET_TO_E1_GAS_EXPENSE: Counter =
-        register_counter!(opts!(
-                prefix_expense("wallet_to_e1_gas"),
-                "Wallet->E1 expense"
-                )).unwrap();
+/// Set balance of `asset` on exchange `we` (labels are registered on first use).
+///
+pub fn set_balance(we: WhichExchange, asset: &str, kind: BalanceKind, value: f64) {
+    BALANCE.with_label_values(&[get_exchange_role(we), get_exchange_name(we), asset, kind.into()]).set(value);
+}
 
-    // EE <--> Wallet (PRIM, ETH)
 
-    pub static ref EE_TO_WALLET_PRIM_EXPENSE: Counter =
-        register_counter!(opts!(
-                prefix_expense("ee_to_wallet_prim"),
-                "EE->Wallet expense"
-                )).unwrap();
+/// Add realized expense of transfer by `route` (in `asset` coins).
+///
+pub fn add_expense(route: Route, asset: &str, value: f64) {
+    EXPENSE.with_label_values(&[&route.to_string(), asset]).inc_by(value);
+}
 
-    pub static ref WALLET_TO_EE_PRIM_EXPENSE: Counter =
-        register_counter!(opts!(
-                prefix_expense("wallet_to_ee_prim"),
-                "Wallet->EE expense"
-                )).unwrap();
 
-    pub static ref WALLET_TO_EE_GAS_EXPENSE: Counter =
-        register_counter!(opts!(
-                prefix_expense("wallet_to_ee_gas"),
-                "Wallet->E1 expense"
-                )).unwrap();
+/// Rough classification of API error by its message.
+///
+fn error_kind(msg: &str) -> &'static str {
+    let msg = msg.to_lowercase();
+    if msg.contains("insufficient") {
+        "insufficient_funds"
+    }
+    else if msg.contains("timeout") || msg.contains("timed out") {
+        "timeout"
+    }
+    else if msg.contains("rate limit") || msg.contains("too many") || msg.contains("429") {
+        "rate_limit"
+    }
+    else if msg.contains("unauthorized") || msg.contains("invalid key") || msg.contains("signature") || msg.contains("permission") {
+        "auth"
+    }
+    else if msg.contains("connect") || msg.contains("network") || msg.contains("dns") || msg.contains("reqwest") {
+        "network"
+    }
+    else {
+        "other"
+    }
+}
 
-    // EE <--> E2 (SEC)
 
-    pub static ref EE_TO_E2_SEC_EXPENSE: Counter =
-        register_counter!(opts!(
-                prefix_expense("ee_to_e2_sec"),
-                "EE->E2 expense"
-                )).unwrap();
+/// Run API call `fut` to exchange `we`, recording its latency and error (if any).
+///
+pub async fn timed_api_call<F, A, E>(we: WhichExchange, call: &str, fut: F) -> Result<A, E>
+where
+    F: Future<Output = Result<A, E>>,
+    E: ToString,
+{
+    let venue = get_exchange_name(we);
+    let timer = API_LATENCY.with_label_values(&[venue, call]).start_timer();
+    let result = fut.await;
+    timer.observe_duration();
+    if let Err(ref err) = result {
+        API_ERRORS.with_label_values(&[venue, error_kind(&err.to_string())]).inc();
+    }
+    result
+}
 
-    pub static ref E2_TO_EE_SEC_EXPENSE: Counter =
-        register_counter!(opts!(
-                prefix_expense("e2_to_ee_sec"),
-                "E2->EE expense"
-                )).unwrap();
 
+/// Record that strategy is in state `state` (name of variant).
+///
+pub fn observe_state(state: &'static str) {
+    let mut since = STATE_SINCE.lock().unwrap();
+    let current = *since;
+    match current {
+        Some((prev_state, started)) if prev_state == state => {
+            TIME_IN_STATE.set(started.elapsed().as_secs_f64());
+        },
+        prev => {
+            if let Some((prev_state, _)) = prev {
+                CURRENT_STATE.with_label_values(&[prev_state]).set(0);
+            }
+            STATE_TRANSITIONS.with_label_values(&[state]).inc();
+            CURRENT_STATE.with_label_values(&[state]).set(1);
+            TIME_IN_STATE.set(0.0);
+            *since = Some((state, Instant::now()));
+        },
+    }
 }
 
 
@@ -228,19 +270,110 @@ async fn metrics_handler() -> Result<impl Reply, Rejection> {
 }
 
 
-pub async fn run_metrics_web_server() {
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+
+/// Check `Authorization` header against credentials from config.
+///
+/// Control token of API (if set) also gives access to the server.
+///
+fn is_authorized(config: &ServerConfig, api_token: &Option<String>, header: &Option<String>) -> bool {
+    if config.basic_auth.is_none() && config.bearer_token.is_none() {
+        return true;
+    }
+    let header = match header {
+        Some(header) => header.trim(),
+        None => return false,
+    };
+    if let Some(token) = header.strip_prefix("Bearer ") {
+        let token = token.trim();
+        return config.bearer_token.as_ref().map(|t| t.expose()) == Some(token) || api_token.as_deref() == Some(token);
+    }
+    if let (Some(encoded), Some(auth)) = (header.strip_prefix("Basic "), &config.basic_auth) {
+        return match base64::decode(encoded.trim()).map(String::from_utf8) {
+            Ok(Ok(user_password)) => user_password == format!("{}:{}", auth.user, auth.password.expose()),
+            _ => false,
+        };
+    }
+    false
+}
+
+
+fn with_auth(config: &Config) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let server_config = Arc::new(config.server.clone());
+    let api_token = Arc::new(config.api.token.as_ref().map(|t| t.expose().to_owned()));
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let is_ok = is_authorized(&server_config, &api_token, &header);
+            async move {
+                if is_ok { Ok(()) } else { Err(warp::reject::custom(Unauthorized)) }
+            }
+        })
+        .untuple_one()
+}
+
+
+async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        Ok(warp::reply::with_header(
+            warp::reply::with_status("unauthorized", warp::http::StatusCode::UNAUTHORIZED),
+            "WWW-Authenticate", "Basic realm=\"hedgedstaking\""))
+    }
+    else {
+        Err(err)
+    }
+}
+
+
+pub async fn run_metrics_web_server(config: &Config) {
+    // NOTE: server config is checked by `read_config()`
+    let address = config.server.socket_address().expect("Bad server address in config file");
     let metrics_route = warp::path!("metrics").and_then(metrics_handler);
-    tokio::task::spawn(
-        warp::serve(metrics_route).run(([0,0,0,0], 8080))
-        );
+    // NOTE: health checks are used by orchestration, which doesn't authorize
+    let routes = health::routes(config)
+        .or(with_auth(config).and(metrics_route.or(api::routes(&config.api))))
+        .recover(handle_rejection);
+    match config.server.tls().expect("Bad TLS config") {
+        Some((cert, key)) => {
+            tokio::task::spawn(
+                warp::serve(routes).tls().cert_path(cert).key_path(key).run(address)
+                );
+        },
+        None => {
+            tokio::task::spawn(
+                warp::serve(routes).run(address)
+                );
+        },
+    }
     // Initialzie counters to view on Grafana
-    E1_TO_WALLET_PRIM_EXPENSE.reset();
-    WALLET_TO_E1_PRIM_EXPENSE.reset();
-    WALLET_TO_E1_GAS_EXPENSE.reset();
-    EE_TO_WALLET_PRIM_EXPENSE.reset();
-    WALLET_TO_EE_PRIM_EXPENSE.reset();
-    WALLET_TO_EE_GAS_EXPENSE.reset();
-    EE_TO_E2_SEC_EXPENSE.reset();
-    E2_TO_EE_SEC_EXPENSE.reset();
+    PAUSED.set(0);
+    for route in Route::iter() {
+        EXPENSE.with_label_values(&[&route.to_string(), route.coin()]).reset();
+    }
+    for we in [WhichExchange::First, WhichExchange::Second, WhichExchange::Exchange] {
+        EXPENSE.with_label_values(&[&Route::from_wallet(we).to_string(), GAS_COIN]).reset();
+    }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expense_is_accounted_for_every_venue() {
+        for we in [WhichExchange::First, WhichExchange::Second, WhichExchange::Exchange] {
+            for route in [Route::to_wallet(we), Route::from_wallet(we)] {
+                let counter = EXPENSE.with_label_values(&[&route.to_string(), route.coin()]);
+                let before = counter.get();
+                add_expense(route, route.coin(), 1.5);
+                assert_eq!(counter.get() - before, 1.5, "{}", route);
+            }
+        }
+        assert_eq!(Route::to_wallet(WhichExchange::Second), Route::E2ToWallet);
+    }
+}
@@ -1,12 +1,136 @@
+/// Notifications (to Telegram and other sinks, see `notifiers.rs`)
+///
+/// Log records pass routing (see `alerts.rs`), then are put into bounded queue of every sink
+/// and delivered by background thread, so slow or unavailable service never blocks logging
+/// (and trading).
+///
+
 use flexi_logger::*;
 use flexi_logger::writers::*;
+use log::debug;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::mpsc::{ sync_channel, Receiver, SyncSender, TrySendError };
+use std::thread;
+use std::time::{ Duration, Instant };
 use termion::{color, style};
 
-use crate::config::NotificationsConfig;
+use crate::alerts::Router;
+use crate::config::{ DeliveryConfig, NotificationsConfig };
+use crate::monitoring;
+use crate::notifiers::{ make_notifiers, Notification, Notifier, SinkRoute };
+
+
+/// Log target of scheduled summaries: they are sent to sinks (as notifications) without routing.
+///
+pub const DIGEST_TARGET: &str = "NOTIFICATION::digest";
+
+
+/// Separator of batched messages.
+///
+const BATCH_SEPARATOR: &str = "\n\n";
+
+
+/// Message waiting for delivery.
+///
+#[derive(Debug, Clone)]
+struct Outgoing {
+    level: Level,
+    text: String,
+}
+
 
+/// Queue of one sink.
+///
+struct SinkQueue {
+    notifier: Arc<dyn Notifier>,
+    /// Prefixes of log targets (all targets if empty)
+    targets: Vec<String>,
+    queue: SyncSender<Outgoing>,
+    /// Number of messages which are queued but not delivered yet
+    pending: Arc<AtomicUsize>,
+}
+
+
+impl SinkQueue {
+    fn new(route: SinkRoute, config: &DeliveryConfig) -> Self {
+        let notifier: Arc<dyn Notifier> = Arc::from(route.notifier);
+        let (queue, receiver) = sync_channel(config.queue_size);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let delivery_config = config.clone();
+        let delivery_notifier = notifier.clone();
+        let delivery_pending = pending.clone();
+        thread::Builder::new()
+            .name(format!("notify-{}", notifier.name()))
+            .spawn(move || deliver_messages(receiver, delivery_notifier, delivery_config, delivery_pending))
+            .expect("Can't start notifications delivery thread");
+        SinkQueue { notifier, targets: route.targets, queue, pending }
+    }
+
+    fn accepts(&self, notification: &Notification) -> bool {
+        notification.level <= self.notifier.level() &&
+            (self.targets.is_empty() || self.targets.iter().any(|t| notification.target.starts_with(t.as_str())))
+    }
 
-pub struct TelegramLogWriter {
+    /// Put message to queue (never blocks, drops message if queue is full).
+    ///
+    fn enqueue(&self, notification: &Notification) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let msg = Outgoing { level: notification.level, text: self.notifier.format(notification) };
+        if let Err(err) = self.queue.try_send(msg) {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            monitoring::NOTIFICATIONS_DROPPED.with_label_values(&[&self.notifier.name()]).inc();
+            let reason = match err { TrySendError::Full(_) => "queue is full", TrySendError::Disconnected(_) => "delivery is stopped" };
+            // NOTE: not logging, because it is called by logger
+            eprintln!("{}Message to {} is dropped: {}{}", color::Fg(color::Red), self.notifier.name(), reason, style::Reset);
+        }
+    }
+}
+
+
+pub struct NotificationsLogWriter {
     pub config: NotificationsConfig,
+    sinks: Arc<Vec<SinkQueue>>,
+    router: Arc<Mutex<Router>>,
+}
+
+
+impl NotificationsLogWriter {
+    /// Error if some sink or routing is misconfigured.
+    ///
+    pub fn new(config: NotificationsConfig) -> Result<Self, String> {
+        let router = Arc::new(Mutex::new(Router::new(config.routing.clone())?));
+        let sinks: Arc<Vec<SinkQueue>> = Arc::new(make_notifiers(&config)?.into_iter()
+            .map(|route| SinkQueue::new(route, &config.delivery))
+            .collect());
+        let (resolver_sinks, resolver_router) = (sinks.clone(), router.clone());
+        thread::Builder::new()
+            .name("notify-resolver".to_string())
+            .spawn(move || send_resolved(resolver_sinks, resolver_router))
+            .expect("Can't start notifications resolver thread");
+        Ok(NotificationsLogWriter { config, sinks, router })
+    }
+}
+
+
+fn enqueue_to_sinks(sinks: &[SinkQueue], notification: &Notification) {
+    for sink in sinks.iter().filter(|s| s.accepts(notification)) {
+        sink.enqueue(notification);
+    }
+}
+
+
+/// Periodically send "resolved" messages for alerts which are not repeated anymore.
+///
+fn send_resolved(sinks: Arc<Vec<SinkQueue>>, router: Arc<Mutex<Router>>) {
+    loop {
+        thread::sleep(Duration::from_secs(5));
+        let resolved = router.lock().unwrap().take_resolved(Instant::now());
+        for mut notification in resolved {
+            notification.time = chrono::Local::now().to_string();
+            enqueue_to_sinks(&sinks, &notification);
+        }
+    }
 }
 
 
@@ -21,49 +145,41 @@ fn limit_str(s: &String) -> String {
 }
 
 
-impl LogWriter for TelegramLogWriter {
+impl LogWriter for NotificationsLogWriter {
     fn write(&self, now: &mut DeferredNow, record: &Record<'_>) -> std::io::Result<()> {
-        // TODO make delayed send
-        if self.config.telegram_enabled &&
-           (record.level() <= Level::Warn || (record.level() <= Level::Info && record.target() == "NOTIFICATION")) {
-                let raw_msg = limit_str(&record.args().to_string());
-                let msg = html_escape::encode_text(&raw_msg).to_string();
-                let logmsg = match record.level() {
-                    Level::Warn => format!("<b>Warning: {}</b>", msg),
-                    Level::Error => format!("<b>ERROR: {}</b>", msg),
-                    _ => msg.to_owned(),
-                };
-                send_message(
-                    format!("{}\n\n<i>{}</i>",
-                            logmsg,
-                            now.now()),
-                    &self.config.logs_telegram_token,
-                    self.config.logs_telegram_chat_id,
-                    true);
-                if record.level() <= Level::Warn {
-                    let place:String = match (record.file_static(), record.line()) {
-                        (None, None) => String::new(),
-                        (None, Some(line)) => format!("???:{}", line),
-                        (Some(file), None) => file.to_owned(),
-                        (Some(file), Some(line)) => format!("{}:{}", file, line),
-                    };
-                    let logmsg = match record.level() {
-                        Level::Error => format!("<b>ERROR: {}</b>", msg),
-                        _ => msg,
-                    };
-                    send_message(
-                        format!("{}\n\n<code>{}</code>\n<i>{}</i>",
-                                logmsg,
-                                place,
-                                now.now()),
-                        &self.config.alerts_telegram_token,
-                        self.config.alerts_telegram_chat_id,
-                        false);
+        let is_digest = record.target() == DIGEST_TARGET;
+        if record.level() <= Level::Warn || (record.level() <= Level::Info && (record.target() == "NOTIFICATION" || is_digest)) {
+            let place: String = match (record.file_static(), record.line()) {
+                (None, None) => String::new(),
+                (None, Some(line)) => format!("???:{}", line),
+                (Some(file), None) => file.to_owned(),
+                (Some(file), Some(line)) => format!("{}:{}", file, line),
+            };
+            let notification = Notification {
+                level: record.level(),
+                target: record.target().to_owned(),
+                text: limit_str(&record.args().to_string()),
+                place,
+                time: now.now().to_string(),
+                resolved: false,
+            };
+            let routed = if is_digest { Some(notification) }
+                         else { self.router.lock().unwrap().route(notification, Instant::now(), now.now().naive_local().time()) };
+            if let Some(notification) = routed {
+                enqueue_to_sinks(&self.sinks, &notification);
             }
         }
         Ok(())
     }
+
+    /// Wait (limited time) for delivery of queued messages.
+    ///
     fn flush(&self) -> std::io::Result<()> {
+        let started = Instant::now();
+        let is_pending = || self.sinks.iter().any(|s| s.pending.load(Ordering::SeqCst) > 0);
+        while is_pending() && started.elapsed() < Duration::from_secs(self.config.delivery.flush_timeout) {
+            thread::sleep(Duration::from_millis(100));
+        }
         Ok(())
     }
 
@@ -73,30 +189,76 @@ impl LogWriter for TelegramLogWriter {
 }
 
 
-// From:
-// https://docs.rs/telegram_notifyrs/latest/src/telegram_notifyrs/lib.rs.html
-//
+/// Join queued messages (of the same level) into as few messages as possible.
+///
+fn make_batches(messages: Vec<Outgoing>, limit: usize) -> Vec<Outgoing> {
+    let mut batches: Vec<Outgoing> = Vec::new();
+    for msg in messages {
+        match batches.iter_mut().rev().find(|b| b.level == msg.level) {
+            Some(batch) if batch.text.len() + BATCH_SEPARATOR.len() + msg.text.len() <= limit => {
+                batch.text.push_str(BATCH_SEPARATOR);
+                batch.text.push_str(&msg.text);
+            },
+            _ => batches.push(msg),
+        }
+    }
+    batches
+}
 
-/// Sends a Telegram message
+
+/// Background loop of delivery: waits for a message, collects burst of messages
+/// during `batch_delay_ms` and sends them respecting rate limits.
 ///
-/// Sends the supplied message to the designated chad ID, using the supplied token.
-pub fn send_message(msg: String, token: &str, chat_id: i64, is_silent: bool) {
-    match ureq::post(&format!(
-        "https://api.telegram.org/bot{token}/sendMessage",
-        token = &token
-    ))
-    .send_json(ureq::json!({
-        "text": msg,
-        "chat_id": chat_id,
-        "parse_mode": "html",
-        "disable_notification": !is_silent,
-    })) {
-        Ok(_) => (),
-        Err(err) => {
-            println!("{}Can't notify Telegram{}", color::Fg(color::Red), style::Reset);
-            println!("{}Error message: {:?}{}", color::Fg(color::Red), err, style::Reset);
-            println!("{}Notify message: {}{}", color::Fg(color::Red), msg, style::Reset);
+fn deliver_messages(receiver: Receiver<Outgoing>, notifier: Arc<dyn Notifier>, config: DeliveryConfig, pending: Arc<AtomicUsize>) {
+    let mut last_sent: Option<Instant> = None;
+    while let Ok(first) = receiver.recv() {
+        thread::sleep(Duration::from_millis(config.batch_delay_ms));
+        let mut messages = vec![first];
+        messages.extend(receiver.try_iter());
+        let count = messages.len();
+        for batch in make_batches(messages, notifier.message_limit()) {
+            if let Some(last_sent) = last_sent {
+                let min_interval = Duration::from_millis(config.min_interval_ms);
+                let elapsed = last_sent.elapsed();
+                if elapsed < min_interval {
+                    thread::sleep(min_interval - elapsed);
+                }
+            }
+            if !send_with_retries(notifier.as_ref(), &batch, &config) {
+                monitoring::NOTIFICATIONS_DROPPED.with_label_values(&[&notifier.name()]).inc();
+            }
+            last_sent = Some(Instant::now());
         }
+        pending.fetch_sub(count, Ordering::SeqCst);
     }
 }
 
+
+/// Send message, retrying on temporary failures; returns `false` if message isn't delivered.
+///
+/// NOTE: failures are logged with `debug` level, which isn't notified (otherwise failing sink
+/// would get messages about its own failures).
+///
+fn send_with_retries(notifier: &dyn Notifier, msg: &Outgoing, config: &DeliveryConfig) -> bool {
+    let mut attempt = 0;
+    loop {
+        match notifier.send(msg.level, &msg.text) {
+            Ok(()) => return true,
+            Err(err) => {
+                attempt += 1;
+                debug!("Can't notify {}: {}", notifier.name(), err.msg);
+                if err.is_permanent {
+                    debug!("Message to {} is dropped (permanent error): {}", notifier.name(), msg.text);
+                    return false;
+                }
+                if attempt > config.max_retries {
+                    debug!("Message to {} is dropped after {} attempts: {}", notifier.name(), attempt, msg.text);
+                    return false;
+                }
+                // If service doesn't say how long to wait, use exponential backoff
+                let delay = err.retry_after.unwrap_or_else(|| Duration::from_secs(1 << std::cmp::min(attempt, 6)));
+                thread::sleep(delay);
+            }
+        }
+    }
+}
@@ -0,0 +1,575 @@
+/// Destinations of notifications: Telegram, JSON webhook, Slack, Discord, email
+///
+/// Every sink formats notification for itself and sends it (blocking, it is called
+/// from background delivery thread, see `notifications.rs`).
+///
+
+use lettre::{ Message, SmtpTransport, Transport };
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use log::Level;
+use std::time::Duration;
+
+use crate::config::{ EmailSinkConfig, NotificationsConfig, NotifyLevel, SinkConfig, SinkKind };
+
+
+/// Timeout of request to notification service.
+///
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// Notification made from log record.
+///
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub level: Level,
+    /// Target of log record
+    pub target: String,
+    pub text: String,
+    /// Source file and line
+    pub place: String,
+    pub time: String,
+    /// Condition of alert is cleared
+    pub resolved: bool,
+}
+
+
+/// Failed sending.
+///
+#[derive(Debug, Clone)]
+pub struct SendError {
+    pub msg: String,
+    /// Time to wait before retry (if service reports it)
+    pub retry_after: Option<Duration>,
+    /// Retry won't help (bad request, wrong token, etc.)
+    pub is_permanent: bool,
+}
+
+
+impl SendError {
+    fn new(msg: String) -> Self {
+        SendError { msg, retry_after: None, is_permanent: false }
+    }
+
+    fn permanent(msg: String) -> Self {
+        SendError { msg, retry_after: None, is_permanent: true }
+    }
+}
+
+
+impl From<ureq::Error> for SendError {
+    fn from(err: ureq::Error) -> Self {
+        match err {
+            ureq::Error::Status(code, response) => {
+                let header_retry_after = response.header("retry-after").and_then(|v| v.trim().parse::<f64>().ok());
+                let body: serde_json::Value = response.into_json().unwrap_or_default();
+                // Telegram: `parameters.retry_after`, Discord: `retry_after` (seconds)
+                let retry_after = header_retry_after
+                    .or_else(|| body["parameters"]["retry_after"].as_f64())
+                    .or_else(|| body["retry_after"].as_f64());
+                SendError {
+                    msg: format!("HTTP status {}: {}", code, body),
+                    retry_after: retry_after.map(|s| Duration::from_secs_f64(s.max(0.0))),
+                    // NOTE: rate limit (429) and server errors are temporary
+                    is_permanent: (400..500).contains(&code) && code != 429,
+                }
+            },
+            err => SendError::new(err.to_string()),
+        }
+    }
+}
+
+
+/// Destination of notifications.
+///
+pub trait Notifier: Send + Sync {
+    /// Name of sink (for logs and metrics)
+    fn name(&self) -> String;
+
+    /// Maximal level of notifications to send (`Info` -- all).
+    fn level(&self) -> Level;
+
+    /// Maximal length of one message (queued messages are joined up to this length).
+    fn message_limit(&self) -> usize;
+
+    fn format(&self, notification: &Notification) -> String;
+
+    fn send(&self, level: Level, text: &str) -> Result<(), SendError>;
+}
+
+
+fn level_prefix(n: &Notification) -> &'static str {
+    match n.level {
+        _ if n.resolved => "RESOLVED: ",
+        Level::Error => "ERROR: ",
+        Level::Warn => "Warning: ",
+        _ => "",
+    }
+}
+
+
+fn to_log_level(level: NotifyLevel) -> Level {
+    match level {
+        NotifyLevel::Info => Level::Info,
+        NotifyLevel::Warn => Level::Warn,
+        NotifyLevel::Error => Level::Error,
+    }
+}
+
+
+fn post_json(url: &str, body: serde_json::Value) -> Result<(), SendError> {
+    ureq::post(url).timeout(SEND_TIMEOUT).send_json(body)?;
+    Ok(())
+}
+
+
+//
+// Telegram -------------------------------------------------------------------------
+//
+
+pub struct TelegramNotifier {
+    pub api_url: String,
+    pub token: String,
+    pub chat_id: i64,
+    pub level: Level,
+    pub is_silent: bool,
+    /// Add source file and line to message
+    pub with_place: bool,
+}
+
+
+/// Sends a Telegram message
+///
+/// Sends the supplied message to the designated chad ID, using the supplied token.
+pub fn send_telegram_message(api_url: &str, token: &str, chat_id: i64, msg: &str, is_silent: bool) -> Result<(), SendError> {
+    call_telegram(api_url, token, "sendMessage", serde_json::json!({
+        "text": msg,
+        "chat_id": chat_id,
+        "parse_mode": "html",
+        "disable_notification": !is_silent,
+    }))
+}
+
+
+/// Call method of Telegram Bot API.
+///
+pub fn call_telegram(api_url: &str, token: &str, method: &str, body: serde_json::Value) -> Result<(), SendError> {
+    post_json(&format!("{}/bot{}/{}", api_url.trim_end_matches('/'), token, method), body)
+}
+
+
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> String { format!("telegram:{}", self.chat_id) }
+
+    fn level(&self) -> Level { self.level }
+
+    fn message_limit(&self) -> usize { 4096 }
+
+    fn format(&self, n: &Notification) -> String {
+        let msg = html_escape::encode_text(&n.text).to_string();
+        if self.with_place {
+            let logmsg = match n.level {
+                _ if n.resolved => format!("<b>RESOLVED:</b> {}", msg),
+                Level::Error => format!("<b>ERROR: {}</b>", msg),
+                _ => msg,
+            };
+            format!("{}\n\n<code>{}</code>\n<i>{}</i>", logmsg, n.place, n.time)
+        }
+        else {
+            let logmsg = match n.level {
+                Level::Warn | Level::Error => format!("<b>{}{}</b>", level_prefix(n), msg),
+                _ => msg,
+            };
+            format!("{}\n\n<i>{}</i>", logmsg, n.time)
+        }
+    }
+
+    fn send(&self, _level: Level, text: &str) -> Result<(), SendError> {
+        send_telegram_message(&self.api_url, &self.token, self.chat_id, text, self.is_silent)
+    }
+}
+
+
+//
+// Generic JSON webhook -------------------------------------------------------------
+//
+
+pub struct WebhookNotifier {
+    pub url: String,
+    pub level: Level,
+}
+
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> String { "webhook".to_owned() }
+
+    fn level(&self) -> Level { self.level }
+
+    fn message_limit(&self) -> usize { 65536 }
+
+    fn format(&self, n: &Notification) -> String {
+        format!("{}{}\n{} ({})", level_prefix(n), n.text, n.time, n.place)
+    }
+
+    fn send(&self, level: Level, text: &str) -> Result<(), SendError> {
+        post_json(&self.url, serde_json::json!({
+            "source": "hedgedstaking",
+            "level": level.as_str().to_lowercase(),
+            "text": text,
+        }))
+    }
+}
+
+
+//
+// Slack incoming webhook -----------------------------------------------------------
+//
+
+pub struct SlackNotifier {
+    pub url: String,
+    pub level: Level,
+}
+
+
+impl Notifier for SlackNotifier {
+    fn name(&self) -> String { "slack".to_owned() }
+
+    fn level(&self) -> Level { self.level }
+
+    fn message_limit(&self) -> usize { 40000 }
+
+    fn format(&self, n: &Notification) -> String {
+        // https://api.slack.com/reference/surfaces/formatting#escaping
+        let msg = n.text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        match n.level {
+            Level::Warn | Level::Error => format!("*{}{}*\n`{}`\n_{}_", level_prefix(n), msg, n.place, n.time),
+            _ => format!("{}\n_{}_", msg, n.time),
+        }
+    }
+
+    fn send(&self, _level: Level, text: &str) -> Result<(), SendError> {
+        post_json(&self.url, serde_json::json!({ "text": text }))
+    }
+}
+
+
+//
+// Discord webhook ------------------------------------------------------------------
+//
+
+pub struct DiscordNotifier {
+    pub url: String,
+    pub level: Level,
+}
+
+
+impl Notifier for DiscordNotifier {
+    fn name(&self) -> String { "discord".to_owned() }
+
+    fn level(&self) -> Level { self.level }
+
+    fn message_limit(&self) -> usize { 2000 }
+
+    fn format(&self, n: &Notification) -> String {
+        let msg: String = n.text.chars().take(1800).collect();
+        match n.level {
+            Level::Warn | Level::Error => format!("**{}{}**\n`{}`\n*{}*", level_prefix(n), msg, n.place, n.time),
+            _ => format!("{}\n*{}*", msg, n.time),
+        }
+    }
+
+    fn send(&self, _level: Level, text: &str) -> Result<(), SendError> {
+        post_json(&self.url, serde_json::json!({ "content": text }))
+    }
+}
+
+
+//
+// Email (SMTP) ---------------------------------------------------------------------
+//
+
+pub struct EmailNotifier {
+    pub level: Level,
+    pub from: Mailbox,
+    pub to: Vec<Mailbox>,
+    pub subject: String,
+    pub transport: SmtpTransport,
+}
+
+
+impl EmailNotifier {
+    fn new(level: Level, cfg: &EmailSinkConfig) -> Result<Self, String> {
+        let builder =
+            if cfg.tls { SmtpTransport::relay(&cfg.smtp_host).map_err(|e| e.to_string())? }
+            else { SmtpTransport::builder_dangerous(&cfg.smtp_host) };
+        let builder = builder.port(cfg.smtp_port).timeout(Some(SEND_TIMEOUT));
+        let builder = match (&cfg.user, &cfg.password) {
+            (Some(user), Some(password)) => builder.credentials(Credentials::new(user.to_owned(), password.expose().to_owned())),
+            _ => builder,
+        };
+        let parse_address = |a: &String| a.parse::<Mailbox>().map_err(|e| format!("Bad address \"{}\": {}", a, e));
+        Ok(EmailNotifier {
+            level,
+            from: parse_address(&cfg.from)?,
+            to: cfg.to.iter().map(parse_address).collect::<Result<_, String>>()?,
+            subject: cfg.subject.to_owned(),
+            transport: builder.build(),
+        })
+    }
+}
+
+
+impl Notifier for EmailNotifier {
+    fn name(&self) -> String { "email".to_owned() }
+
+    fn level(&self) -> Level { self.level }
+
+    fn message_limit(&self) -> usize { 65536 }
+
+    fn format(&self, n: &Notification) -> String {
+        format!("{}{}\n\n{}\n{}", level_prefix(n), n.text, n.place, n.time)
+    }
+
+    fn send(&self, level: Level, text: &str) -> Result<(), SendError> {
+        let subject_prefix = match level {
+            Level::Error => "ERROR: ",
+            Level::Warn => "Warning: ",
+            _ => "",
+        };
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(format!("{}{}", subject_prefix, self.subject));
+        for to in self.to.iter() {
+            builder = builder.to(to.clone());
+        }
+        let email = builder.body(text.to_owned()).map_err(|e| SendError::permanent(e.to_string()))?;
+        self.transport.send(&email).map_err(|e| if e.is_permanent() { SendError::permanent(e.to_string()) } else { SendError::new(e.to_string()) })?;
+        Ok(())
+    }
+}
+
+
+/// Sink with log targets routed to it.
+///
+pub struct SinkRoute {
+    pub notifier: Box<dyn Notifier>,
+    /// Prefixes of log targets (all targets if empty)
+    pub targets: Vec<String>,
+}
+
+
+fn make_notifier(cfg: &SinkConfig) -> Result<Box<dyn Notifier>, String> {
+    let level = to_log_level(cfg.level);
+    Ok(match &cfg.kind {
+        SinkKind::Webhook { url } => Box::new(WebhookNotifier { url: url.to_owned(), level }),
+        SinkKind::Slack { url } => Box::new(SlackNotifier { url: url.to_owned(), level }),
+        SinkKind::Discord { url } => Box::new(DiscordNotifier { url: url.to_owned(), level }),
+        SinkKind::Email(email) => Box::new(EmailNotifier::new(level, email)?),
+    })
+}
+
+
+/// All enabled sinks from config (Telegram chats of logs and alerts, and `[[notifications.sinks]]`).
+///
+pub fn make_notifiers(config: &NotificationsConfig) -> Result<Vec<SinkRoute>, String> {
+    let mut notifiers: Vec<SinkRoute> = Vec::new();
+    if config.telegram_enabled {
+        notifiers.push(SinkRoute { targets: Vec::new(), notifier: Box::new(TelegramNotifier {
+            api_url: config.telegram_api_url.to_owned(),
+            token: config.logs_telegram_token.expose().to_owned(),
+            chat_id: config.logs_telegram_chat_id,
+            level: Level::Info,
+            is_silent: true,
+            with_place: false,
+        })});
+        notifiers.push(SinkRoute { targets: Vec::new(), notifier: Box::new(TelegramNotifier {
+            api_url: config.telegram_api_url.to_owned(),
+            token: config.alerts_telegram_token.expose().to_owned(),
+            chat_id: config.alerts_telegram_chat_id,
+            level: Level::Warn,
+            is_silent: false,
+            with_place: true,
+        })});
+    }
+    for sink in config.sinks.iter().filter(|s| s.enabled) {
+        let notifier = make_notifier(sink).map_err(|err| format!("Bad notification sink \"{}\": {}", sink.kind, err))?;
+        notifiers.push(SinkRoute { notifier, targets: sink.targets.clone() });
+    }
+    Ok(notifiers)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ BufRead, BufReader, Read, Write };
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    fn http_response(status: &str, headers: &str, body: &str) -> String {
+        format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+                status, body.len(), headers, body)
+    }
+
+    /// Local HTTP server which answers one request by `response`, returns its URL and
+    /// receiver of the request (head and body).
+    fn http_stub(response: String) -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let length = head.lines()
+                .filter_map(|l| l.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+            tx.send((head, String::from_utf8(body).unwrap())).unwrap();
+        });
+        (url, rx)
+    }
+
+    /// Local SMTP server which accepts everything, returns its port and receiver of messages.
+    fn smtp_stub() -> (u16, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let command = line.trim_end().to_uppercase();
+                if command == "DATA" {
+                    stream.write_all(b"354 go ahead\r\n").unwrap();
+                    let mut data = String::new();
+                    loop {
+                        let mut data_line = String::new();
+                        if reader.read_line(&mut data_line).unwrap() == 0 || data_line == ".\r\n" {
+                            break;
+                        }
+                        data.push_str(&data_line);
+                    }
+                    stream.write_all(b"250 queued\r\n").unwrap();
+                    let _ = tx.send(data);
+                }
+                else if command == "QUIT" {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                }
+                else {
+                    stream.write_all(b"250 ok\r\n").unwrap();
+                }
+                line.clear();
+            }
+        });
+        (port, rx)
+    }
+
+    fn telegram(api_url: String) -> TelegramNotifier {
+        TelegramNotifier { api_url, token: "TOKEN".to_owned(), chat_id: 42, level: Level::Info, is_silent: false, with_place: false }
+    }
+
+    #[test]
+    fn telegram_message() {
+        let (url, requests) = http_stub(http_response("200 OK", "", r#"{"ok":true}"#));
+        telegram(url).send(Level::Warn, "<b>hello</b>").unwrap();
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /botTOKEN/sendMessage "), "{}", head);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["text"], "<b>hello</b>");
+        assert_eq!(body["chat_id"], 42);
+        assert_eq!(body["parse_mode"], "html");
+    }
+
+    #[test]
+    fn telegram_retry_after() {
+        let (url, _requests) = http_stub(http_response("429 Too Many Requests", "",
+            r#"{"ok":false,"error_code":429,"parameters":{"retry_after":7}}"#));
+        let err = telegram(url).send(Level::Info, "hello").unwrap_err();
+        assert_eq!(err.retry_after, Some(Duration::from_secs(7)));
+        assert!(!err.is_permanent);
+    }
+
+    #[test]
+    fn webhook_message_and_retry_after_header() {
+        let (url, requests) = http_stub(http_response("503 Service Unavailable", "Retry-After: 3\r\n", "{}"));
+        let err = WebhookNotifier { url: format!("{}/hook", url), level: Level::Info }.send(Level::Warn, "hello").unwrap_err();
+        assert_eq!(err.retry_after, Some(Duration::from_secs(3)));
+        assert!(!err.is_permanent);
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /hook "), "{}", head);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body, serde_json::json!({ "source": "hedgedstaking", "level": "warn", "text": "hello" }));
+    }
+
+    #[test]
+    fn slack_message_and_permanent_error() {
+        let (url, requests) = http_stub(http_response("400 Bad Request", "", r#"{"error":"invalid_payload"}"#));
+        let err = SlackNotifier { url, level: Level::Info }.send(Level::Info, "hello").unwrap_err();
+        assert!(err.is_permanent);
+        assert_eq!(err.retry_after, None);
+        let (_, body) = requests.recv().unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), serde_json::json!({ "text": "hello" }));
+    }
+
+    #[test]
+    fn discord_message_and_retry_after() {
+        let (url, requests) = http_stub(http_response("429 Too Many Requests", "", r#"{"retry_after":1.5}"#));
+        let err = DiscordNotifier { url, level: Level::Info }.send(Level::Info, "hello").unwrap_err();
+        assert_eq!(err.retry_after, Some(Duration::from_millis(1500)));
+        assert!(!err.is_permanent);
+        let (_, body) = requests.recv().unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), serde_json::json!({ "content": "hello" }));
+    }
+
+    fn email_config(port: u16, from: &str) -> EmailSinkConfig {
+        EmailSinkConfig {
+            smtp_host: "127.0.0.1".to_owned(),
+            smtp_port: port,
+            tls: false,
+            user: None,
+            password: None,
+            from: from.to_owned(),
+            to: vec!["ops@example.com".to_owned()],
+            subject: "Test".to_owned(),
+        }
+    }
+
+    #[test]
+    fn email_message() {
+        let (port, messages) = smtp_stub();
+        let notifier = EmailNotifier::new(Level::Info, &email_config(port, "bot@example.com")).unwrap();
+        notifier.send(Level::Error, "hello").unwrap();
+        let message = messages.recv().unwrap();
+        assert!(message.contains("Subject: ERROR: Test"), "{}", message);
+        assert!(message.contains("To: ops@example.com"), "{}", message);
+        assert!(message.contains("hello"), "{}", message);
+    }
+
+    #[test]
+    fn misconfigured_sink_is_error() {
+        let sink: SinkConfig = toml::from_str(r#"
+            type = "email"
+            smtp_host = "localhost"
+            from = "not an address"
+            to = ["ops@example.com"]
+        "#).unwrap();
+        assert!(make_notifier(&sink).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+/// Secrets: API keys and secrets, private keys, tokens
+///
+/// In config file every secret can be given:
+///
+/// * inline: `secret = "..."`;
+/// * by environment variable: `secret = { env = "KRAKEN_SECRET" }`;
+/// * by file (e.g. Docker or Kubernetes secret): `secret = { file = "/run/secrets/kraken_secret" }`;
+/// * by name in encrypted keystore: `secret = { keystore = "kraken_secret" }`.
+///
+/// Keystore (`./keystore.json` or path from `HEDGEDSTAKING_KEYSTORE`) is encrypted by AES-256-GCM
+/// with key derived from passphrase by scrypt. Passphrase is taken from `HEDGEDSTAKING_KEYSTORE_PASSPHRASE`
+/// or asked in terminal. Secrets are added by `keystore set <name>` command.
+///
+/// Secrets are wrapped in `Secret`, which `Debug` never shows the value.
+///
+
+use aes_gcm::{ Aes256Gcm, Nonce };
+use aes_gcm::aead::{ Aead, KeyInit };
+use lazy_static::*;
+use serde::{ Deserialize, Deserializer, Serialize };
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use termion::input::TermRead;
+
+
+pub static KEYSTORE_PATH_VAR: &str = "HEDGEDSTAKING_KEYSTORE";
+pub static KEYSTORE_PASSPHRASE_VAR: &str = "HEDGEDSTAKING_KEYSTORE_PASSPHRASE";
+static DEFAULT_KEYSTORE_PATH: &str = "./keystore.json";
+
+/// Encrypted to check passphrase
+static CHECK_PLAINTEXT: &str = "hedged_staking";
+
+
+/// Secret value, `Debug` doesn't show it.
+///
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(value)
+    }
+
+    /// The secret itself (must never be logged).
+    ///
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+
+/// Where secret is taken from (see module description).
+///
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SecretSource {
+    Inline(String),
+    Env { env: String },
+    File { file: String },
+    Keystore { keystore: String },
+}
+
+
+impl SecretSource {
+    fn resolve(self) -> Result<String, String> {
+        match self {
+            SecretSource::Inline(value) => Ok(value),
+            SecretSource::Env { env } => std::env::var(&env)
+                .map_err(|err| format!("Can't read secret from environment variable {}: {}", env, err)),
+            SecretSource::File { file } => std::fs::read_to_string(&file)
+                .map(|s| s.trim_end_matches(['\r', '\n']).to_owned())
+                .map_err(|err| format!("Can't read secret from file {}: {}", file, err)),
+            SecretSource::Keystore { keystore } => keystore_secret(&keystore),
+        }
+    }
+}
+
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        SecretSource::deserialize(deserializer)?
+            .resolve()
+            .map(Secret)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+
+//
+// Keystore -------------------------------------------------------------------------
+//
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KdfParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    /// Base64
+    salt: String,
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EncryptedSecret {
+    /// Base64
+    nonce: String,
+    /// Base64
+    ciphertext: String,
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeystoreFile {
+    kdf: KdfParams,
+    /// `CHECK_PLAINTEXT` (to detect wrong passphrase)
+    check: EncryptedSecret,
+    secrets: BTreeMap<String, EncryptedSecret>,
+}
+
+
+/// Opened keystore.
+///
+struct Keystore {
+    path: String,
+    file: KeystoreFile,
+    cipher: Aes256Gcm,
+}
+
+
+lazy_static! {
+    /// Keystore is opened (and passphrase is asked) once
+    static ref KEYSTORE: Mutex<Option<Keystore>> = Mutex::new(None);
+}
+
+
+fn keystore_path() -> String {
+    std::env::var(KEYSTORE_PATH_VAR).unwrap_or_else(|_| DEFAULT_KEYSTORE_PATH.to_owned())
+}
+
+
+/// Read line without echo.
+///
+fn read_hidden(prompt: &str) -> Result<String, String> {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let stdin = std::io::stdin();
+    let mut stdin = stdin.lock();
+    write!(stdout, "{}", prompt).and_then(|_| stdout.flush()).map_err(|err| err.to_string())?;
+    let value = stdin.read_passwd(&mut stdout).map_err(|err| err.to_string())?;
+    writeln!(stdout).map_err(|err| err.to_string())?;
+    value.ok_or_else(|| "Input is cancelled".to_owned())
+}
+
+
+fn read_passphrase(path: &str) -> Result<String, String> {
+    match std::env::var(KEYSTORE_PASSPHRASE_VAR) {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => read_hidden(&format!("Passphrase of keystore {}: ", path)),
+    }
+}
+
+
+fn make_cipher(passphrase: &str, kdf: &KdfParams) -> Result<Aes256Gcm, String> {
+    let salt = base64::decode(&kdf.salt).map_err(|err| format!("Bad salt: {}", err))?;
+    let params = scrypt::Params::new(kdf.log_n, kdf.r, kdf.p, 32).map_err(|err| format!("Bad KDF parameters: {}", err))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), &salt, &params, &mut key).map_err(|err| err.to_string())?;
+    Aes256Gcm::new_from_slice(&key).map_err(|err| err.to_string())
+}
+
+
+fn encrypt(cipher: &Aes256Gcm, value: &str) -> Result<EncryptedSecret, String> {
+    let nonce: [u8; 12] = rand::random();
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), value.as_bytes())
+        .map_err(|_| "Can't encrypt secret".to_owned())?;
+    Ok(EncryptedSecret { nonce: base64::encode(nonce), ciphertext: base64::encode(ciphertext) })
+}
+
+
+fn decrypt(cipher: &Aes256Gcm, secret: &EncryptedSecret) -> Result<String, String> {
+    let nonce = base64::decode(&secret.nonce).map_err(|err| format!("Bad nonce: {}", err))?;
+    let ciphertext = base64::decode(&secret.ciphertext).map_err(|err| format!("Bad ciphertext: {}", err))?;
+    if nonce.len() != 12 {
+        return Err("Bad nonce length".to_owned());
+    }
+    let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| "Wrong passphrase or keystore is corrupted".to_owned())?;
+    String::from_utf8(plaintext).map_err(|err| err.to_string())
+}
+
+
+fn read_keystore_file(path: &str) -> Result<KeystoreFile, String> {
+    let content = std::fs::read_to_string(path).map_err(|err| format!("Can't read keystore {}: {}", path, err))?;
+    serde_json::from_str(&content).map_err(|err| format!("Bad keystore {}: {}", path, err))
+}
+
+
+/// Open existing keystore or (if `create`) make new one.
+///
+fn open_keystore(create: bool) -> Result<Keystore, String> {
+    let path = keystore_path();
+    if Path::new(&path).exists() {
+        let file = read_keystore_file(&path)?;
+        let cipher = make_cipher(&read_passphrase(&path)?, &file.kdf)?;
+        decrypt(&cipher, &file.check)?;
+        Ok(Keystore { path, file, cipher })
+    }
+    else if create {
+        let passphrase = match std::env::var(KEYSTORE_PASSPHRASE_VAR) {
+            Ok(passphrase) => passphrase,
+            Err(_) => {
+                let passphrase = read_hidden(&format!("New passphrase of keystore {}: ", path))?;
+                if read_hidden("Repeat passphrase: ")? != passphrase {
+                    return Err("Passphrases don't match".to_owned());
+                }
+                passphrase
+            },
+        };
+        let salt: [u8; 16] = rand::random();
+        let kdf = KdfParams { log_n: 15, r: 8, p: 1, salt: base64::encode(salt) };
+        let cipher = make_cipher(&passphrase, &kdf)?;
+        let check = encrypt(&cipher, CHECK_PLAINTEXT)?;
+        Ok(Keystore { path, file: KeystoreFile { kdf, check, secrets: BTreeMap::new() }, cipher })
+    }
+    else {
+        Err(format!("Keystore {} doesn't exist (add secrets by `keystore set <name>`)", path))
+    }
+}
+
+
+fn keystore_secret(name: &str) -> Result<String, String> {
+    let mut keystore = KEYSTORE.lock().unwrap();
+    if keystore.is_none() {
+        *keystore = Some(open_keystore(false)?);
+    }
+    let keystore = keystore.as_ref().unwrap();
+    match keystore.file.secrets.get(name) {
+        Some(secret) => decrypt(&keystore.cipher, secret),
+        None => Err(format!("No secret \"{}\" in keystore {}", name, keystore.path)),
+    }
+}
+
+
+/// Add or replace secret `name` in keystore (value is read from terminal).
+///
+pub fn keystore_set(name: &str) -> Result<(), String> {
+    let value = read_hidden(&format!("Value of \"{}\": ", name))?;
+    keystore_store(&[(name, value.trim())])
+}
+
+
+/// Add or replace secrets (name, value) in keystore.
+///
+pub fn keystore_store(secrets: &[(&str, &str)]) -> Result<(), String> {
+    let mut keystore = open_keystore(true)?;
+    for (name, value) in secrets {
+        let encrypted = encrypt(&keystore.cipher, value)?;
+        keystore.file.secrets.insert(name.to_string(), encrypted);
+    }
+    let content = serde_json::to_string_pretty(&keystore.file).map_err(|err| err.to_string())?;
+    write_private(&keystore.path, content.as_bytes()).map_err(|err| format!("Can't write keystore {}: {}", keystore.path, err))
+}
+
+
+/// Replace `path` by file readable only by owner: `content` is written to temporary file
+/// (created with this mode), which is renamed then (so keystore is never partially written).
+///
+fn write_private(path: &str, content: &[u8]) -> std::io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    match std::fs::remove_file(&tmp_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => (),
+    }
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+
+/// Names of secrets in keystore (passphrase isn't required).
+///
+pub fn keystore_list() -> Result<Vec<String>, String> {
+    Ok(read_keystore_file(&keystore_path())?.secrets.into_keys().collect())
+}
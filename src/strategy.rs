@@ -10,14 +10,24 @@ use tokio::time::{sleep,timeout,Duration};
 
 use std::cmp::{min, max};
 
+use crate::actions;
+use crate::actions::register_actions;
+use crate::api;
+use crate::approval;
+use crate::audit;
 use crate::consts::*;
+use crate::control;
+use crate::digest;
+use crate::fees::Route;
 use crate::helpers::*;
+use crate::history;
 use crate::monitoring;
 use crate::types::*;
+use crate::unwind;
 use crate::utils::*;
 
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::EnumMessage)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, strum_macros::EnumMessage, strum_macros::IntoStaticStr)]
 pub enum StrategyState {
 
     #[strum(message="Monitoring")]
@@ -80,14 +90,17 @@ fn pretty_state_msg(s: &StrategyState) -> String {
 type NewStateAndDelay = (Option<StrategyState>, Option<Duration>);
 
 
-async fn process_state(ctx: &mut Context, start_state: Option<StrategyState>, is_monitoring_only: bool) -> StrategyResult<NewStateAndDelay> {
+async fn process_state(ctx: &mut Context, start_state: Option<StrategyState>) -> StrategyResult<NewStateAndDelay> {
     let state_pre = match start_state {
-        None => detect_current_state(ctx, is_monitoring_only).await?,
+        None => detect_current_state(ctx, false).await?,
         Some(st) => st,
         };
-    let state = if is_monitoring_only { StrategyState::Monitoring } else { state_pre };
-    let monitoring_msg = if is_monitoring_only { " (MONITORING) " } else { "" };
+    let is_paused = control::is_paused();
+    let state = if is_paused { StrategyState::Monitoring } else { state_pre };
+    let monitoring_msg = if is_paused { " (PAUSED) " } else { "" };
     info!("=== Current state: {} ({:?}) {}===", pretty_state_msg(&state), state, monitoring_msg);
+    api::set_state(state);
+    monitoring::observe_state(state.into());
     log_balances(ctx).await?;
     if ctx.consts.keypress_to_continue { press_enter_to_continue() } // debug: waiting for key press
     match state {
@@ -95,25 +108,114 @@ async fn process_state(ctx: &mut Context, start_state: Option<StrategyState>, is
             debug!("Waiting {} seconds...", ctx.consts.monitoring_timeout);
             return Ok((None, if ctx.consts.keypress_to_continue { None } else { Some(Duration::from_secs(ctx.consts.monitoring_timeout)) }));
         },
-        StrategyState::TransferE1ToWallet(v) => do_e1_to_wallet(ctx, Some(v)).await?,
-        StrategyState::ReduceSecondaryBoth(v) => do_reduce_secondary_soft(ctx, Some(v)).await?,
-        StrategyState::EnlargeSecondaryBoth(v) => do_enlarge_secondary_soft(ctx, Some(v)).await?,
-        StrategyState::TransferWalletToEE(v) => do_wallet_to_ee(ctx, Some(v)).await?,
-        StrategyState::TransferEEToE2(v) => do_ee_to_e2(ctx, Some(v)).await?,
-        StrategyState::Stake(v) => do_stake(ctx, Some(v)).await?,
-        StrategyState::ReduceShort(v) => do_change_short(ctx, Some(-v)).await?,
-        StrategyState::EnlargeShort(v) => do_change_short(ctx, Some(v)).await?, 
-        //
-        StrategyState::Unstake(v) => do_unstake(ctx, Some(v)).await?,
-        StrategyState::TransferE2ToEE(v) => do_e2_to_ee(ctx, Some(v)).await?,
-        StrategyState::TransferEEToWallet(v) => do_ee_to_wallet(ctx, Some(v)).await?,
-        StrategyState::TransferWalletToE1(v) => do_wallet_to_e1(ctx, Some(v)).await?,
+        _ => (),
+    }
+    // NOTE: state is detected again on every cycle, so action approved meanwhile is run with the current amount
+    if approve(ctx, state_action_name(&state), state_value(&state), false).await? != approval::Approval::Approved {
+        return Ok((None, Some(Duration::from_secs(ctx.consts.monitoring_timeout))));
     }
+    // NOTE: failed action is also observed (when timer is dropped)
+    let _action_timer = monitoring::ACTION_DURATION.with_label_values(&[state_action_name(&state)]).start_timer();
+    digest::observe_state(state.into());
+    audit::set_trigger(Some(state), state_action_name(&state));
+    let result = match state {
+        StrategyState::Monitoring => unreachable!(),
+        StrategyState::TransferE1ToWallet(v) => do_e1_to_wallet(ctx, Some(v)).await,
+        StrategyState::ReduceSecondaryBoth(v) => do_reduce_secondary_soft(ctx, Some(v)).await,
+        StrategyState::EnlargeSecondaryBoth(v) => do_enlarge_secondary_soft(ctx, Some(v)).await,
+        StrategyState::TransferWalletToEE(v) => do_wallet_to_ee(ctx, Some(v)).await,
+        StrategyState::TransferEEToE2(v) => do_ee_to_e2(ctx, Some(v)).await,
+        StrategyState::Stake(v) => do_stake(ctx, Some(v)).await,
+        StrategyState::ReduceShort(v) => do_change_short(ctx, Some(-v)).await,
+        StrategyState::EnlargeShort(v) => do_change_short(ctx, Some(v)).await, 
+        //
+        StrategyState::Unstake(v) => do_unstake(ctx, Some(v)).await,
+        StrategyState::TransferE2ToEE(v) => do_e2_to_ee(ctx, Some(v)).await,
+        StrategyState::TransferEEToWallet(v) => do_ee_to_wallet(ctx, Some(v)).await,
+        StrategyState::TransferWalletToE1(v) => do_wallet_to_e1(ctx, Some(v)).await,
+    };
+    api::observe_action(state_action_name(&state), state_value(&state), &result);
+    result?;
     Ok((None, None))
 }
 
 
-pub async fn prepare(ctx: &mut Context) -> ActionResult {
+/// Name of action which processes state `state`.
+///
+fn state_action_name(state: &StrategyState) -> &'static str {
+    match state {
+        StrategyState::Monitoring => "monitoring",
+        StrategyState::TransferE1ToWallet(_) => "do_e1_to_wallet",
+        StrategyState::ReduceSecondaryBoth(_) => "do_reduce_secondary_soft",
+        StrategyState::EnlargeSecondaryBoth(_) => "do_enlarge_secondary_soft",
+        StrategyState::TransferWalletToEE(_) => "do_wallet_to_ee",
+        StrategyState::TransferEEToE2(_) => "do_ee_to_e2",
+        StrategyState::Stake(_) => "do_stake",
+        StrategyState::ReduceShort(_) | StrategyState::EnlargeShort(_) => "do_change_short",
+        StrategyState::Unstake(_) => "do_unstake",
+        StrategyState::TransferE2ToEE(_) => "do_e2_to_ee",
+        StrategyState::TransferEEToWallet(_) => "do_ee_to_wallet",
+        StrategyState::TransferWalletToE1(_) => "do_wallet_to_e1",
+    }
+}
+
+
+/// Amount of state (in coins of its action).
+///
+fn state_value(state: &StrategyState) -> Option<Value> {
+    match *state {
+        StrategyState::Monitoring => None,
+        StrategyState::TransferE1ToWallet(v)
+            | StrategyState::TransferWalletToEE(v)
+            | StrategyState::EnlargeSecondaryBoth(v)
+            | StrategyState::TransferEEToWallet(v)
+            | StrategyState::TransferWalletToE1(v)
+            | StrategyState::ReduceSecondaryBoth(v) => Some(*v),
+        StrategyState::TransferEEToE2(v)
+            | StrategyState::Stake(v)
+            | StrategyState::EnlargeShort(v)
+            | StrategyState::Unstake(v)
+            | StrategyState::TransferE2ToEE(v) => Some(*v),
+        StrategyState::ReduceShort(v) => Some(-*v),
+    }
+}
+
+
+/// Value (in transferring coins) of funds moved by action `name` with amount `value`.
+///
+fn action_notional(name: &str, value: Option<Value>, price: Price) -> approval::Notional {
+    let param = match actions::find_action(name) {
+        Some(action) => action.param,
+        None => return approval::Notional::NoFunds,
+    };
+    match (param, value) {
+        (actions::Param::Nothing, _) => approval::Notional::NoFunds,
+        // Value of unwinding is percent
+        (actions::Param::Percent, _) | (_, None) => approval::Notional::Unknown,
+        (actions::Param::Primary, Some(v)) => approval::Notional::Amount(v.abs()),
+        (actions::Param::Secondary | actions::Param::SignedSecondary, Some(v)) => approval::Notional::Amount(v.abs() * price),
+    }
+}
+
+
+/// Check human approval of action (if it is required, see `approval.rs`) without waiting.
+///
+async fn approve(ctx: &mut Context, name: &str, value: Option<Value>, is_requested: bool) -> StrategyResult<approval::Approval> {
+    if !ctx.approval.enabled {
+        return Ok(approval::Approval::Approved);
+    }
+    let price = avg_price(&get_token_price(ctx, WhichExchange::Exchange).await?);
+    let result = approval::approve(&ctx.approval, name, value, action_notional(name, value, price), is_requested);
+    match result {
+        approval::Approval::Approved => (),
+        approval::Approval::Pending => info!("Action \"{}\" ({:?}) is waiting for approval, continue monitoring", name, value),
+        approval::Approval::Rejected => info!("Action \"{}\" ({:?}) is not approved, continue monitoring", name, value),
+    }
+    Ok(result)
+}
+
+
+pub async fn prepare<A: Access>(ctx: &mut Context<A>) -> ActionResult {
     monitoring::E1_E2_RATIO_LOW.set(percent_to_decimal(ctx.consts.low_ratio_percent).to_f64().unwrap());
     monitoring::E1_E2_RATIO_HIGH.set(percent_to_decimal(ctx.consts.high_ratio_percent).to_f64().unwrap());
     info!(target: "NOTIFICATION", "STARTED\n{}", get_long_version_string()); 
@@ -121,14 +223,32 @@ pub async fn prepare(ctx: &mut Context) -> ActionResult {
 }
 
 
-pub async fn strategy(ctx: &mut Context, is_monitoring_only: bool) -> ActionResult {
+pub async fn strategy(ctx: &mut Context) -> ActionResult {
     prepare(ctx).await?;
     let mut state = None;
+    // Requested action waiting for approval
+    let mut parked = None;
     loop {
-        match timeout(Duration::from_secs(ctx.consts.operations_timeout), process_state(ctx, state, is_monitoring_only)).await.map_or_else(
+        if control::is_stop_requested() {
+            info!("Strategy is stopped by request");
+            return Ok(());
+        }
+        if control::is_paused() {
+            // Pending state could be outdated after resume, so it will be detected again.
+            state = None;
+        }
+        else if let Some(action) = parked.take().or_else(api::pop_action) {
+            let name = action.name.clone();
+            parked = timeout(Duration::from_secs(ctx.consts.operations_timeout), run_requested_action(ctx, action)).await.map_or_else(
+                    |err| Err (StrategyError::Misc { msg: format!("Timeout during requested action '{}': {:?}", name, err) }),
+                    |ok| ok)?;
+            state = None;
+        }
+        match timeout(Duration::from_secs(ctx.consts.operations_timeout), process_state(ctx, state)).await.map_or_else(
                 |err| Err (StrategyError::Misc { msg: format!("Timeout during processing state '{:?}': {:?}", state, err) }),
                 |ok| ok) {
             Ok((new_state, delay)) => {
+                api::set_cycle_done();
                 match delay {
                     Some(delay) => sleep(delay).await,
                     None => (),
@@ -141,6 +261,57 @@ pub async fn strategy(ctx: &mut Context, is_monitoring_only: bool) -> ActionResu
 }
 
 
+/// Only monitoring: read-only context can't be used for orders, transfers and staking,
+/// so requested actions are dropped.
+///
+pub async fn monitoring(ctx: &mut Context<ReadOnly>) -> ActionResult {
+    prepare(ctx).await?;
+    loop {
+        if let Some(action) = api::pop_action() {
+            warn!("Action \"{}\" can't be run in monitoring mode", action.name);
+        }
+        let state = timeout(Duration::from_secs(ctx.consts.operations_timeout), detect_current_state(ctx, true)).await.map_or_else(
+                |err| Err (StrategyError::Misc { msg: format!("Timeout during monitoring: {:?}", err) }),
+                |ok| ok)?;
+        info!("=== Current state: {} ({:?}) (MONITORING) ===", pretty_state_msg(&StrategyState::Monitoring), state);
+        api::set_state(StrategyState::Monitoring);
+        monitoring::observe_state(StrategyState::Monitoring.into());
+        log_balances(ctx).await?;
+        api::set_cycle_done();
+        if ctx.consts.keypress_to_continue {
+            press_enter_to_continue(); // debug: waiting for key press
+        }
+        else {
+            debug!("Waiting {} seconds...", ctx.consts.monitoring_timeout);
+            sleep(Duration::from_secs(ctx.consts.monitoring_timeout)).await;
+        }
+    }
+}
+
+
+/// Run action requested by operator (via HTTP API, etc.), returns it back if it waits for approval.
+///
+/// Failure is returned like failure of state processing.
+///
+async fn run_requested_action(ctx: &mut Context, action: api::ActionRequest) -> StrategyResult<Option<api::ActionRequest>> {
+    match approve(ctx, &action.name, action.value, true).await? {
+        approval::Approval::Approved => (),
+        approval::Approval::Pending => return Ok(Some(action)),
+        approval::Approval::Rejected => return Ok(None),
+    }
+    info!(target: "NOTIFICATION", "Run requested action \"{}\" ({:?})", action.name, action.value);
+    let action_timer = monitoring::ACTION_DURATION.with_label_values(&[&action.name]).start_timer();
+    digest::observe_state(&action.name);
+    audit::set_trigger(None, &action.name);
+    let result = run_action_by_name(action.name.clone(), ctx, action.value).await;
+    action_timer.observe_duration();
+    api::observe_action(&action.name, action.value, &result);
+    result?;
+    info!(target: "NOTIFICATION", "Requested action \"{}\" is done", action.name);
+    Ok(None)
+}
+
+
 /// NOTE TODO this is for reduce short position on E1. (and *just trying* on EE/E2...)
 ///
 ///
@@ -354,6 +525,59 @@ pub async fn do_change_short(ctx: &mut Context, opt_amount: Option<SecondaryAsse
 }
 
 
+/// Unwind some percent (all by default) of positions
+///
+pub async fn do_unwind(ctx: &mut Context, opt_percent: Option<Value>) -> ActionResult {
+    let percent = opt_percent.unwrap_or(dec!(100));
+    info!("Unwind {}% of positions", percent);
+    unwind::unwind(ctx, percent).await
+}
+
+
+/// Run action requested from command line (`only`): show summary, ask confirmation
+/// (unless `is_confirmed`) and run it. In dry run action stops before its first side effect.
+///
+pub async fn run_single_action(ctx: &mut Context, name: &str, value: Option<Value>, is_confirmed: bool) -> ActionResult {
+    let action = match actions::find_action(name) {
+        Some(action) => action,
+        None => return err(format!("No such action \"{}\"", name)),
+    };
+    show_balances(update_balances(ctx, None).await?);
+    let price = avg_price(&get_token_price(ctx, WhichExchange::Exchange).await?);
+    println!("--------------------------------------------");
+    println!("Action: {} -- {}", action.name, action.description);
+    match value {
+        Some(v) => println!("Value:  {}", action.param.format(v)),
+        None if action.param != actions::Param::Nothing => println!("Value:  default (see description)"),
+        None => (),
+    }
+    match action_notional(name, value, price) {
+        approval::Notional::Amount(notional) => println!("Effect: moves {:.2} {} (by {} price {:.4})", notional, TRANSFERRING_COIN, EE_NAME, price),
+        approval::Notional::Unknown => println!("Effect: amount isn't given, action can move the whole balance"),
+        approval::Notional::NoFunds => println!("Effect: no funds are moved"),
+    }
+    if ctx.dry_run {
+        println!("Dry run: action stops before its first side effect");
+    }
+    else if !is_confirmed && !confirm(&format!("Run {}?", action.name)) {
+        info!("Action \"{}\" is cancelled (use `--yes` to run without confirmation)", action.name);
+        return Ok(());
+    }
+    let result = run_action_by_name(name.to_owned(), ctx, value).await;
+    match result {
+        Err(err @ StrategyError::DryRun { .. }) => {
+            println!("{}", err.to_string());
+            Ok(())
+        },
+        Ok(_) if ctx.dry_run => {
+            println!("Dry run: action is finished without side effects");
+            Ok(())
+        },
+        result => result,
+    }
+}
+
+
 // ---- Debug actions:
 //
 
@@ -391,6 +615,7 @@ pub async fn do_debug_show_state(ctx: &mut Context) -> ActionResult {
 
 
 fn notify_state(state: StrategyState, msg: String) -> StrategyResult<StrategyState> {
+    api::set_plan(state, &msg);
     let whole_msg = if state == StrategyState::Monitoring { msg } else { format!("{}\n\n{}", msg, state.get_message().unwrap_or("?")) };
     info!(target: "NOTIFICATION", "{}", whole_msg);
     Ok(state)
@@ -399,7 +624,7 @@ fn notify_state(state: StrategyState, msg: String) -> StrategyResult<StrategySta
 
 /// Detects current exchange state to run or continue state machine working.
 ///
-async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Result<StrategyState, StrategyError> {
+async fn detect_current_state<A: Access>(ctx: &mut Context<A>, is_monitoring_only: bool) -> Result<StrategyState, StrategyError> {
     //return Ok(StrategyState::Monitoring);
     let bal = update_balances(ctx, None).await?;
     // TODO make getting pricess in parallel
@@ -436,7 +661,7 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
         if e1_e2_ratio < low_ratio { format!("{:.4} ({0:.4} < {:.4}~{:.4})", e1_e2_ratio, low_ratio, high_ratio) }
         else if e1_e2_ratio > high_ratio { format!("{:.4} ({:.4}~{:.4} > {0:.4})", e1_e2_ratio, low_ratio, high_ratio) }
         else { format!("{:.4} < {:.4} < {:.4}", low_ratio, e1_e2_ratio, high_ratio) };
-    let monitoring_msg = if is_monitoring_only { "(only monitoring)\n" } else { "" }; 
+    let monitoring_msg = if is_monitoring_only { "(only monitoring)\n" } else if control::is_paused() { "(PAUSED)\n" } else { "" }; 
     let notify_message = format!(
         "Ratio: {}\n\
          \n\
@@ -456,20 +681,37 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
          EE_NAME, ee_total, bal.ee.operational_coins,
          WALLET_NAME, wallet_total, bal.wallet.gas_coins, GAS_COIN,
          aprice, OPERATING_COIN, TRANSFERRING_COIN);
-    monitoring::WALLET_BALANCE_TOTAL.set(bal.wallet.transferring_coins.to_f64().unwrap());
-    monitoring::WALLET_BALANCE_GAS.set(bal.wallet.gas_coins.to_f64().unwrap());
-    monitoring::E1_BALANCE_TOTAL.set(e1_total.to_f64().unwrap());
-    monitoring::E1_BALANCE_FREE.set(bal.e1.free.to_f64().unwrap());
-    monitoring::E1_BALANCE_SHORTED.set(bal.e1.operational_coins.to_f64().unwrap());
-    monitoring::E2_BALANCE_TOTAL.set(e2_total.to_f64().unwrap());
-    monitoring::E2_BALANCE_TRANSFERRING.set(bal.e2.transferring_coins.to_f64().unwrap());
-    monitoring::E2_BALANCE_INTERMEDIATE.set(bal.e2.intermediate_coins.to_f64().unwrap());
-    monitoring::E2_BALANCE_UNSTAKED.set(bal.e2.unstaked_coins.to_f64().unwrap());
-    monitoring::E2_BALANCE_STAKED.set(bal.e2.staked_coins.to_f64().unwrap());
-    monitoring::EE_BALANCE_TOTAL.set(ee_total.to_f64().unwrap());
-    monitoring::EE_BALANCE_TRANSFERRING.set(bal.ee.transferring_coins.to_f64().unwrap());
-    monitoring::EE_BALANCE_OPERATIONAL.set(bal.ee.operational_coins.to_f64().unwrap());
+    {
+        use monitoring::{ set_balance, BalanceKind::* };
+        use WhichExchange::*;
+        set_balance(Wallet, TRANSFERRING_COIN, Total, bal.wallet.transferring_coins.to_f64().unwrap());
+        set_balance(Wallet, GAS_COIN, Total, bal.wallet.gas_coins.to_f64().unwrap());
+        set_balance(First, TRANSFERRING_COIN, Total, e1_total.to_f64().unwrap());
+        set_balance(First, TRANSFERRING_COIN, Free, bal.e1.free.to_f64().unwrap());
+        set_balance(First, OPERATING_COIN, Shorted, bal.e1.operational_coins.to_f64().unwrap());
+        set_balance(Second, TRANSFERRING_COIN, Total, e2_total.to_f64().unwrap());
+        set_balance(Second, E2_TRANSFERRING_COIN, Free, bal.e2.transferring_coins.to_f64().unwrap());
+        set_balance(Second, E2_INTERMEDIATE_COIN, Free, bal.e2.intermediate_coins.to_f64().unwrap());
+        set_balance(Second, E2_UNSTAKED_COIN, Free, bal.e2.unstaked_coins.to_f64().unwrap());
+        set_balance(Second, E2_UNSTAKED_COIN, Staked, bal.e2.staked_coins.to_f64().unwrap());
+        set_balance(Exchange, TRANSFERRING_COIN, Total, ee_total.to_f64().unwrap());
+        set_balance(Exchange, EE_TRANSFERRING_COIN, Free, bal.ee.transferring_coins.to_f64().unwrap());
+        set_balance(Exchange, OPERATING_COIN, Free, bal.ee.operational_coins.to_f64().unwrap());
+    }
     monitoring::E1_E2_RATIO.set(e1_e2_ratio.to_f64().unwrap());
+    api::set_market(e1_e2_ratio, low_ratio, high_ratio, &[
+        (E1_NAME, avg_price(&e1_price)), (E2_NAME, avg_price(&e2_price)), (EE_NAME, avg_price(&ee_price))]);
+    history::record(&ctx.history, &history::Sample::new(api::now_timestamp() as i64, &bal,
+        [avg_price(&e1_price), avg_price(&e2_price), avg_price(&ee_price)],
+        [*e1_total, *e2_total, *ee_total, *wallet_total], e1_e2_ratio));
+    digest::observe_cycle(digest::CycleSnapshot {
+        nav: *total,
+        ratio: e1_e2_ratio,
+        staked: *bal.e2.staked_coins,
+        e1_total: *e1_total,
+        e1_free: *bal.e1.free,
+        short_value: *(-bal.e1.operational_coins).to_prim(avg_price(&e1_price)),
+    });
     monitoring::PRICE.set(avg_price(&e1_price).to_f64().unwrap());
     //The limit of funds on the account, below which the strategy will consider the account as zero.
     //
@@ -493,17 +735,28 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
     let e1_lack = expected_e1_total - e1_total;
     let e1_excess = -e1_lack;
 
-    const TRANSFER_COMMISSION: Value = dec!(0.005); // expected commission on transfers to simplify calculations
     const OPERATING_COIN_MIN: SecondaryAsset = ONE_S; // minimal amount for buy/sell
     const EE_TO_WALLET_MIN: PrimaryAsset = PrimaryAsset(dec!(50)); // minimal transferrable amount
-    const BUY_SELL_COMMISSION: Value = dec!(0.005);
 
-    const TRANSFERS_TOLERANCE: Value = dec!(0.10);
+    // Expected commissions (see `fees.rs`)
+    let e1_to_wallet_commission = ctx.fees.transfer_relative(Route::E1ToWallet);
+    let ee_to_wallet_commission = ctx.fees.transfer_relative(Route::EEToWallet);
+    let ee_to_e2_commission = ctx.fees.transfer_relative(Route::EEToE2);
+    let e2_to_ee_commission = ctx.fees.transfer_relative(Route::E2ToEE);
+    let ee_buy_sell_commission = ctx.fees.trade_relative(WhichExchange::Exchange);
+    let transfers_tolerance = ctx.fees.tolerance();
+    debug!("Commissions: {} -> {}: {}, {} -> {}: {}, {} -> {}: {}, {} -> {}: {}, {} buy/sell: {}, tolerance: {}",
+           E1_NAME, WALLET_NAME, e1_to_wallet_commission,
+           EE_NAME, WALLET_NAME, ee_to_wallet_commission,
+           EE_NAME, E2_NAME, ee_to_e2_commission,
+           E2_NAME, EE_NAME, e2_to_ee_commission,
+           EE_NAME, ee_buy_sell_commission,
+           transfers_tolerance);
 
     let wallet_transferable = bal.wallet.transferring_coins;
-    let ee_prim_transferable = if bal.ee.transferring_coins >= EE_TO_WALLET_MIN { bal.ee.transferring_coins * (ONE - TRANSFER_COMMISSION) } else { ZERO_P };
-    let ee_exchangable = if bal.ee.operational_coins >= OPERATING_COIN_MIN { bal.ee.operational_coins.to_prim(ee_price.sell) * (ONE -  BUY_SELL_COMMISSION) }  else { ZERO_P };
-    let ee_total_transferable_pre = ee_prim_transferable + ee_exchangable * (ONE - TRANSFER_COMMISSION);
+    let ee_prim_transferable = if bal.ee.transferring_coins >= EE_TO_WALLET_MIN { bal.ee.transferring_coins * (ONE - ee_to_wallet_commission) } else { ZERO_P };
+    let ee_exchangable = if bal.ee.operational_coins >= OPERATING_COIN_MIN { bal.ee.operational_coins.to_prim(ee_price.sell) * (ONE - ee_buy_sell_commission) }  else { ZERO_P };
+    let ee_total_transferable_pre = ee_prim_transferable + ee_exchangable * (ONE - ee_to_wallet_commission);
     let ee_total_transferable = if ee_total_transferable_pre >= EE_TO_WALLET_MIN { ee_total_transferable_pre } else { ZERO_P };
     let eew_total_transferable = wallet_transferable + ee_total_transferable;
 
@@ -521,12 +774,12 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
     // Funds on Wallet in 'ATOM', which could be transferrable to EE (or `0`, if it's not profitable)
     let wallet_transferable_to_sec =
         if bal.wallet.transferring_coins >= transferring_coins_min_limit
-            { bal.wallet.transferring_coins.to_sec(ee_price.buy) * (ONE - BUY_SELL_COMMISSION) }
+            { bal.wallet.transferring_coins.to_sec(ee_price.buy) * (ONE - ee_buy_sell_commission) }
         else
             { ZERO_S }; 
     // Funds on Wallet and EE in `ATOM`, which could be transferrable (or `0`, if it's not profitable)
     let ee_wallet_exchangable_to_sec = {
-        let ee_prim_exchangable_pre = bal.ee.transferring_coins.to_sec(ee_price.buy) * (ONE - BUY_SELL_COMMISSION);
+        let ee_prim_exchangable_pre = bal.ee.transferring_coins.to_sec(ee_price.buy) * (ONE - ee_buy_sell_commission);
         let ee_wallet_exchangable_to_sec_pre = wallet_transferable_to_sec + ee_prim_exchangable_pre;
         if ee_wallet_exchangable_to_sec_pre >= ONE_S { ee_wallet_exchangable_to_sec_pre } else { ZERO_S }
     };
@@ -537,7 +790,7 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
     debug!("ee_total_transferable_to_sec = {}", ee_total_transferable_to_sec);
 
     let e1_delta = min(ew_total, expected_e1_total - e1_total);
-    let e1_delta_with_tol = e1_delta * (ONE + TRANSFERS_TOLERANCE);
+    let e1_delta_with_tol = e1_delta * (ONE + transfers_tolerance);
     debug!("e1_delta = {}", e1_delta);
     debug!("e1_delta_with_tol = {}", e1_delta_with_tol);
     debug!("eew_total_transferable = {}", eew_total_transferable);
@@ -622,7 +875,7 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
                     // There's plenty of money in the wallet, so you only have to transfer part of it.
                     if wallet_transferable >= e1_delta {
                         // Only the Wallet will suffice.
-                        let to_transfer = min(bal.wallet.transferring_coins, e1_delta_with_tol * (ONE + TRANSFERS_TOLERANCE)); // here is another increase, because the price may not have changed at the previous steps, as a result the same amount will come
+                        let to_transfer = min(bal.wallet.transferring_coins, e1_delta_with_tol * (ONE + transfers_tolerance)); // here is another increase, because the price may not have changed at the previous steps, as a result the same amount will come
                         info!("{}: I'm going to transfer {} to {}", WALLET_NAME, to_transfer, E1_NAME);
                         return notify_state(StrategyState::TransferWalletToE1(to_transfer), notify_message);
                     }
@@ -631,7 +884,7 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
                         let to_transfer =  EE_TO_WALLET_MIN.max(e1_delta_with_tol - bal.wallet.transferring_coins);
                         let to_exchange0 = to_transfer - bal.ee.transferring_coins;
                         let to_exchange =
-                            if to_transfer * (ONE + TRANSFERS_TOLERANCE) >= bal.ee.operational_coins.to_prim(price) + bal.ee.transferring_coins {
+                            if to_transfer * (ONE + transfers_tolerance) >= bal.ee.operational_coins.to_prim(price) + bal.ee.transferring_coins {
                                 bal.ee.operational_coins.to_prim(price)
                             }
                             else {
@@ -654,7 +907,7 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
                             let to_transfer_pre = to_cover_e1_delta.max(EE_TO_WALLET_MIN).min(bal.ee.transferring_coins);
                             let to_transfer =
                                 // If there are pennies left after the transfer, then transfer everything at all.
-                                if bal.ee.transferring_coins - to_transfer_pre * (ONE + TRANSFERS_TOLERANCE) < ONE_S.to_prim(ee_price.buy)
+                                if bal.ee.transferring_coins - to_transfer_pre * (ONE + transfers_tolerance) < ONE_S.to_prim(ee_price.buy)
                                 { bal.ee.transferring_coins }
                                 else
                                 { to_transfer_pre };
@@ -706,14 +959,14 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
                 //let total_after_withdraw = bal.e1.total - e1_excess * dec!(1.05); // + commission
                 //let leverage_after_withdraw = *sec_to_prim((-bal.e1.operational_coins).into(), e1_price) / total_after_withdraw;
                 //debug!("{}: total_after_withdraw = {}, leverage_after_withdraw = {}", E1_NAME, total_after_withdraw, leverage_after_withdraw);
-                let shorting_to_cancel : PrimaryAsset = (-bal.e1.operational_coins).to_prim(e1_price.buy) - (bal.e1.total - e1_excess) * MAX_LEVERAGE * (ONE + TRANSFERS_TOLERANCE);
+                let shorting_to_cancel : PrimaryAsset = (-bal.e1.operational_coins).to_prim(e1_price.buy) - (bal.e1.total - e1_excess) * MAX_LEVERAGE * (ONE + transfers_tolerance);
                 debug!("{}: shorting_to_cancel = {}", E1_NAME, shorting_to_cancel);
                 if shorting_to_cancel <= ZERO.into() {
                     return notify_state(StrategyState::TransferE1ToWallet(e1_excess), notify_message);
                 }
                 else {
                     // There is no enough USDC, so sell some ATOMs
-                    let shorting_to_cancel_fixed = max(shorting_to_cancel, (ONE_S * (ONE + TRANSFERS_TOLERANCE)).to_prim(e1_price.buy));
+                    let shorting_to_cancel_fixed = max(shorting_to_cancel, (ONE_S * (ONE + transfers_tolerance)).to_prim(e1_price.buy));
                     debug!("{}: I'm going to sell {} ({}) for make withdraw {} ({:.2} -> {:.2})",
                         E1_NAME, shorting_to_cancel_fixed.to_sec(e1_price.buy), shorting_to_cancel_fixed, e1_excess, bal.e1.total, expected_e1_total);
                     return notify_state(StrategyState::ReduceSecondaryBoth(get_single_order_size(ctx, shorting_to_cancel_fixed)), notify_message);
@@ -729,14 +982,14 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
                     // *slightly* not enough money on the wallet. If this "slightly" is too little for a normal
                     // transfer, we transfer what we have, in which case the balance of E1/E2 will still be sufficient.
                     //
-                    let amount = min(e1_lack, wallet_total) * (ONE + TRANSFERS_TOLERANCE);
+                    let amount = min(e1_lack, wallet_total) * (ONE + transfers_tolerance);
                     return notify_state(StrategyState::TransferWalletToE1(amount), notify_message);
                 }
                 else {
                     // It is need to transfer some funds from EE+Wallet
                     if ee_total + wallet_total - e1_lack >= -transferring_coins_min_limit {
                         // At first, transfer from EE
-                        let ee_delta = max((e1_lack - wallet_total)*(ONE + TRANSFER_COMMISSION), EE_TO_WALLET_MIN);
+                        let ee_delta = max((e1_lack - wallet_total)*(ONE + ee_to_wallet_commission), EE_TO_WALLET_MIN);
                         if (ee_delta <= bal.ee.transferring_coins) || (bal.ee.operational_coins < ONE_S)  {
                             // There is enought USDC
                             let amount = min(ee_delta, bal.ee.transferring_coins);
@@ -770,10 +1023,10 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
     if e1_ex_ratio < low_ratio {
         // E2 have too much funds, transfer some to E1 (with a small surplus to compensate for the change in price at the time of transfer).
         let delta_prim0 = expected_e1_total - e1_total;
-        let delta_prim = delta_prim0 * (ONE + TRANSFER_COMMISSION)   // commission for transfer from E2 to EE
-                                     * (ONE + BUY_SELL_COMMISSION)   // commission for exchange
-                                     * (ONE + TRANSFER_COMMISSION)   // commission for transfer from EE to WALLET
-                                     * (ONE + TRANSFERS_TOLERANCE);  // "insurance" for changing price during this transfer
+        let delta_prim = delta_prim0 * (ONE + e2_to_ee_commission)     // commission for transfer from E2 to EE
+                                     * (ONE + ee_buy_sell_commission)  // commission for exchange
+                                     * (ONE + ee_to_wallet_commission) // commission for transfer from EE to WALLET
+                                     * (ONE + transfers_tolerance);     // "insurance" for changing price during this transfer
         let delta = delta_prim.to_sec(ee_price.sell);
         let to_unstake = delta - (bal.e2.unstaked_coins + bal.ee.operational_coins);
 
@@ -789,7 +1042,7 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
             // If there are kopecks, too, transfer them, because then they can not be used in any way.
             let to_transfer_pre = min(delta, bal.e2.unstaked_coins);
             let to_transfer =
-                if bal.e2.unstaked_coins - to_transfer_pre * (ONE + TRANSFERS_TOLERANCE) < ONE_S
+                if bal.e2.unstaked_coins - to_transfer_pre * (ONE + transfers_tolerance) < ONE_S
                 { bal.e2.unstaked_coins }
                 else
                 { to_transfer_pre };
@@ -814,10 +1067,10 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
         // It is enough just to make a withdraw, then the upper code will figure it out.
         let delta0 = max(transferring_coins_min_limit,
                          (e1_total - e2_total * init_ratio) / (ONE + init_ratio));
-        let delta = delta0 * (ONE + TRANSFER_COMMISSION)   // commission for withdraw from dYdX
-                           * (ONE + BUY_SELL_COMMISSION)   // commission for exchange ATOM on EE
-                           * (ONE + TRANSFER_COMMISSION)   // commission for transfer to E2
-                           * (ONE + TRANSFERS_TOLERANCE);  // "insurance" for changing price during this transfer
+        let delta = delta0 * (ONE + e1_to_wallet_commission) // commission for withdraw from dYdX
+                           * (ONE + ee_buy_sell_commission)  // commission for exchange ATOM on EE
+                           * (ONE + ee_to_e2_commission)     // commission for transfer to E2
+                           * (ONE + transfers_tolerance);    // "insurance" for changing price during this transfer
         debug!("{}: I'm going to transfer {} from {} to {} to reduce ratio from {:.4} to {:.4}",
                E1_NAME, delta, E1_NAME, E2_NAME, e1_ex_ratio, init_ratio);
         notify_state(StrategyState::TransferE1ToWallet(delta), notify_message)
@@ -851,25 +1104,19 @@ async fn detect_current_state(ctx: &mut Context, is_monitoring_only: bool) -> Re
 
 // TODO add more operations to debug!
 
-/// Run action by its name
-///
-pub async fn run_action_by_name(action_name: String, ctx: &mut Context, additional_value: Option<Value>) -> ActionResult {
-    // NOTE this function is autogenerated by "update-action-names.hs" script
-    let d = || debug!("Run \"{}\"", action_name);
-    match action_name.as_str() {
-        "do_change_short" => { d(); do_change_short(ctx, additional_value.map(|v| v.into())).await }
-        "do_debug_show_balances" => { d(); do_debug_show_balances(ctx).await }
-        "do_debug_show_state" => { d(); do_debug_show_state(ctx).await }
-        "do_e1_to_wallet" => { d(); do_e1_to_wallet(ctx, additional_value.map(|v| v.into())).await }
-        "do_e2_to_ee" => { d(); do_e2_to_ee(ctx, additional_value.map(|v| v.into())).await }
-        "do_ee_to_e2" => { d(); do_ee_to_e2(ctx, additional_value.map(|v| v.into())).await }
-        "do_ee_to_wallet" => { d(); do_ee_to_wallet(ctx, additional_value.map(|v| v.into())).await }
-        "do_stake" => { d(); do_stake(ctx, additional_value.map(|v| v.into())).await }
-        "do_unstake" => { d(); do_unstake(ctx, additional_value.map(|v| v.into())).await }
-        "do_wallet_to_e1" => { d(); do_wallet_to_e1(ctx, additional_value.map(|v| v.into())).await }
-        "do_wallet_to_ee" => { d(); do_wallet_to_ee(ctx, additional_value.map(|v| v.into())).await }
-        "do_reduce_secondary_soft" => { d(); do_reduce_secondary_soft(ctx, additional_value.map(|v| v.into())).await }
-        "do_enlarge_secondary_soft" => { d(); do_enlarge_secondary_soft(ctx, additional_value.map(|v| v.into())).await }
-        _ => err(format!("No such action \"{}\"", action_name)),
-    }
+register_actions! {
+    do_reduce_secondary_soft(Primary) "Sell ATOM on Binance and reduce short position on dYdX (all by default)";
+    do_enlarge_secondary_soft(Primary) "Buy ATOM on Binance and enlarge short position on dYdX (all USDC on Binance by default)";
+    do_e1_to_wallet(Primary) "Withdraw USDC from dYdX to wallet (all free by default)";
+    do_wallet_to_e1(Primary) "Deposit USDC from wallet to dYdX (all by default)";
+    do_wallet_to_ee(Primary) "Deposit USDC from wallet to Binance (all by default)";
+    do_ee_to_wallet(Primary) "Withdraw USDC from Binance to wallet";
+    do_ee_to_e2(Secondary) "Transfer ATOM from Binance to Kraken (all by default)";
+    do_e2_to_ee(Secondary) "Transfer ATOM from Kraken to Binance (all unstaked by default)";
+    do_stake(Secondary) "Stake ATOM on Kraken (all unstaked by default)";
+    do_unstake(Secondary) "Unstake ATOM on Kraken (all staked by default)";
+    do_change_short(SignedSecondary) "Enlarge (positive) or reduce (negative) short position on dYdX (to staked amount by default)";
+    do_unwind(Percent) "Unwind part of positions (100% by default)";
+    do_debug_show_balances(Nothing) "Show balances";
+    do_debug_show_state(Nothing) "Show balances and current state";
 }
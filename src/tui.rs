@@ -0,0 +1,305 @@
+/// Terminal dashboard (`tui` subcommand)
+///
+/// Strategy runs as with `run`, but instead of scrolling logs the terminal shows balances,
+/// ratio and its bands, prices, current and next state, recent actions, transfers in flight
+/// and tail of log file. Data is taken from status of HTTP API (see `api.rs`), so dashboard
+/// doesn't make its own requests to exchanges.
+///
+/// Keys: `p` -- pause, `r` -- resume, `a` -- queue action, `q` -- quit (after confirmation;
+/// strategy is stopped between cycles, so the current operation is finished).
+///
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal_macros::dec;
+use std::fs::File;
+use std::io::{ Read, Seek, SeekFrom, Write };
+use std::time::{ Duration, Instant };
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::IntoAlternateScreen;
+use termion::{ clear, color, cursor, style };
+
+use crate::actions::*;
+use crate::api;
+use crate::consts::*;
+use crate::control;
+use crate::strategy::ACTIONS;
+use crate::types::*;
+use crate::utils::*;
+
+
+/// Size of log file tail which is read for every redraw
+const LOG_TAIL_BYTES: u64 = 32 * 1024;
+const REDRAW_PERIOD: Duration = Duration::from_secs(1);
+const KEYS_PERIOD: Duration = Duration::from_millis(100);
+
+
+/// What keys do now.
+///
+enum Mode {
+    Normal,
+    /// Choosing action (index in `ACTIONS`)
+    Select(usize),
+    /// Entering value of action
+    Value(usize, String),
+    /// Waiting for confirmation of action with value
+    Confirm(usize, Option<Value>),
+    /// Waiting for confirmation of quit
+    ConfirmQuit,
+}
+
+
+struct Ui {
+    mode: Mode,
+    /// Result of the last command
+    message: String,
+    is_quit: bool,
+}
+
+
+impl Ui {
+    fn handle_key(&mut self, key: Key) {
+        let mode = std::mem::replace(&mut self.mode, Mode::Normal);
+        self.mode = match (mode, key) {
+            (Mode::Normal, Key::Char('q')) | (_, Key::Ctrl('c')) => Mode::ConfirmQuit,
+            (Mode::ConfirmQuit, Key::Char('y')) => {
+                self.is_quit = true;
+                Mode::Normal
+            },
+            (Mode::Normal, Key::Char('p')) => {
+                control::set_paused(true, "terminal dashboard");
+                self.message = "Paused".to_owned();
+                Mode::Normal
+            },
+            (Mode::Normal, Key::Char('r')) => {
+                control::set_paused(false, "terminal dashboard");
+                self.message = "Resumed".to_owned();
+                Mode::Normal
+            },
+            (Mode::Normal, Key::Char('a')) => Mode::Select(0),
+            (Mode::Select(i), Key::Up) => Mode::Select(i.saturating_sub(1)),
+            (Mode::Select(i), Key::Down) => Mode::Select((i + 1).min(ACTIONS.len() - 1)),
+            (Mode::Select(i), Key::Char('\n')) if ACTIONS[i].param == Param::Nothing => Mode::Confirm(i, None),
+            (Mode::Select(i), Key::Char('\n')) => Mode::Value(i, String::new()),
+            (Mode::Value(i, mut input), Key::Char('\n')) => {
+                if input.trim().is_empty() {
+                    Mode::Confirm(i, None)
+                }
+                else {
+                    match ACTIONS[i].param.parse(&input) {
+                        Ok(value) => Mode::Confirm(i, Some(value)),
+                        Err(err) => {
+                            self.message = format!("Wrong value: {}", err);
+                            input.clear();
+                            Mode::Value(i, input)
+                        },
+                    }
+                }
+            },
+            (Mode::Value(i, mut input), Key::Backspace) => {
+                input.pop();
+                Mode::Value(i, input)
+            },
+            (Mode::Value(i, mut input), Key::Char(c)) if !c.is_control() => {
+                input.push(c);
+                Mode::Value(i, input)
+            },
+            (Mode::Confirm(i, value), Key::Char('y')) => {
+                let position = api::push_action(api::ActionRequest { name: ACTIONS[i].name.to_owned(), value });
+                self.message = format!("Action \"{}\" is queued (position: {}){}", ACTIONS[i].name, position,
+                                       if control::is_paused() { ", it will be run after resume" } else { "" });
+                Mode::Normal
+            },
+            (Mode::Confirm(..) | Mode::ConfirmQuit, _) | (_, Key::Esc) => {
+                self.message = "Cancelled".to_owned();
+                Mode::Normal
+            },
+            (mode, _) => mode,
+        };
+    }
+}
+
+
+/// Last lines of log file (without ANSI colors).
+///
+fn log_tail(file_name: &str, count: usize) -> Vec<String> {
+    let mut content = Vec::new();
+    let result = File::open(file_name).and_then(|mut f| {
+        let len = f.metadata()?.len();
+        f.seek(SeekFrom::Start(len.saturating_sub(LOG_TAIL_BYTES)))?;
+        f.read_to_end(&mut content)
+    });
+    if let Err(err) = result {
+        return vec![format!("Can't read {}: {}", file_name, err)];
+    }
+    let text = String::from_utf8_lossy(&content);
+    let lines: Vec<String> = text.lines().map(|l| l.replace('\x1b', "")).collect();
+    lines[lines.len().saturating_sub(count)..].to_vec()
+}
+
+
+fn ago(timestamp: u64) -> String {
+    let secs = api::now_timestamp().saturating_sub(timestamp);
+    if secs < 120 { format!("{}s ago", secs) } else { format!("{}m ago", secs / 60) }
+}
+
+
+/// Position of ratio between its bands: `[---|--*---|---]`.
+///
+fn ratio_gauge(ratio: Value, low: Value, high: Value) -> String {
+    const WIDTH: usize = 30;
+    let margin = (high - low).max(dec!(0.01));
+    let (from, to) = (low - margin, high + margin);
+    let pos = |v: Value| (((v - from) / (to - from)).clamp(ZERO, ONE) * Value::from(WIDTH - 1)).round().to_usize().unwrap_or(0);
+    let mut gauge: Vec<char> = vec!['-'; WIDTH];
+    gauge[pos(low)] = '|';
+    gauge[pos(high)] = '|';
+    gauge[pos(ratio)] = '*';
+    format!("[{}]", gauge.into_iter().collect::<String>())
+}
+
+
+/// Lines of dashboard (without log).
+///
+fn status_lines(status: &api::Status) -> Vec<String> {
+    let mut lines = Vec::new();
+    let (state_color, state_name) = if control::is_paused() { (color::Fg(color::Red).to_string(), "PAUSED") }
+                                    else { (color::Fg(color::Green).to_string(), "RUNNING") };
+    lines.push(format!("{}{}{} {}{}   last cycle: {}   venues: {}",
+                       style::Bold, state_color, state_name, style::Reset, color::Fg(color::Reset),
+                       status.last_cycle_timestamp.map(ago).unwrap_or_else(|| "-".to_owned()),
+                       status.venues.iter().map(|(name, v)| format!("{} {}", name, if v.ok { "ok" } else { "ERROR" })).collect::<Vec<_>>().join(", ")));
+    lines.push(String::new());
+    lines.push(format!("{}Balances{}", style::Bold, style::Reset));
+    match status.balances {
+        Some(bal) => {
+            // NOTE: inner values are used, because `Display` of assets adds default coin names
+            lines.push(format!("  {:8} total {:.2} {}, free {:.2} {}, short {:.4} {}", E1_NAME,
+                               *bal.e1.total, TRANSFERRING_COIN, *bal.e1.free, TRANSFERRING_COIN, -*bal.e1.operational_coins, OPERATING_COIN));
+            lines.push(format!("  {:8} {:.2} {}, {:.2} {}, staked {:.4} {}, unstaked {:.4} {}", E2_NAME,
+                               *bal.e2.transferring_coins, E2_TRANSFERRING_COIN, *bal.e2.intermediate_coins, E2_INTERMEDIATE_COIN,
+                               *bal.e2.staked_coins, E2_STAKED_COIN, *bal.e2.unstaked_coins, E2_UNSTAKED_COIN));
+            lines.push(format!("  {:8} {:.2} {}, {:.4} {}", EE_NAME,
+                               *bal.ee.transferring_coins, EE_TRANSFERRING_COIN, *bal.ee.operational_coins, OPERATING_COIN));
+            lines.push(format!("  {:8} {:.2} {}, gas {:.6} {}", WALLET_NAME,
+                               *bal.wallet.transferring_coins, TRANSFERRING_COIN, bal.wallet.gas_coins, GAS_COIN));
+        },
+        None => lines.push("  not loaded yet".to_owned()),
+    }
+    lines.push(String::new());
+    match (status.ratio, status.ratio_low, status.ratio_high) {
+        (Some(ratio), Some(low), Some(high)) => {
+            let ratio_color = if ratio < low || ratio > high { color::Fg(color::Red).to_string() } else { color::Fg(color::Green).to_string() };
+            lines.push(format!("{}Ratio{} {}{:.4}{} {} (bands {:.4} ~ {:.4})", style::Bold, style::Reset,
+                               ratio_color, ratio, color::Fg(color::Reset), ratio_gauge(ratio, low, high), low, high));
+        },
+        _ => lines.push(format!("{}Ratio{} -", style::Bold, style::Reset)),
+    }
+    lines.push(format!("{}Prices{} {}", style::Bold, style::Reset,
+                       status.prices.iter().map(|(name, price)| format!("{}: {:.4}", name, price)).collect::<Vec<_>>().join(", ")));
+    lines.push(String::new());
+    lines.push(format!("{}State{} {}", style::Bold, style::Reset,
+                       status.state.map(|s| format!("{:?}", s)).unwrap_or_else(|| "-".to_owned())));
+    lines.push(format!("{}Next{}  {}", style::Bold, style::Reset,
+                       status.plan.map(|s| format!("{:?}", s)).unwrap_or_else(|| "-".to_owned())));
+    lines.push(String::new());
+    lines.push(format!("{}Recent actions{}", style::Bold, style::Reset));
+    for action in status.recent_actions.iter().rev().take(5) {
+        lines.push(format!("  {:>8} {} {} {}", ago(action.timestamp), action.name,
+                           action.value.map(|v| v.to_string()).unwrap_or_default(),
+                           match &action.error { None => "ok".to_owned(), Some(err) => format!("{}failed: {}{}", color::Fg(color::Red), err, color::Fg(color::Reset)) }));
+    }
+    lines.push(format!("{}Transfers in flight{}", style::Bold, style::Reset));
+    for transfer in &status.transfers {
+        lines.push(format!("  {} {} {} (since {})", transfer.route, transfer.amount, transfer.coin, ago(transfer.timestamp)));
+    }
+    lines
+}
+
+
+/// Lines of action selector (or prompt) at the bottom of screen.
+///
+fn mode_lines(mode: &Mode) -> Vec<String> {
+    match mode {
+        Mode::Normal => vec![],
+        Mode::Select(selected) => {
+            let mut lines = vec![format!("{}Queue action{} (Up/Down, Enter -- select, Esc -- cancel):", style::Bold, style::Reset)];
+            lines.extend(ACTIONS.iter().enumerate().map(|(i, a)| format!("{} {} -- {}",
+                if i == *selected { ">" } else { " " }, a.name, a.description)));
+            lines
+        },
+        Mode::Value(i, input) => vec![format!("{}: {} (empty -- default, Esc -- cancel): {}",
+                                               ACTIONS[*i].name, ACTIONS[*i].param.help(), input)],
+        Mode::Confirm(i, value) => vec![format!("{}Queue {} {}? [y/N]{}", style::Bold, ACTIONS[*i].name,
+                                                 value.map(|v| ACTIONS[*i].param.format(v)).unwrap_or_else(|| "(default)".to_owned()), style::Reset)],
+        Mode::ConfirmQuit => vec![format!("{}Quit and stop strategy? [y/N]{}", style::Bold, style::Reset)],
+    }
+}
+
+
+fn truncate(line: &str, width: usize) -> String {
+    // NOTE: escape sequences are counted too, so colored lines may be cut a bit earlier
+    line.chars().take(width).collect()
+}
+
+
+fn draw<W: Write>(screen: &mut W, ui: &Ui, log_file: &str) -> std::io::Result<()> {
+    let (width, height) = termion::terminal_size()?;
+    let (width, height) = (width as usize, height as usize);
+    let mut lines = vec![format!("{}hedged staking{}   p -- pause, r -- resume, a -- action, q -- quit   {}",
+                                 style::Invert, style::Reset, ui.message)];
+    lines.extend(status_lines(&api::status()));
+    let bottom = mode_lines(&ui.mode);
+    lines.push(format!("{}Log{}", style::Bold, style::Reset));
+    let log_count = height.saturating_sub(lines.len() + bottom.len());
+    lines.extend(log_tail(log_file, log_count));
+    lines.extend(bottom);
+    write!(screen, "{}", clear::All)?;
+    for (row, line) in lines.iter().take(height).enumerate() {
+        write!(screen, "{}{}{}", cursor::Goto(1, row as u16 + 1), truncate(line, width), style::Reset)?;
+    }
+    screen.flush()
+}
+
+
+fn tui_loop(log_file: &str) -> std::io::Result<()> {
+    let mut screen = std::io::stdout().into_raw_mode()?.into_alternate_screen()?;
+    write!(screen, "{}", cursor::Hide)?;
+    let mut keys = termion::async_stdin().keys();
+    let mut ui = Ui { mode: Mode::Normal, message: String::new(), is_quit: false };
+    let mut last_draw = Instant::now();
+    let mut is_changed = true;
+    loop {
+        while let Some(Ok(key)) = keys.next() {
+            ui.handle_key(key);
+            is_changed = true;
+        }
+        if ui.is_quit {
+            write!(screen, "{}", cursor::Show)?;
+            return screen.flush();
+        }
+        if is_changed || last_draw.elapsed() >= REDRAW_PERIOD {
+            draw(&mut screen, &ui, log_file)?;
+            last_draw = Instant::now();
+            is_changed = false;
+        }
+        std::thread::sleep(KEYS_PERIOD);
+    }
+}
+
+
+/// Start dashboard in separate thread, strategy is stopped when dashboard is closed.
+///
+pub fn run_tui(log_file: &'static str) {
+    std::thread::spawn(move || {
+        let result = tui_loop(log_file);
+        // NOTE: terminal is restored here (screen is dropped in `tui_loop`)
+        if let Err(err) = result {
+            eprintln!("Terminal dashboard error: {}", err);
+        }
+        // NOTE: process isn't exited here, it could be in the middle of order or transfer
+        eprintln!("Strategy will be stopped after the current cycle...");
+        control::request_stop("terminal dashboard");
+    });
+}
@@ -1,14 +1,22 @@
 use openlimits::binance::Binance;
 use openlimits::dydx::Dydx;
 use openlimits::errors::OpenLimitsError;
+use openlimits::exchange::*;
 use openlimits::kraken::Kraken;
+use openlimits::model::Balance;
 use rust_decimal::{Decimal, RoundingStrategy};
 use rust_decimal::prelude::ToPrimitive;
+use serde::Serialize;
 use std::convert::From;
+use std::marker::PhantomData;
 use derive_more::*;
 
 //#[allow(unused_imports)]
+use crate::allowlist::Allowlist;
 use crate::config::*;
+use crate::fees::FeeModel;
+use crate::market_info::MarketsInfo;
+use crate::secrets::Secret;
 
 pub static E1_NAME: &str = "dYdX";
 pub type FirstExchange = Dydx;
@@ -32,15 +40,105 @@ pub enum WhichExchange {
 }
 
 
-pub struct Connections {
-    pub e1: FirstExchange,
-    pub e2: SecondExchange,
-    pub ee_trade: ExchangeExchange,
-    pub ee_funding: ExchangeExchange,
+/// Access level of connections (type parameter of `Connections` and `Context`).
+///
+/// With `ReadOnly` access only balances, prices and market rules can be requested,
+/// so order, withdraw and stake methods can't be called (it is checked by compiler).
+///
+pub trait Access: Send + Sync + 'static {}
+
+
+/// Full access: trading, transfers, staking.
+///
+pub struct Trading;
+
+/// Only reading (monitoring).
+///
+pub struct ReadOnly;
+
+impl Access for Trading {}
+impl Access for ReadOnly {}
+
+
+pub struct Connections<A: Access = Trading> {
+    e1: FirstExchange,
+    e2: SecondExchange,
+    ee_trade: ExchangeExchange,
+    ee_funding: ExchangeExchange,
+    /// Passed to wallet script (empty for read-only connections)
+    wallet_secret: Secret,
+    e1_stark_private_key: Secret,
+    access: PhantomData<A>,
+}
+
+
+impl<A: Access> Connections<A> {
+    /// Market data (order books), available with any access.
+    ///
+    pub fn market_data(&self, we: WhichExchange) -> &dyn ExchangeMarketData {
+        match we {
+            WhichExchange::First => &self.e1,
+            WhichExchange::Second => &self.e2,
+            WhichExchange::Exchange => &self.ee_trade,
+            WhichExchange::Wallet => unreachable!(),
+        }
+    }
+
+    /// Market rules, available with any access.
+    ///
+    pub fn info(&self, we: WhichExchange) -> &dyn ExchangeInfoRetrieval {
+        match we {
+            WhichExchange::First => &self.e1,
+            WhichExchange::Second => &self.e2,
+            WhichExchange::Exchange => &self.ee_trade,
+            WhichExchange::Wallet => unreachable!(),
+        }
+    }
+
+    /// Balances of account (`is_funding` -- funding account of EE).
+    ///
+    pub async fn account_balances(&self, we: WhichExchange, is_funding: bool) -> Result<Vec<Balance>, OpenLimitsError> {
+        match we {
+            WhichExchange::First => self.e1.get_account_balances(None).await,
+            WhichExchange::Second => self.e2.get_account_balances(None).await,
+            WhichExchange::Exchange if is_funding => self.ee_funding.get_account_balances(None).await,
+            WhichExchange::Exchange => self.ee_trade.get_account_balances(None).await,
+            WhichExchange::Wallet => unreachable!(),
+        }
+    }
+}
+
+
+impl Connections<ReadOnly> {
+    pub fn new_read_only(e1: FirstExchange, e2: SecondExchange, ee: ExchangeExchange, ee_funding: ExchangeExchange) -> Self {
+        Connections { e1, e2, ee_trade: ee, ee_funding, wallet_secret: Secret::default(), e1_stark_private_key: Secret::default(), access: PhantomData }
+    }
+}
+
+
+/// Full access to exchanges (only for trading context).
+///
+impl Connections<Trading> {
+    pub fn new(e1: FirstExchange, e2: SecondExchange, ee_trade: ExchangeExchange, ee_funding: ExchangeExchange,
+               wallet_secret: Secret, e1_stark_private_key: Secret) -> Self {
+        Connections { e1, e2, ee_trade, ee_funding, wallet_secret, e1_stark_private_key, access: PhantomData }
+    }
+
+    pub fn e1(&self) -> &FirstExchange { &self.e1 }
+
+    pub fn e2(&self) -> &SecondExchange { &self.e2 }
+
+    pub fn ee_trade(&self) -> &ExchangeExchange { &self.ee_trade }
+
+    pub fn ee_funding(&self) -> &ExchangeExchange { &self.ee_funding }
+
+    pub fn wallet_secret(&self) -> &Secret { &self.wallet_secret }
+
+    pub fn e1_stark_private_key(&self) -> &Secret { &self.e1_stark_private_key }
 }
 
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
 pub struct E1Balances {
     pub total: PrimaryAsset,
     pub free: PrimaryAsset,
@@ -48,7 +146,7 @@ pub struct E1Balances {
 }
 
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
 pub struct E2Balances {
     pub transferring_coins: PrimaryAsset,
     pub intermediate_coins: PrimaryAsset,
@@ -57,21 +155,21 @@ pub struct E2Balances {
 }
 
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
 pub struct EEBalances {
     pub transferring_coins: PrimaryAsset,
     pub operational_coins: SecondaryAsset,
 }
 
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
 pub struct WalletBalances {
     pub transferring_coins: PrimaryAsset,
     pub gas_coins: Value,
 }
 
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
 pub struct Balances {
     pub e1: E1Balances,
     pub e2: E2Balances,
@@ -90,10 +188,10 @@ pub fn get_free_transferring_coins(balances: &Balances, we: WhichExchange) -> Pr
 }
 
 
-/// Connections, etc.
+/// Connections, etc. (`Context<ReadOnly>` is used for monitoring)
 ///
-pub struct Context {
-    pub connections: Connections,
+pub struct Context<A: Access = Trading> {
+    pub connections: Connections<A>,
 
     pub short_min_limit: Value,
     pub transferring_min_limit: PrimaryAsset,
@@ -103,9 +201,21 @@ pub struct Context {
     pub e2_withdrawal_key: String,
     pub e2_withdrawal_key_operational: String,
     pub e2_operational_token_deposit_address: String,
+    pub e2_operational_token_deposit_memo: Option<String>,
+    pub e2_transferring_token_deposit_address: String,
+    pub ee_transferring_token_deposit_address: String,
     pub wallet_withdraw_address: String,
     pub balances: Option<Balances>,
     pub consts: StrategyConfig,
+    pub fees: FeeModel,
+    pub approval: ApprovalConfig,
+    pub allowlist: Allowlist,
+    pub limits: LimitsConfig,
+    pub audit: AuditConfig,
+    pub history: HistoryConfig,
+    /// Stop actions before the first side effect (see `only --dry-run`)
+    pub dry_run: bool,
+    pub markets: MarketsInfo,
 }
 
 
@@ -128,11 +238,11 @@ pub fn avg_price(price: &BuySellPrice) -> Price {
 }
 
 
-#[derive(Debug, PartialOrd, PartialEq, Neg, From, Add, Sub, Mul, Div, Clone, Copy, Eq, Ord, Into, Deref, Default)]
+#[derive(Debug, PartialOrd, PartialEq, Neg, From, Add, Sub, Mul, Div, Clone, Copy, Eq, Ord, Into, Deref, Default, Serialize)]
 pub struct PrimaryAsset(pub Value);
 
 
-#[derive(Debug, PartialOrd, PartialEq, Neg, From, Add, Sub, Mul, Div, Clone, Copy, Eq, Ord, Into, Deref, Default)]
+#[derive(Debug, PartialOrd, PartialEq, Neg, From, Add, Sub, Mul, Div, Clone, Copy, Eq, Ord, Into, Deref, Default, Serialize)]
 pub struct SecondaryAsset(pub Value);
 
 
@@ -200,6 +310,8 @@ impl SecondaryAsset {
 #[derive(Debug)]
 pub enum StrategyError {
     Misc { msg: String },
+    /// Action is stopped before side effect `what` in dry run
+    DryRun { what: String },
 }
 
 
@@ -207,6 +319,7 @@ impl ToString for StrategyError {
     fn to_string(&self) -> String {
         match self {
             StrategyError::Misc{msg} => msg.to_owned(),
+            StrategyError::DryRun{what} => format!("Dry run: stopped before {}", what),
         }
     }
 }
@@ -226,6 +339,13 @@ impl From<std::io::Error> for StrategyError {
 }
 
 
+impl From<serde_json::Error> for StrategyError {
+    fn from(sje: serde_json::Error) -> Self {
+        StrategyError::Misc { msg: format!("JSON error: {:?}", sje) }
+    }
+}
+
+
 pub type StrategyResult<A> = Result<A, StrategyError>;
 
 
@@ -0,0 +1,304 @@
+/// Emergency unwind: flatten the whole book (or a part of it)
+///
+/// Unwinding is a sequence of steps, each one is made by existing `do_*` actions:
+///
+/// 1. Unstake `OPERATING_COIN`s on E2.
+/// 1. Transfer them from E2 to EE.
+/// 1. Sell them on EE and close the same part of short position on E1 (chunk by chunk to keep delta near zero).
+/// 1. Transfer `TRANSFERRING_COIN`s from EE, E2 and E1 to wallet.
+///
+/// Progress is stored in `UNWIND_STATE_FILE_NAME` after every step, so an interrupted
+/// unwinding is continued on the next run.
+///
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn };
+use openlimits::model::Side;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+
+use crate::audit;
+use crate::consts::*;
+use crate::helpers::*;
+use crate::strategy::*;
+use crate::types::*;
+use crate::utils::*;
+
+
+pub static UNWIND_STATE_FILE_NAME: &str = "./unwind-state.json";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, strum_macros::EnumMessage)]
+pub enum UnwindStep {
+    #[strum(message="unstake on Kraken")]
+    Unstake,
+    #[strum(message="transfer from Kraken to Binance")]
+    TransferE2ToEE,
+    #[strum(message="sell on Binance and reduce short position on dYdX")]
+    ReduceBoth,
+    #[strum(message="transfer from Binance to Wallet")]
+    TransferEEToWallet,
+    #[strum(message="transfer from Kraken to Wallet")]
+    TransferE2ToWallet,
+    #[strum(message="transfer from dYdX to Wallet")]
+    TransferE1ToWallet,
+    #[strum(message="done")]
+    Done,
+}
+
+
+impl UnwindStep {
+    fn next(self) -> UnwindStep {
+        match self {
+            UnwindStep::Unstake => UnwindStep::TransferE2ToEE,
+            UnwindStep::TransferE2ToEE => UnwindStep::ReduceBoth,
+            UnwindStep::ReduceBoth => UnwindStep::TransferEEToWallet,
+            UnwindStep::TransferEEToWallet => UnwindStep::TransferE2ToWallet,
+            UnwindStep::TransferE2ToWallet => UnwindStep::TransferE1ToWallet,
+            UnwindStep::TransferE1ToWallet => UnwindStep::Done,
+            UnwindStep::Done => UnwindStep::Done,
+        }
+    }
+
+    fn number(self) -> usize {
+        self as usize + 1
+    }
+}
+
+
+/// Unwinding plan and its progress.
+///
+/// Amounts are calculated once (at the start of unwinding).
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnwindState {
+    pub percent: Value,
+    pub step: UnwindStep,
+    /// `OPERATING_COIN`s to unstake on E2
+    pub to_unstake: Value,
+    /// `OPERATING_COIN`s to transfer from E2 to EE
+    pub to_transfer_e2_ee: Value,
+    /// `OPERATING_COIN`s to sell on EE (rest)
+    pub to_sell_ee: Value,
+    /// Short position to close on E1 (rest)
+    pub to_close_e1: Value,
+    /// `TRANSFERRING_COIN`s which must remain on EE
+    pub ee_remain: Value,
+    /// `TRANSFERRING_COIN`s to transfer from E2
+    pub to_transfer_e2_wallet: Value,
+    /// Part of E1 total balance which must remain on E1
+    pub e1_remain: Value,
+    /// Short position to close on E1 for the chunk already sold on EE
+    #[serde(default)]
+    pub pending_close_e1: Value,
+}
+
+
+impl UnwindState {
+    fn new(bal: &Balances, percent: Value) -> UnwindState {
+        let part = percent_to_decimal(percent);
+        let rest = ONE - part;
+        let to_unstake = *bal.e2.staked_coins * part;
+        let to_transfer_e2_ee = to_unstake + *bal.e2.unstaked_coins * part;
+        UnwindState {
+            percent,
+            step: UnwindStep::Unstake,
+            to_unstake,
+            to_transfer_e2_ee,
+            to_sell_ee: to_transfer_e2_ee + *bal.ee.operational_coins * part,
+            to_close_e1: *(-bal.e1.operational_coins) * part,
+            ee_remain: *bal.ee.transferring_coins * rest,
+            to_transfer_e2_wallet: *(bal.e2.transferring_coins) * part,
+            e1_remain: *bal.e1.total * rest,
+            pending_close_e1: ZERO,
+        }
+    }
+}
+
+
+fn load_state() -> StrategyResult<Option<UnwindState>> {
+    match std::fs::read_to_string(UNWIND_STATE_FILE_NAME) {
+        Ok(s) => Ok(Some(serde_json::from_str(&s)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+
+/// NOTE: state isn't saved in dry run (otherwise the next real run would continue it)
+///
+fn save_state(ctx: &Context, state: &UnwindState) -> ActionResult {
+    if ctx.dry_run {
+        return Ok(());
+    }
+    std::fs::write(UNWIND_STATE_FILE_NAME, serde_json::to_string_pretty(state)?)?;
+    Ok(())
+}
+
+
+fn report_step(state: &UnwindState, msg: String) {
+    use strum::EnumMessage;
+    info!(target: "NOTIFICATION", "UNWIND {}%: step {}/{} ({}): {}",
+          state.percent,
+          state.step.number(), UnwindStep::Done.number() - 1,
+          state.step.get_message().unwrap_or("?"),
+          msg);
+}
+
+
+/// Sell `OPERATING_COIN`s on EE and close short position on E1 by chunks.
+///
+/// Both legs of every chunk have the same amount, so delta isn't changed. If only one
+/// leg can be continued, unwinding is stopped (the rest remains in the state).
+///
+async fn reduce_both(ctx: &mut Context, state: &mut UnwindState) -> ActionResult {
+    let ee_min = ctx.markets.ee.min_quantity.max(ONE);
+    let e1_min = ctx.markets.e1.min_quantity.max(*ONE_S);
+    loop {
+        if state.pending_close_e1 > ZERO {
+            // Unwinding was interrupted between legs of the chunk
+            do_change_short(ctx, Some(-SecondaryAsset(state.pending_close_e1))).await?;
+            state.to_close_e1 -= state.pending_close_e1;
+            state.pending_close_e1 = ZERO;
+            save_state(ctx, state)?;
+        }
+        let bal = update_balances(ctx, None).await?;
+        let price = get_token_price(ctx, WhichExchange::Exchange).await?;
+        // Balances could be changed since plan was made
+        state.to_sell_ee = state.to_sell_ee.min(*bal.ee.operational_coins);
+        state.to_close_e1 = state.to_close_e1.min(*(-bal.e1.operational_coins));
+        let can_sell = state.to_sell_ee >= ee_min;
+        let can_close = state.to_close_e1 >= e1_min;
+        if !can_sell && !can_close {
+            break;
+        }
+        if can_sell != can_close {
+            let msg = format!("UNWIND: only one leg can be reduced (rest to sell on {}: {:.4} {}, rest to close on {}: {:.4} {}), \
+                               stopped to keep delta (check balances, then reset unwinding)",
+                              EE_NAME, state.to_sell_ee, OPERATING_COIN,
+                              E1_NAME, state.to_close_e1, OPERATING_COIN);
+            error!(target: "NOTIFICATION", "{}", msg);
+            return err(msg);
+        }
+        let rest = state.to_sell_ee.min(state.to_close_e1);
+        let chunk = get_single_order_size_sec(ctx, SecondaryAsset(rest), price.sell);
+        // If only some pennies remain after this chunk, then reduce everything
+        let amount = if rest - *chunk < ee_min.max(e1_min) { rest } else { (*chunk).min(rest) };
+        change_tokens_ex(ctx, WhichExchange::Exchange, Side::Sell, Asset::Secondary(amount.into())).await?;
+        state.to_sell_ee -= amount;
+        state.pending_close_e1 = amount;
+        save_state(ctx, state)?;
+        do_change_short(ctx, Some(-SecondaryAsset(amount))).await?;
+        state.to_close_e1 -= amount;
+        state.pending_close_e1 = ZERO;
+        save_state(ctx, state)?;
+        report_step(state, format!("rest to sell on {}: {:.4} {}, rest to close on {}: {:.4} {}",
+                                   EE_NAME, state.to_sell_ee, OPERATING_COIN,
+                                   E1_NAME, state.to_close_e1, OPERATING_COIN));
+    }
+    Ok(())
+}
+
+
+async fn run_step(ctx: &mut Context, state: &mut UnwindState) -> ActionResult {
+    match state.step {
+        UnwindStep::Unstake => {
+            let staked = update_balances(ctx, Some(WhichExchange::Second)).await?.e2.staked_coins;
+            let amount = SecondaryAsset(state.to_unstake).min(staked);
+            if amount >= ctx.staking_min_limit {
+                report_step(state, format!("{}", amount));
+                do_unstake(ctx, Some(amount)).await?;
+            }
+        },
+        UnwindStep::TransferE2ToEE => {
+            let unstaked = update_balances(ctx, Some(WhichExchange::Second)).await?.e2.unstaked_coins;
+            let amount = SecondaryAsset(state.to_transfer_e2_ee).min(unstaked);
+            if amount >= ONE_S {
+                report_step(state, format!("{}", amount));
+                do_e2_to_ee(ctx, Some(amount)).await?;
+            }
+        },
+        UnwindStep::ReduceBoth => {
+            report_step(state, format!("sell {:.4} {} on {}, reduce short position on {} by {:.4} {}",
+                                       state.to_sell_ee, OPERATING_COIN, EE_NAME,
+                                       E1_NAME, state.to_close_e1, OPERATING_COIN));
+            reduce_both(ctx, state).await?;
+        },
+        UnwindStep::TransferEEToWallet => {
+            let available = update_balances(ctx, Some(WhichExchange::Exchange)).await?.ee.transferring_coins;
+            let amount = available - PrimaryAsset(state.ee_remain);
+            if amount >= ctx.transferring_min_limit {
+                report_step(state, format!("{}", amount));
+                do_ee_to_wallet(ctx, Some(amount)).await?;
+            }
+        },
+        UnwindStep::TransferE2ToWallet => {
+            let available = update_balances(ctx, Some(WhichExchange::Second)).await?.e2.transferring_coins;
+            let amount = min(available, PrimaryAsset(state.to_transfer_e2_wallet));
+            if amount >= ctx.transferring_min_limit {
+                report_step(state, format!("{}", amount));
+                internal_do_withdraw(ctx, amount, WhichExchange::Second).await?;
+            }
+        },
+        UnwindStep::TransferE1ToWallet => {
+            let bal = update_balances(ctx, Some(WhichExchange::First)).await?;
+            let amount = min(bal.e1.free, bal.e1.total - PrimaryAsset(state.e1_remain)) * (ONE - dec!(0.01)); // keep some for rounding
+            if amount >= ctx.transferring_min_limit {
+                report_step(state, format!("{}", amount));
+                do_e1_to_wallet(ctx, Some(amount)).await?;
+            }
+        },
+        UnwindStep::Done => {},
+    }
+    Ok(())
+}
+
+
+/// Unwind `percent` of all positions.
+///
+/// If previous unwinding was interrupted, it is continued (`percent` is ignored in this case).
+///
+pub async fn unwind(ctx: &mut Context, percent: Value) -> ActionResult {
+    if percent <= ZERO || percent > dec!(100) {
+        return err(format!("Wrong unwinding percent: {}", percent));
+    }
+    audit::set_trigger(None, "unwind");
+    let mut state = match load_state()? {
+        Some(state) => {
+            warn!(target: "NOTIFICATION", "UNWIND {}%: continue from step {} ({:?})", state.percent, state.step.number(), state.step);
+            state
+        },
+        None => {
+            let bal = update_balances(ctx, None).await?;
+            let state = UnwindState::new(&bal, percent);
+            warn!(target: "NOTIFICATION", "UNWIND {}%: started", percent);
+            debug!("Unwind plan: {:?}", state);
+            save_state(ctx, &state)?;
+            state
+        }
+    };
+    while state.step != UnwindStep::Done {
+        run_step(ctx, &mut state).await?;
+        state.step = state.step.next();
+        save_state(ctx, &state)?;
+    }
+    log_balances(ctx).await?;
+    // NOTE: state isn't saved in dry run, so the file (if any) belongs to interrupted real unwinding
+    if !ctx.dry_run {
+        std::fs::remove_file(UNWIND_STATE_FILE_NAME)?;
+    }
+    info!(target: "NOTIFICATION", "UNWIND {}%: done", state.percent);
+    Ok(())
+}
+
+
+/// Forget interrupted unwinding.
+///
+pub fn reset_unwind() -> ActionResult {
+    if load_state()?.is_some() {
+        warn!("Unfinished unwinding is reset");
+        std::fs::remove_file(UNWIND_STATE_FILE_NAME)?;
+    }
+    Ok(())
+}
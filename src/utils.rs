@@ -2,6 +2,7 @@ use clap::crate_version;
 use pause_console::*;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
+use std::io::Write;
 use termion::{color, style};
 
 use crate::types::*;
@@ -23,6 +24,19 @@ pub fn press_enter_to_continue() {
 }
 
 
+/// Ask question in terminal, `false` if answer isn't "y" (or stdin isn't terminal).
+///
+pub fn confirm(question: &str) -> bool {
+    if !termion::is_tty(&std::io::stdin()) {
+        return false;
+    }
+    print!("{}{}{} [y/N]{} ", color::Fg(color::Red), style::Bold, question, style::Reset);
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+
 #[allow(dead_code)]
 pub fn wait_for_human_action(msg: String) {
     pause_console!(format!(